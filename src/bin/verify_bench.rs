@@ -0,0 +1,68 @@
+//! 测量签名校验路径的吞吐量（events/sec）
+//!
+//! 用法：cargo run --release --bin verify_bench -- [events] [authors]
+
+use futures::future::join_all;
+use ksana_relay::{
+    nostr::{Event, EventKind, PreEvent, PrivateKey, Tag, Unixtime},
+    relay::Verifier,
+};
+use std::{env, time::Instant};
+
+fn gen_events(count: usize, authors: usize) -> Vec<Event> {
+    let keys: Vec<PrivateKey> = (0..authors.max(1)).map(|_| PrivateKey::gen()).collect();
+    (0..count)
+        .map(|i| {
+            let key = &keys[i % keys.len()];
+            let pre = PreEvent {
+                pubkey: key.public_key(),
+                created_at: Unixtime(1677600000 + i as i64),
+                kind: EventKind::TextNote,
+                tags: vec![Tag::Subject("bench".to_string())],
+                content: format!("verify bench event #{}", i),
+            };
+            Event::new(pre, key).expect("sign event faild")
+        })
+        .collect()
+}
+
+fn report(name: &str, count: usize, start: Instant) {
+    let secs = start.elapsed().as_secs_f64();
+    println!(
+        "{:<28} {:>8} events in {:>8.3}s  {:>10.0} events/sec",
+        name,
+        count,
+        secs,
+        count as f64 / secs
+    );
+}
+
+async fn run_verifier(verifier: &Verifier, events: &[Event]) {
+    let results = join_all(events.iter().map(|e| verifier.verify(e.clone()))).await;
+    assert!(results.iter().all(|r| r.is_ok()), "verify faild");
+}
+
+#[tokio::main]
+async fn main() {
+    let mut args = env::args().skip(1);
+    let count: usize = args.next().and_then(|v| v.parse().ok()).unwrap_or(20_000);
+    let authors: usize = args.next().and_then(|v| v.parse().ok()).unwrap_or(100);
+
+    println!("generating {} events from {} authors...", count, authors);
+    let events = gen_events(count, authors);
+
+    let start = Instant::now();
+    for e in &events {
+        e.verify().expect("verify faild");
+    }
+    report("inline Event::verify", count, start);
+
+    let verifier = Verifier::default();
+    let start = Instant::now();
+    run_verifier(&verifier, &events).await;
+    report("verifier pool (cold cache)", count, start);
+
+    let start = Instant::now();
+    run_verifier(&verifier, &events).await;
+    report("verifier pool (warm cache)", count, start);
+}
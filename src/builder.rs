@@ -0,0 +1,395 @@
+use crate::{
+    config::{self, Config, StoreBackend},
+    database::{Database, EventStore, MemoryStore},
+    nostr::Event,
+    relay::{AllowAll, Context, Policy, RateLimiter, Relay, SeenEvents, SubscriberEvent, Verifier},
+    server::{self, bind_all, Connections, Listener, Tls},
+    ConfigLoader, ConfigReloader, RelayError,
+};
+use log::{info, warn};
+use std::{future::Future, net::SocketAddr, sync::Arc, time::Duration};
+use tokio::{
+    sync::{broadcast, mpsc, watch},
+    task::JoinHandle,
+    time::timeout,
+};
+
+/// 组装一个 relay
+///
+/// ```no_run
+/// # async fn run() -> Result<(), ksana_relay::RelayError> {
+/// use ksana_relay::{config::Config, database::Database, RelayBuilder};
+///
+/// let db = Database::connect("sqlite://ksana.db").await?;
+/// let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+/// let server = RelayBuilder::new(Config::default())
+///     .database(db)
+///     .listener(listener)
+///     .build()
+///     .await?;
+/// println!("listening on {:?}", server.local_addrs());
+/// server.serve().await
+/// # }
+/// ```
+pub struct RelayBuilder {
+    config: Config,
+    store: Option<Arc<dyn EventStore>>,
+    database: Option<Database>,
+    listeners: Vec<Listener>,
+    tls_listeners: Vec<Listener>,
+    policy: Arc<dyn Policy>,
+    config_loader: Option<ConfigLoader>,
+}
+
+impl RelayBuilder {
+    pub fn new(config: Config) -> Self {
+        RelayBuilder {
+            config,
+            store: None,
+            database: None,
+            listeners: vec![],
+            tls_listeners: vec![],
+            policy: Arc::new(AllowAll),
+            config_loader: None,
+        }
+    }
+
+    /// 使用自定义的 event 存储，否则按 `database.backend` 创建
+    pub fn store(mut self, store: impl EventStore + 'static) -> Self {
+        self.store = Some(Arc::new(store));
+        self.database = None;
+        self
+    }
+
+    /// 使用已经连接好的 SQLite 数据库作为存储，管理接口的备份也会使用它
+    pub fn database(mut self, database: Database) -> Self {
+        self.store = Some(Arc::new(database.clone()));
+        self.database = Some(database);
+        self
+    }
+
+    /// 使用宿主程序创建的 TCP 或 Unix socket listener，可以调用多次；指定后不再绑定 `network.listen`
+    pub fn listener(mut self, listener: impl Into<Listener>) -> Self {
+        self.listeners.push(listener.into());
+        self
+    }
+
+    /// 使用宿主程序创建的 TLS listener，证书来自 `network.tls`；
+    /// 和 [`RelayBuilder::listener`] 一样，指定后不再绑定配置中的监听地址
+    pub fn tls_listener(mut self, listener: impl Into<Listener>) -> Self {
+        self.tls_listeners.push(listener.into());
+        self
+    }
+
+    /// 自定义 event 和订阅的准入策略，默认全部接受
+    pub fn policy(mut self, policy: impl Policy + 'static) -> Self {
+        self.policy = Arc::new(policy);
+        self
+    }
+
+    /// 重新加载配置时调用的函数，见 [`ConfigReloader::reload`]
+    ///
+    /// 新的配置生效后会调用准入策略的 [`Policy::reload`]
+    pub fn config_loader(
+        mut self,
+        loader: impl Fn() -> Result<Config, config::Error> + Send + Sync + 'static,
+    ) -> Self {
+        self.config_loader = Some(Arc::new(loader));
+        self
+    }
+
+    /// 创建存储、绑定监听地址并启动 Relay 任务
+    ///
+    /// 需要在 tokio runtime 中调用
+    pub async fn build(self) -> Result<RelayServer, RelayError> {
+        let config = self.config;
+        config.validate()?;
+
+        let mut database = self.database;
+        let store: Arc<dyn EventStore> = match self.store {
+            Some(store) => store,
+            None => match config.database.backend {
+                StoreBackend::Memory => Arc::new(MemoryStore::new()),
+                StoreBackend::Sqlite if config.database.url.is_empty() => {
+                    return Err(config::Error::Invalid(
+                        "database.url",
+                        "set it in the config file, with --database-url or DATABASE_URL"
+                            .to_string(),
+                    )
+                    .into())
+                }
+                StoreBackend::Sqlite => {
+                    let db = Database::connect_with(&config.database).await?;
+                    database = Some(db.clone());
+                    Arc::new(db)
+                }
+            },
+        };
+
+        let (mut listeners, mut tls_listeners) = (self.listeners, self.tls_listeners);
+        if listeners.is_empty() && tls_listeners.is_empty() {
+            listeners = bind_all(&config.network.listen).await?;
+            if let Some(tls) = &config.network.tls {
+                tls_listeners = bind_all(&tls.listen).await?;
+            }
+        }
+        let tls = match &config.network.tls {
+            Some(tls) => Some(Tls::new(tls)?),
+            None if !tls_listeners.is_empty() => {
+                return Err(config::Error::Invalid(
+                    "network.tls",
+                    "tls listeners need a certificate".to_string(),
+                )
+                .into())
+            }
+            None => None,
+        };
+
+        let limits = &config.limits;
+        let (subscriber_msg_sender, subscriber_msg_receiver) =
+            mpsc::channel::<SubscriberEvent>(limits.subscriber_channel_size);
+        let (broadcast_sender, _) = broadcast::channel::<Event>(limits.broadcast_channel_size);
+        let seen = SeenEvents::new(limits.seen_cache_size);
+        let verifier = Verifier::with_workers(limits.verify_workers);
+        let reloader = ConfigReloader::new(config, self.config_loader, Some(self.policy.clone()));
+
+        if let Some(db) = &database {
+            let seen = seen.clone();
+            tokio::spawn(
+                db.clone()
+                    .prune_periodically(reloader.subscribe(), move |ids| {
+                        for id in ids {
+                            seen.remove(id);
+                        }
+                    }),
+            );
+        }
+        if let Some(tls) = &tls {
+            tokio::spawn(tls.clone().reload_on_change(reloader.subscribe()));
+        }
+
+        let relay = Relay::new(
+            store,
+            subscriber_msg_receiver,
+            broadcast_sender.clone(),
+            seen.clone(),
+            reloader.subscribe(),
+        )
+        .start();
+        let (shutdown, shutdown_receiver) = watch::channel(false);
+
+        let ctx = Context {
+            sender: subscriber_msg_sender,
+            broadcast_sender,
+            seen,
+            verifier,
+            policy: self.policy,
+            rate_limiter: RateLimiter::new(),
+            connections: Connections::new(),
+            shutdown: shutdown_receiver,
+            database,
+            reloader,
+        };
+        Ok(RelayServer {
+            ctx,
+            listeners,
+            tls_listeners,
+            tls,
+            shutdown,
+            relay,
+        })
+    }
+}
+
+/// 已经启动的 relay，调用 [`RelayServer::serve`] 开始接受连接
+pub struct RelayServer {
+    ctx: Context,
+    listeners: Vec<Listener>,
+    tls_listeners: Vec<Listener>,
+    tls: Option<Tls>,
+    shutdown: watch::Sender<bool>,
+    relay: JoinHandle<()>,
+}
+
+impl RelayServer {
+    /// 实际监听的 TCP 地址，绑定端口 0 时可以用来获取分配的端口
+    pub fn local_addrs(&self) -> Vec<SocketAddr> {
+        self.listeners
+            .iter()
+            .chain(&self.tls_listeners)
+            .filter_map(Listener::local_addr)
+            .collect()
+    }
+
+    /// 运行中的配置，可以用来重新加载配置
+    pub fn config_reloader(&self) -> ConfigReloader {
+        self.ctx.reloader.clone()
+    }
+
+    /// 在所有 listener 上接受连接，不会返回
+    pub async fn serve(self) -> Result<(), RelayError> {
+        self.serve_with_shutdown(std::future::pending()).await
+    }
+
+    /// 在所有 listener 上接受连接，signal 完成后优雅退出
+    ///
+    /// 退出时先停止接受新连接，已有的连接处理完当前消息后发送 CLOSED 和关闭帧，
+    /// 再等待队列中的 event 写入存储，最多等待 `network.shutdown_timeout` 秒
+    pub async fn serve_with_shutdown(
+        self,
+        signal: impl Future<Output = ()>,
+    ) -> Result<(), RelayError> {
+        let RelayServer {
+            ctx,
+            listeners,
+            tls_listeners,
+            tls,
+            shutdown,
+            relay,
+        } = self;
+        let listeners = listeners.into_iter().map(|l| (l, None));
+        let tls_listeners = tls_listeners.into_iter().map(|l| (l, tls.clone()));
+        let servers: Vec<_> = listeners
+            .chain(tls_listeners)
+            .map(|(listener, tls)| {
+                let scheme = if tls.is_some() { "wss" } else { "ws" };
+                info!("Listening on: {} ({})", listener, scheme);
+                tokio::spawn(server::serve(listener, tls, ctx.clone()))
+            })
+            .collect();
+
+        signal.await;
+        info!(
+            "shutting down, {} connections to close",
+            ctx.connections.total()
+        );
+        let _ = shutdown.send(true);
+        futures::future::join_all(servers).await;
+
+        // 所有连接关闭后发往 Relay 的队列随之关闭，Relay 写完队列中剩余的 event 后结束
+        let deadline = Duration::from_secs(ctx.config().network.shutdown_timeout);
+        let (connections, database) = (ctx.connections.clone(), ctx.database.clone());
+        drop(ctx);
+        match timeout(deadline, relay).await {
+            Ok(_) => info!("all connections closed and pending events saved"),
+            Err(_) => warn!(
+                "shutdown timed out with {} connections still open",
+                connections.total()
+            ),
+        }
+        if let Some(db) = database {
+            db.close().await;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        database::{Error, WriteOp},
+        nostr::{EventKind, Filter, Id, PreEvent, PrivateKey, PublicKey},
+    };
+    use async_trait::async_trait;
+    use tokio::sync::{oneshot, Notify};
+
+    /// 写入前等待 open 变为 true 的存储，用来让 event 停留在 Relay 的队列中
+    #[derive(Clone)]
+    struct GateStore {
+        inner: Arc<MemoryStore>,
+        entered: Arc<Notify>,
+        open: watch::Receiver<bool>,
+    }
+
+    #[async_trait]
+    impl EventStore for GateStore {
+        async fn save(&self, event: &Event) -> Result<(), Error> {
+            self.inner.save(event).await
+        }
+        async fn query(&self, filters: &[Filter]) -> Result<Vec<Event>, Error> {
+            self.inner.query(filters).await
+        }
+        async fn count(&self, filters: &[Filter]) -> Result<u64, Error> {
+            self.inner.count(filters).await
+        }
+        async fn delete(&self, id: &Id, pubkey: &PublicKey) -> Result<u64, Error> {
+            self.inner.delete(id, pubkey).await
+        }
+        async fn replace(&self, event: &Event) -> Result<bool, Error> {
+            self.inner.replace(event).await
+        }
+        async fn exists(&self, id: &Id) -> Result<bool, Error> {
+            self.inner.exists(id).await
+        }
+        async fn write(&self, ops: &[WriteOp]) -> Vec<Result<bool, Error>> {
+            self.entered.notify_one();
+            let mut open = self.open.clone();
+            while !*open.borrow_and_update() {
+                if open.changed().await.is_err() {
+                    break;
+                }
+            }
+            self.inner.write(ops).await
+        }
+    }
+
+    fn event(key: &PrivateKey, content: &str) -> Event {
+        let pre = PreEvent::new(
+            key.public_key(),
+            EventKind::TextNote,
+            vec![],
+            content.into(),
+        );
+        Event::new(pre, key).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_shutdown_saves_queued_events() {
+        let (open_sender, open) = watch::channel(false);
+        let store = GateStore {
+            inner: Arc::new(MemoryStore::new()),
+            entered: Arc::new(Notify::new()),
+            open,
+        };
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let server = RelayBuilder::new(Config::default())
+            .store(store.clone())
+            .listener(listener)
+            .build()
+            .await
+            .unwrap();
+
+        // 第一个 event 卡在写入中，第二个 event 留在队列里
+        let key = PrivateKey::gen();
+        let (first, second) = (event(&key, "first"), event(&key, "second"));
+        let sender = server.ctx.sender.clone();
+        let (tx, _first_ok) = oneshot::channel();
+        sender
+            .send(SubscriberEvent::Event(Box::new(first.clone()), tx))
+            .await
+            .unwrap_or_else(|_| panic!("relay is not running"));
+        store.entered.notified().await;
+        let (tx, _second_ok) = oneshot::channel();
+        sender
+            .send(SubscriberEvent::Event(Box::new(second.clone()), tx))
+            .await
+            .unwrap_or_else(|_| panic!("relay is not running"));
+        drop(sender);
+
+        let (signal, signal_receiver) = oneshot::channel::<()>();
+        let serving = tokio::spawn(server.serve_with_shutdown(async {
+            let _ = signal_receiver.await;
+        }));
+        signal.send(()).unwrap();
+        // 收到退出信号之后才放行写入，serve_with_shutdown 必须等到写入完成才能返回
+        tokio::spawn(async move {
+            tokio::time::sleep(Duration::from_millis(100)).await;
+            let _ = open_sender.send(true);
+        });
+        serving.await.unwrap().unwrap();
+
+        assert!(store.inner.exists(&first.id()).await.unwrap());
+        assert!(store.inner.exists(&second.id()).await.unwrap());
+    }
+}
@@ -0,0 +1,126 @@
+use clap::{Args, Parser, Subcommand};
+use ksana_relay::config::{Config, Error};
+use std::path::PathBuf;
+
+/// Nostr relay
+#[derive(Parser, Debug)]
+#[command(version, about)]
+pub struct Cli {
+    /// 配置文件路径（TOML）
+    #[arg(short, long, env = "KSANA_CONFIG", global = true)]
+    pub config: Option<PathBuf>,
+
+    /// 监听地址（IPv4、IPv6、unix:<路径> 或 systemd），可以指定多次，覆盖配置文件中的 network.listen
+    #[arg(short, long, env = "KSANA_LISTEN", value_delimiter = ',')]
+    pub listen: Vec<String>,
+
+    /// 对外的 relay 地址，覆盖 network.relay_url
+    #[arg(long, env = "KSANA_RELAY_URL")]
+    pub relay_url: Option<String>,
+
+    /// 数据库地址，覆盖 database.url
+    #[arg(long, env = "DATABASE_URL", global = true)]
+    pub database_url: Option<String>,
+
+    /// 日志级别，覆盖 logging.level
+    #[arg(long, env = "RUST_LOG", global = true)]
+    pub log_level: Option<String>,
+
+    /// 不指定时启动 relay
+    #[command(subcommand)]
+    pub command: Option<Command>,
+}
+
+#[derive(Subcommand, Debug)]
+pub enum Command {
+    /// 把数据库中的 event 导出为 JSONL
+    Export(ExportArgs),
+    /// 从 JSONL 导入 event
+    Import(ImportArgs),
+    /// 在 relay 运行时备份数据库，写入一个校验过的快照并轮转旧快照
+    Backup(BackupArgs),
+    /// 检查数据库中的 event 和 tag 索引，可以隔离或删除损坏的 event
+    Check(CheckArgs),
+    /// 按占用的字节数列出存储最多的作者
+    Quota(QuotaArgs),
+}
+
+#[derive(Args, Debug)]
+pub struct ExportArgs {
+    /// NIP-01 filter JSON，例如 '{"kinds":[0,3]}'，不指定时导出全部
+    #[arg(short, long)]
+    pub filter: Option<String>,
+
+    /// 输出文件，不指定时写到标准输出
+    #[arg(short, long)]
+    pub output: Option<PathBuf>,
+
+    /// 使用 gzip 压缩，输出文件以 .gz 结尾时自动开启
+    #[arg(short = 'z', long)]
+    pub gzip: bool,
+}
+
+#[derive(Args, Debug)]
+pub struct ImportArgs {
+    /// 输入文件，不指定或为 `-` 时从标准输入读取，gzip 压缩的内容会自动解压
+    pub input: Option<PathBuf>,
+
+    /// 跳过前 N 行，从上次中断的位置继续
+    #[arg(long, default_value_t = 0)]
+    pub skip_lines: usize,
+}
+
+impl Cli {
+    /// 读取配置文件，再用命令行参数和环境变量覆盖，最后校验
+    ///
+    /// 优先级：命令行参数 > 环境变量 > 配置文件 > 默认值
+    pub fn load_config(&self) -> Result<Config, Error> {
+        let mut config = match &self.config {
+            Some(path) => Config::from_file(path)?,
+            None => Config::default(),
+        };
+        if !self.listen.is_empty() {
+            config.network.listen = self.listen.clone();
+        }
+        if let Some(relay_url) = &self.relay_url {
+            config.network.relay_url = relay_url.clone();
+        }
+        if let Some(url) = &self.database_url {
+            config.database.url = url.clone();
+        }
+        if let Some(level) = &self.log_level {
+            config.logging.level = level.clone();
+        }
+        config.validate()?;
+        Ok(config)
+    }
+}
+
+#[derive(Args, Debug)]
+pub struct BackupArgs {
+    /// 快照保存的目录，覆盖 backup.dir
+    #[arg(short, long)]
+    pub dir: Option<PathBuf>,
+
+    /// 保留最新的多少个快照，覆盖 backup.keep
+    #[arg(short, long)]
+    pub keep: Option<usize>,
+}
+
+#[derive(Args, Debug)]
+pub struct CheckArgs {
+    /// 把损坏的 event 移到 quarantined_events 表中，并修复 tag 索引
+    #[arg(long, conflicts_with = "delete")]
+    pub quarantine: bool,
+
+    /// 直接删除损坏的 event，并修复 tag 索引
+    #[arg(long)]
+    pub delete: bool,
+}
+
+#[derive(Args, Debug)]
+pub struct QuotaArgs {
+    /// 列出多少个作者
+    #[arg(short = 'n', long, default_value_t = 20)]
+    pub limit: usize,
+}
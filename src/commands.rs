@@ -0,0 +1,193 @@
+use crate::cli::{BackupArgs, CheckArgs, ExportArgs, ImportArgs, QuotaArgs};
+use flate2::{read::MultiGzDecoder, write::GzEncoder, Compression};
+use ksana_relay::{
+    config::{Config, StoreBackend},
+    database::{CheckAction, Database},
+    nostr::{Filter, PublicKey},
+};
+use log::info;
+use std::{
+    error::Error,
+    io::{self, Read, Write},
+};
+use tokio::{
+    fs::File,
+    io::{
+        AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader, BufWriter,
+    },
+    runtime::Handle,
+    task,
+};
+
+/// gzip 文件的前两个字节
+const GZIP_MAGIC: [u8; 2] = [0x1f, 0x8b];
+
+async fn open_database(config: &Config) -> Result<Database, Box<dyn Error>> {
+    if config.database.backend != StoreBackend::Sqlite || config.database.url.is_empty() {
+        return Err("this command needs the sqlite backend with database.url".into());
+    }
+    Ok(Database::connect_with(&config.database).await?)
+}
+
+pub async fn export(config: &Config, args: ExportArgs) -> Result<(), Box<dyn Error>> {
+    let filter: Filter = match &args.filter {
+        Some(json) => serde_json::from_str(json).map_err(|e| format!("invalid filter: {}", e))?,
+        None => Filter::default(),
+    };
+    let db = open_database(config).await?;
+
+    let gzip = args.gzip
+        || args
+            .output
+            .as_ref()
+            .is_some_and(|p| p.extension().is_some_and(|ext| ext == "gz"));
+    let out: Box<dyn AsyncWrite + Send + Unpin> = match &args.output {
+        Some(path) => Box::new(File::create(path).await?),
+        None => Box::new(tokio::io::stdout()),
+    };
+    let mut out = BufWriter::new(out);
+    let count = if gzip {
+        // flate2 只有同步接口，压缩放到 blocking 线程中，通过 duplex 接收导出的数据
+        let (mut writer, reader) = tokio::io::duplex(64 * 1024);
+        let handle = Handle::current();
+        let encoder = task::spawn_blocking(move || -> io::Result<()> {
+            let mut encoder =
+                GzEncoder::new(BlockingIo::new(out, handle.clone()), Compression::default());
+            io::copy(&mut BlockingIo::new(reader, handle), &mut encoder)?;
+            // drop 时写入的 gzip 结尾会忽略错误，这里显式结束
+            encoder.finish()?.flush()
+        });
+        let result = db.export(&filter, &mut writer).await;
+        drop(writer);
+        // 压缩线程出错时导出会因为 duplex 关闭而失败，优先返回压缩线程的错误
+        encoder.await??;
+        result?
+    } else {
+        let count = db.export(&filter, &mut out).await?;
+        out.shutdown().await?;
+        count
+    };
+    info!("exported {} events", count);
+    Ok(())
+}
+
+pub async fn import(config: &Config, args: ImportArgs) -> Result<(), Box<dyn Error>> {
+    let db = open_database(config).await?;
+    let input: Box<dyn AsyncRead + Send + Unpin> = match &args.input {
+        Some(path) if path.as_os_str() != "-" => Box::new(File::open(path).await?),
+        _ => Box::new(tokio::io::stdin()),
+    };
+    let mut input = BufReader::new(input);
+    let report = if input.fill_buf().await?.starts_with(&GZIP_MAGIC) {
+        // 解压放到 blocking 线程中，通过 duplex 把解压后的数据交给导入
+        let (writer, reader) = tokio::io::duplex(64 * 1024);
+        let handle = Handle::current();
+        let decoder = task::spawn_blocking(move || -> io::Result<u64> {
+            let mut decoder = MultiGzDecoder::new(BlockingIo::new(input, handle.clone()));
+            io::copy(&mut decoder, &mut BlockingIo::new(writer, handle))
+        });
+        let result = db
+            .import(
+                BufReader::new(reader),
+                args.skip_lines,
+                config.database.batch_size,
+            )
+            .await;
+        // 解压出错时 duplex 提前结束，导入会正常返回，这里要检查解压的结果
+        decoder.await??;
+        result?
+    } else {
+        db.import(input, args.skip_lines, config.database.batch_size)
+            .await?
+    };
+    info!(
+        "imported {} events, {} duplicates, {} skipped, {} lines skipped by --skip-lines, last line {}",
+        report.imported, report.duplicates, report.skipped, report.resumed, report.last_line
+    );
+    Ok(())
+}
+
+/// 在 blocking 线程中把 tokio 的异步 IO 当作同步的 Read 和 Write 使用
+struct BlockingIo<T> {
+    inner: T,
+    handle: Handle,
+}
+
+impl<T> BlockingIo<T> {
+    fn new(inner: T, handle: Handle) -> Self {
+        BlockingIo { inner, handle }
+    }
+}
+
+impl<T: AsyncRead + Unpin> Read for BlockingIo<T> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.handle.block_on(self.inner.read(buf))
+    }
+}
+
+impl<T: AsyncWrite + Unpin> Write for BlockingIo<T> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.handle.block_on(self.inner.write(buf))
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.handle.block_on(self.inner.flush())
+    }
+}
+
+pub async fn backup(config: &Config, args: BackupArgs) -> Result<(), Box<dyn Error>> {
+    let db = open_database(config).await?;
+    let backup = &config.backup;
+    let dir = args.dir.as_ref().unwrap_or(&backup.dir);
+    let keep = args.keep.unwrap_or(backup.keep);
+    let report = db.backup(dir, keep, backup.verify_sample).await?;
+    println!("{}", report.path.display());
+    Ok(())
+}
+
+pub async fn check(config: &Config, args: CheckArgs) -> Result<(), Box<dyn Error>> {
+    let db = open_database(config).await?;
+    let action = if args.quarantine {
+        CheckAction::Quarantine
+    } else if args.delete {
+        CheckAction::Delete
+    } else {
+        CheckAction::Report
+    };
+    let report = db.check(action).await?;
+    info!(
+        "checked {} events: {} bad events, {} inconsistent tag indexes, {} orphan tag rows, {} fixed",
+        report.checked, report.bad_events, report.bad_indexes, report.orphan_tags, report.fixed
+    );
+    if report.has_problems() {
+        return Err("database has problems, run check with --quarantine or --delete to fix".into());
+    }
+    Ok(())
+}
+
+pub async fn quota(config: &Config, args: QuotaArgs) -> Result<(), Box<dyn Error>> {
+    let db = open_database(config).await?;
+    for consumer in db.top_consumers(args.limit).await? {
+        let (max_events, max_bytes) = match PublicKey::try_from_hex_string(&consumer.pubkey) {
+            Ok(pubkey) => config.quota.limits_for(&pubkey),
+            Err(_) => (0, 0),
+        };
+        println!(
+            "{} {}/{} events {}/{} bytes",
+            consumer.pubkey,
+            consumer.usage.events,
+            limit_display(max_events),
+            consumer.usage.bytes,
+            limit_display(max_bytes)
+        );
+    }
+    Ok(())
+}
+
+fn limit_display(limit: u64) -> String {
+    if limit == 0 {
+        "unlimited".to_string()
+    } else {
+        limit.to_string()
+    }
+}
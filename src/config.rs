@@ -0,0 +1,841 @@
+use crate::{
+    nostr::PublicKey,
+    relay::{KindRange, Limitation, RelayInformation, RetentionRule},
+};
+use log::LevelFilter;
+use serde::Deserialize;
+use std::{
+    fs, io,
+    net::{IpAddr, SocketAddr},
+    path::PathBuf,
+    str::FromStr,
+};
+use thiserror::Error;
+use url::Url;
+
+#[derive(Error, Debug)]
+pub enum Error {
+    #[error("read config file {0} faild: {1}")]
+    Read(PathBuf, #[source] io::Error),
+
+    #[error("parse config file {0} faild: {1}")]
+    Parse(PathBuf, #[source] toml::de::Error),
+
+    #[error("invalid config `{0}`: {1}")]
+    Invalid(&'static str, String),
+}
+
+/// Relay 的配置
+///
+/// 可以从 TOML 文件读取，也可以在代码中直接构造，未指定的字段使用默认值
+#[derive(Deserialize, Debug, Clone, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub network: NetworkConfig,
+    pub info: RelayInformation,
+    pub limits: LimitsConfig,
+    pub auth: AuthConfig,
+    pub database: DatabaseConfig,
+    pub backup: BackupConfig,
+    pub retention: RetentionConfig,
+    pub quota: QuotaConfig,
+    pub rate_limit: RateLimitConfig,
+    pub admin: AdminConfig,
+    pub metrics: MetricsConfig,
+    pub logging: LoggingConfig,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct NetworkConfig {
+    /// 监听地址，格式见 [`ListenAddr`]
+    pub listen: Vec<String>,
+    /// 对外的 relay 地址，用于校验 AUTH event 的 relay tag
+    pub relay_url: String,
+    /// 以下配置为 0 时表示不限制
+    /// 最多同时保持的连接数
+    pub max_connections: usize,
+    /// 每个 IP 最多同时保持的连接数
+    pub max_connections_per_ip: usize,
+    /// PROXY 协议头、TLS 握手、读取请求头和 websocket 握手每一步的超时（秒）
+    pub handshake_timeout: u64,
+    /// 向客户端发送 ping 的间隔（秒），同时也是检查 pong 超时的间隔，为 0 时不发送 ping
+    pub ping_interval: u64,
+    /// 发送 ping 之后多久没有收到任何消息就断开连接（秒）
+    pub pong_timeout: u64,
+    /// 没有订阅的连接多久没有发送消息就断开（秒），与 ping 无关，为 0 时不限制
+    pub idle_timeout: u64,
+    /// 收到退出信号后等待连接关闭、event 写入完成的最长时间（秒）
+    pub shutdown_timeout: u64,
+    /// 收到退出信号后继续接受连接的时间（秒），期间 `/readyz` 返回 503、新的 websocket 连接被拒绝，
+    /// 让负载均衡有时间摘掉这个实例；为 0 时立即停止接受连接
+    pub shutdown_drain: u64,
+    /// 在单独的地址上提供 wss://，可以和上面的明文地址同时使用
+    pub tls: Option<TlsConfig>,
+    /// 受信任的反向代理，IP 或网段；只有来自这些地址的连接才会使用
+    /// X-Forwarded-For、X-Real-IP 或 PROXY 协议中的客户端地址
+    pub trusted_proxies: Vec<IpNet>,
+    /// 来自受信任代理的连接以 HAProxy PROXY 协议（v1 或 v2）的头开始
+    pub proxy_protocol: bool,
+}
+
+impl NetworkConfig {
+    pub fn is_trusted_proxy(&self, ip: IpAddr) -> bool {
+        self.trusted_proxies.iter().any(|net| net.contains(ip))
+    }
+}
+
+impl Default for NetworkConfig {
+    fn default() -> Self {
+        NetworkConfig {
+            listen: vec!["127.0.0.1:9002".to_string()],
+            relay_url: "wss://relay.ksana.net".to_string(),
+            max_connections: 10_000,
+            max_connections_per_ip: 64,
+            handshake_timeout: 10,
+            ping_interval: 30,
+            pong_timeout: 20,
+            idle_timeout: 300,
+            shutdown_timeout: 10,
+            shutdown_drain: 0,
+            tls: None,
+            trusted_proxies: vec![],
+            proxy_protocol: false,
+        }
+    }
+}
+
+/// 监听地址
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ListenAddr {
+    /// IPv4 或 IPv6 地址和端口，例如 `0.0.0.0:9002`、`[::]:9002`
+    Tcp(SocketAddr),
+    /// `unix:<路径>`，Unix domain socket，供本机的反向代理连接
+    Unix(PathBuf),
+    /// `systemd` 或 `systemd:<名称>`，systemd socket activation 传入的 socket，
+    /// 指定名称时只使用 FileDescriptorName 相同的 socket
+    Systemd(Option<String>),
+}
+
+impl FromStr for ListenAddr {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if let Some(path) = s.strip_prefix("unix:") {
+            if path.is_empty() {
+                return Err(format!("{}: socket path is empty", s));
+            }
+            return Ok(ListenAddr::Unix(PathBuf::from(path)));
+        }
+        if s == "systemd" {
+            return Ok(ListenAddr::Systemd(None));
+        }
+        if let Some(name) = s.strip_prefix("systemd:") {
+            return Ok(ListenAddr::Systemd(Some(name.to_string())));
+        }
+        SocketAddr::from_str(s)
+            .map(ListenAddr::Tcp)
+            .map_err(|e| format!("{}: {}", s, e))
+    }
+}
+
+/// 一个 IP 或者 CIDR 网段，例如 `10.0.0.0/8`、`::1`
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(try_from = "String")]
+pub struct IpNet {
+    addr: IpAddr,
+    prefix: u8,
+}
+
+impl IpNet {
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        let mask = |bits: u32| {
+            u128::MAX
+                .checked_shl(bits - self.prefix as u32)
+                .unwrap_or(0)
+        };
+        match (self.addr, ip.to_canonical()) {
+            (IpAddr::V4(net), IpAddr::V4(ip)) => {
+                let mask = mask(32) as u32;
+                u32::from(net) & mask == u32::from(ip) & mask
+            }
+            (IpAddr::V6(net), IpAddr::V6(ip)) => {
+                let mask = mask(128);
+                u128::from(net) & mask == u128::from(ip) & mask
+            }
+            _ => false,
+        }
+    }
+}
+
+impl FromStr for IpNet {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (addr, prefix) = match s.split_once('/') {
+            Some((addr, prefix)) => (addr, Some(prefix)),
+            None => (s, None),
+        };
+        let addr = IpAddr::from_str(addr)
+            .map_err(|e| format!("{}: {}", s, e))?
+            .to_canonical();
+        let max = if addr.is_ipv4() { 32 } else { 128 };
+        let prefix = match prefix {
+            Some(prefix) => prefix
+                .parse()
+                .ok()
+                .filter(|&p| p <= max)
+                .ok_or_else(|| format!("{}: bad prefix length", s))?,
+            None => max,
+        };
+        Ok(IpNet { addr, prefix })
+    }
+}
+
+impl TryFrom<String> for IpNet {
+    type Error = String;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct TlsConfig {
+    /// TLS 监听地址
+    #[serde(default)]
+    pub listen: Vec<String>,
+    /// PEM 格式的证书链，服务器证书在最前面
+    pub cert: PathBuf,
+    /// PEM 格式的 PKCS#8 私钥
+    pub key: PathBuf,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct LimitsConfig {
+    /// Subscriber 发往 Relay 的消息队列长度
+    pub subscriber_channel_size: usize,
+    /// Relay 广播 event 的队列长度
+    pub broadcast_channel_size: usize,
+    /// 用于去重的 event id 缓存数量
+    pub seen_cache_size: usize,
+    /// 签名校验的并发数，0 表示使用 CPU 核数
+    pub verify_workers: usize,
+    /// 以下限制为 0 时表示不限制，非 0 的限制会发布在 NIP-11 的 `limitation` 中
+    /// 一条 websocket 消息的最大字节数
+    pub max_message_length: usize,
+    /// 每个连接最多的订阅数
+    pub max_subscriptions: usize,
+    /// 每个 REQ 最多的 filter 数
+    pub max_filters: usize,
+    /// filter 的 limit 上限，更大的 limit 会被截断
+    pub max_limit: usize,
+    /// 订阅 id 的最大长度
+    pub max_subid_length: usize,
+    /// 每个 event 最多的 tag 数
+    pub max_event_tags: usize,
+    /// event content 的最大字符数
+    pub max_content_length: usize,
+}
+
+impl Default for LimitsConfig {
+    fn default() -> Self {
+        LimitsConfig {
+            subscriber_channel_size: 32,
+            broadcast_channel_size: 32,
+            seen_cache_size: 10_000,
+            verify_workers: 0,
+            max_message_length: 128 * 1024,
+            max_subscriptions: 20,
+            max_filters: 10,
+            max_limit: 5000,
+            max_subid_length: 64,
+            max_event_tags: 2000,
+            max_content_length: 64 * 1024,
+        }
+    }
+}
+
+/// NIP-42 认证策略
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct AuthConfig {
+    /// 发布 event 和订阅之前是否必须认证
+    pub required: bool,
+    /// 下发给客户端的 challenge
+    pub challenge: String,
+    /// AUTH event 的 created_at 与当前时间允许的最大偏差（秒）
+    pub max_age: u64,
+}
+
+impl Default for AuthConfig {
+    fn default() -> Self {
+        AuthConfig {
+            required: true,
+            challenge: "ksana.io".to_string(),
+            max_age: 10 * 60,
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct DatabaseConfig {
+    /// 存储后端
+    pub backend: StoreBackend,
+    /// SQLite 数据库地址，backend 为 sqlite 时必须指定
+    pub url: String,
+    /// 使用 WAL 日志模式，读写可以并发
+    pub wal: bool,
+    /// `PRAGMA synchronous`
+    pub synchronous: Synchronous,
+    /// 每个连接的页缓存大小（KiB）
+    pub cache_size_kib: u64,
+    /// 每个连接的 mmap 大小（字节），0 表示不使用 mmap
+    pub mmap_size: u64,
+    /// 数据库被锁时的等待时间（毫秒）
+    pub busy_timeout_ms: u64,
+    /// 只读连接池的最大连接数，写入始终只用一个连接；内存数据库读写共用一个连接
+    pub read_connections: u32,
+    /// 执行 `PRAGMA optimize` 的间隔（秒），0 表示不执行
+    pub optimize_interval: u64,
+    /// 执行 WAL checkpoint 的间隔（秒），0 表示交给 SQLite 自动处理
+    pub checkpoint_interval: u64,
+    /// 一次批量写入最多包含的 event 数
+    pub batch_size: usize,
+    /// 收到 event 后最多再等待多久（毫秒）凑成一批，0 表示只合并已经到达的
+    pub batch_linger_ms: u64,
+}
+
+impl Default for DatabaseConfig {
+    fn default() -> Self {
+        DatabaseConfig {
+            backend: StoreBackend::default(),
+            url: String::new(),
+            wal: true,
+            synchronous: Synchronous::Normal,
+            cache_size_kib: 64 * 1024,
+            mmap_size: 256 * 1024 * 1024,
+            busy_timeout_ms: 5000,
+            read_connections: 4,
+            optimize_interval: 3600,
+            checkpoint_interval: 300,
+            batch_size: 256,
+            batch_linger_ms: 2,
+        }
+    }
+}
+
+/// SQLite 的 `synchronous` 级别
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Synchronous {
+    Off,
+    Normal,
+    Full,
+    Extra,
+}
+
+/// event 存储后端
+#[derive(Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum StoreBackend {
+    /// 持久化到 SQLite
+    #[default]
+    Sqlite,
+    /// 只保存在内存中，重启后丢失
+    Memory,
+}
+
+/// SQLite 快照备份
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct BackupConfig {
+    /// 快照保存的目录
+    pub dir: PathBuf,
+    /// 保留最新的多少个快照，0 表示全部保留
+    pub keep: usize,
+    /// 每次备份后随机抽取多少个 event 校验签名
+    pub verify_sample: usize,
+}
+
+impl Default for BackupConfig {
+    fn default() -> Self {
+        BackupConfig {
+            dir: PathBuf::from("backups"),
+            keep: 7,
+            verify_sample: 100,
+        }
+    }
+}
+
+/// event 保留策略，由定期任务执行，只对 SQLite 存储生效
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct RetentionConfig {
+    /// 执行清理的间隔（秒），0 表示不清理
+    pub interval: u64,
+    /// 数据库的空间预算（MiB），超出时从最旧的 event 开始删除，0 表示不限制
+    pub max_db_size_mb: u64,
+    /// 这些作者（hex 公钥）的 event 永远不会被清理
+    pub exempt_pubkeys: Vec<String>,
+    /// 按 kind 的规则，会发布在 NIP-11 的 `retention` 中
+    pub rules: Vec<RetentionRule>,
+}
+
+impl Default for RetentionConfig {
+    fn default() -> Self {
+        RetentionConfig {
+            interval: 3600,
+            max_db_size_mb: 0,
+            exempt_pubkeys: vec![],
+            rules: vec![],
+        }
+    }
+}
+
+/// 每个作者的存储配额，超出时拒绝保存新的 event，deletion event 除外
+#[derive(Deserialize, Debug, Clone, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct QuotaConfig {
+    /// 每个作者最多保存的 event 数，0 表示不限制
+    pub max_events: u64,
+    /// 每个作者最多保存的字节数（原始 JSON），0 表示不限制
+    pub max_bytes: u64,
+    /// 为指定的作者单独设置配额
+    pub overrides: Vec<QuotaOverride>,
+}
+
+/// 一个作者的配额，没有设置的项使用默认配额
+#[derive(Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct QuotaOverride {
+    /// hex 公钥
+    pub pubkey: String,
+    pub max_events: Option<u64>,
+    pub max_bytes: Option<u64>,
+}
+
+impl QuotaConfig {
+    /// 是否有任何作者受配额限制
+    pub fn is_enabled(&self) -> bool {
+        self.max_events > 0 || self.max_bytes > 0 || !self.overrides.is_empty()
+    }
+
+    /// pubkey 适用的 (max_events, max_bytes)，0 表示不限制
+    pub fn limits_for(&self, pubkey: &PublicKey) -> (u64, u64) {
+        let hex = pubkey.as_hex_string();
+        match self
+            .overrides
+            .iter()
+            .find(|o| o.pubkey.eq_ignore_ascii_case(&hex))
+        {
+            Some(o) => (
+                o.max_events.unwrap_or(self.max_events),
+                o.max_bytes.unwrap_or(self.max_bytes),
+            ),
+            None => (self.max_events, self.max_bytes),
+        }
+    }
+}
+
+/// 令牌桶限流：每秒补充 rate 个令牌，最多积累 burst 个，每条消息消耗一个
+#[derive(Deserialize, Debug, Clone, Copy, Default, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct Rate {
+    /// 每秒补充的令牌数，0 表示不限制
+    pub rate: f64,
+    /// 桶的容量，即允许的突发消息数
+    pub burst: f64,
+}
+
+impl Rate {
+    pub const fn new(rate: f64, burst: f64) -> Self {
+        Rate { rate, burst }
+    }
+
+    pub fn is_unlimited(&self) -> bool {
+        self.rate <= 0.0
+    }
+}
+
+/// 各类客户端消息的限流
+#[derive(Deserialize, Debug, Clone, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct MessageRates {
+    pub event: Rate,
+    pub req: Rate,
+    /// NIP-45 COUNT
+    pub count: Rate,
+    pub auth: Rate,
+}
+
+/// 按 kind 限制 EVENT，按 IP 计算
+#[derive(Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct KindRate {
+    pub kinds: Vec<KindRange>,
+    pub rate: f64,
+    pub burst: f64,
+}
+
+impl KindRate {
+    pub fn rate(&self) -> Rate {
+        Rate::new(self.rate, self.burst)
+    }
+
+    pub fn contains(&self, kind: u64) -> bool {
+        self.kinds.iter().any(|k| {
+            let (start, end) = k.bounds();
+            start <= kind && kind <= end
+        })
+    }
+}
+
+/// 限流和封禁
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct RateLimitConfig {
+    /// 按 IP 限流，同一个 IP 的所有连接共享
+    pub ip: MessageRates,
+    /// 按认证过的公钥限流，同一个公钥的所有连接共享
+    pub pubkey: MessageRates,
+    /// 按 kind 限制 EVENT，一个 kind 只受第一条包含它的规则约束
+    pub kinds: Vec<KindRate>,
+    /// 同一个 IP 的连接共被限流多少次后断开并封禁这个 IP，0 表示不封禁
+    pub max_strikes: u32,
+    /// 封禁的时长（秒）
+    pub ban_secs: u64,
+}
+
+impl Default for RateLimitConfig {
+    fn default() -> Self {
+        RateLimitConfig {
+            ip: MessageRates {
+                event: Rate::new(20.0, 100.0),
+                req: Rate::new(10.0, 50.0),
+                count: Rate::new(10.0, 50.0),
+                auth: Rate::new(1.0, 5.0),
+            },
+            pubkey: MessageRates::default(),
+            kinds: vec![],
+            max_strikes: 50,
+            ban_secs: 600,
+        }
+    }
+}
+
+/// 管理接口
+#[derive(Deserialize, Debug, Clone, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct AdminConfig {
+    /// 访问 `/admin/*` 时 `Authorization: Bearer` 需要携带的 token，为空时关闭管理接口
+    pub token: String,
+}
+
+/// Prometheus 指标
+#[derive(Deserialize, Debug, Clone, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct MetricsConfig {
+    /// 是否提供 `GET /metrics`，默认关闭
+    pub enabled: bool,
+    /// 抓取时 `Authorization: Bearer` 需要携带的 token，为空时不需要认证
+    pub token: String,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct LoggingConfig {
+    /// env_logger 的过滤规则，例如 `info` 或 `nostr=debug`
+    pub level: String,
+}
+
+impl Default for LoggingConfig {
+    fn default() -> Self {
+        LoggingConfig {
+            level: "info".to_string(),
+        }
+    }
+}
+
+impl Config {
+    pub fn from_file(path: &PathBuf) -> Result<Config, Error> {
+        let content = fs::read_to_string(path).map_err(|e| Error::Read(path.clone(), e))?;
+        toml::from_str(&content).map_err(|e| Error::Parse(path.clone(), e))
+    }
+
+    /// 从 self 换成 new 时，哪些改动的配置项只有重启后才能生效
+    ///
+    /// 其余的配置项在重新加载后立即生效，[`Config::new_connections_only`] 中的除外
+    pub fn restart_required(&self, new: &Config) -> Vec<&'static str> {
+        // batch_size 和 batch_linger_ms 每批写入时重新读取
+        let database = DatabaseConfig {
+            batch_size: self.database.batch_size,
+            batch_linger_ms: self.database.batch_linger_ms,
+            ..new.database.clone()
+        };
+        let (old, limits) = (&self.limits, &new.limits);
+        // 证书文件每次重新加载时都会重新读取，只有监听地址需要重启
+        let tls_listen =
+            |network: &NetworkConfig| network.tls.as_ref().map(|tls| tls.listen.clone());
+        [
+            ("network.listen", self.network.listen != new.network.listen),
+            (
+                "network.tls.listen",
+                tls_listen(&self.network) != tls_listen(&new.network),
+            ),
+            ("database", self.database != database),
+            (
+                "limits.subscriber_channel_size",
+                old.subscriber_channel_size != limits.subscriber_channel_size,
+            ),
+            (
+                "limits.broadcast_channel_size",
+                old.broadcast_channel_size != limits.broadcast_channel_size,
+            ),
+            (
+                "limits.seen_cache_size",
+                old.seen_cache_size != limits.seen_cache_size,
+            ),
+            (
+                "limits.verify_workers",
+                old.verify_workers != limits.verify_workers,
+            ),
+            ("logging.level", self.logging.level != new.logging.level),
+        ]
+        .into_iter()
+        .filter_map(|(name, changed)| changed.then_some(name))
+        .collect()
+    }
+
+    /// 从 self 换成 new 时，哪些改动的配置项只影响之后的新连接
+    ///
+    /// ping 和超时参数在连接开始时读取，消息大小上限在 websocket 握手时确定
+    pub fn new_connections_only(&self, new: &Config) -> Vec<&'static str> {
+        let (old, network) = (&self.network, &new.network);
+        [
+            (
+                "network.ping_interval",
+                old.ping_interval != network.ping_interval,
+            ),
+            (
+                "network.pong_timeout",
+                old.pong_timeout != network.pong_timeout,
+            ),
+            (
+                "network.idle_timeout",
+                old.idle_timeout != network.idle_timeout,
+            ),
+            (
+                "limits.max_message_length",
+                self.limits.max_message_length != new.limits.max_message_length,
+            ),
+        ]
+        .into_iter()
+        .filter_map(|(name, changed)| changed.then_some(name))
+        .collect()
+    }
+
+    /// 对外发布的 NIP-11 文档，带上限制和保留策略
+    pub fn relay_information(&self) -> RelayInformation {
+        let limits = &self.limits;
+        let limit = |v: usize| (v > 0).then_some(v);
+        RelayInformation {
+            limitation: Some(Limitation {
+                max_message_length: limit(limits.max_message_length),
+                max_subscriptions: limit(limits.max_subscriptions),
+                max_filters: limit(limits.max_filters),
+                max_limit: limit(limits.max_limit),
+                max_subid_length: limit(limits.max_subid_length),
+                max_event_tags: limit(limits.max_event_tags),
+                max_content_length: limit(limits.max_content_length),
+                auth_required: self.auth.required,
+            }),
+            retention: self.retention.rules.clone(),
+            ..self.info.clone()
+        }
+    }
+
+    pub fn validate(&self) -> Result<(), Error> {
+        let tls_listen = self.network.tls.iter().flat_map(|tls| &tls.listen);
+        if self.network.listen.is_empty() && tls_listen.clone().next().is_none() {
+            return Err(Error::Invalid(
+                "network.listen",
+                "at least one listen address is required".to_string(),
+            ));
+        }
+        for addr in &self.network.listen {
+            ListenAddr::from_str(addr).map_err(|e| Error::Invalid("network.listen", e))?;
+        }
+        for addr in tls_listen.clone() {
+            ListenAddr::from_str(addr).map_err(|e| Error::Invalid("network.tls.listen", e))?;
+        }
+
+        // Unix socket 上的连接都来自本机的代理，总是受信任
+        let local = self.network.listen.iter().chain(tls_listen).any(|addr| {
+            matches!(
+                ListenAddr::from_str(addr),
+                Ok(ListenAddr::Unix(_) | ListenAddr::Systemd(_))
+            )
+        });
+        if self.network.proxy_protocol && self.network.trusted_proxies.is_empty() && !local {
+            return Err(Error::Invalid(
+                "network.proxy_protocol",
+                "set network.trusted_proxies to accept PROXY protocol headers".to_string(),
+            ));
+        }
+
+        let relay_url = Url::parse(&self.network.relay_url)
+            .map_err(|e| Error::Invalid("network.relay_url", e.to_string()))?;
+        if !matches!(relay_url.scheme(), "ws" | "wss") {
+            return Err(Error::Invalid(
+                "network.relay_url",
+                format!("scheme must be ws or wss, got {}", relay_url.scheme()),
+            ));
+        }
+
+        let limits = &self.limits;
+        for (name, value) in [
+            (
+                "limits.subscriber_channel_size",
+                limits.subscriber_channel_size,
+            ),
+            (
+                "limits.broadcast_channel_size",
+                limits.broadcast_channel_size,
+            ),
+            ("limits.seen_cache_size", limits.seen_cache_size),
+            (
+                "database.read_connections",
+                self.database.read_connections as usize,
+            ),
+            ("database.batch_size", self.database.batch_size),
+        ] {
+            if value == 0 {
+                return Err(Error::Invalid(name, "must be greater than 0".to_string()));
+            }
+        }
+
+        if self.auth.challenge.is_empty() {
+            return Err(Error::Invalid(
+                "auth.challenge",
+                "must not be empty".to_string(),
+            ));
+        }
+
+        if let Some(pubkey) = &self.info.pubkey {
+            PublicKey::try_from_hex_string(pubkey)
+                .map_err(|e| Error::Invalid("info.pubkey", e.to_string()))?;
+        }
+
+        for pubkey in &self.retention.exempt_pubkeys {
+            PublicKey::try_from_hex_string(pubkey)
+                .map_err(|e| Error::Invalid("retention.exempt_pubkeys", e.to_string()))?;
+        }
+        for o in &self.quota.overrides {
+            PublicKey::try_from_hex_string(&o.pubkey)
+                .map_err(|e| Error::Invalid("quota.overrides", e.to_string()))?;
+        }
+        let limits = &self.rate_limit;
+        let rates = [&limits.ip, &limits.pubkey]
+            .into_iter()
+            .flat_map(|r| [r.event, r.req, r.count, r.auth])
+            .chain(limits.kinds.iter().map(|k| k.rate()));
+        for rate in rates {
+            if !rate.is_unlimited() && rate.burst < 1.0 {
+                return Err(Error::Invalid(
+                    "rate_limit",
+                    "burst must be at least 1 when rate is set".to_string(),
+                ));
+            }
+        }
+
+        for rule in &self.retention.rules {
+            if rule.time.is_none() && rule.count.is_none() {
+                return Err(Error::Invalid(
+                    "retention.rules",
+                    "each rule needs time or count".to_string(),
+                ));
+            }
+            if let Some(range) = rule.kinds.iter().find(|k| k.bounds().0 > k.bounds().1) {
+                return Err(Error::Invalid(
+                    "retention.rules",
+                    format!("kind range {:?} is empty", range),
+                ));
+            }
+        }
+
+        // env_logger 遇到错误的规则只会打印警告，这里先检查最常见的整体级别写法
+        let level = self.logging.level.trim();
+        if !level.contains(['=', ',', '/']) && LevelFilter::from_str(level).is_err() {
+            return Err(Error::Invalid(
+                "logging.level",
+                format!("unknown log level {}", level),
+            ));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_config() {
+        let mut config: Config = toml::from_str(
+            r#"
+            [network]
+            listen = ["0.0.0.0:7000"]
+
+            [info]
+            name = "ksana"
+
+            [database]
+            url = "sqlite://ksana.db"
+
+            [[retention.rules]]
+            kinds = [1, [30000, 39999]]
+            time = 3600
+            "#,
+        )
+        .unwrap();
+        config.validate().unwrap();
+        assert_eq!(config.network.listen, vec!["0.0.0.0:7000"]);
+        assert_eq!(config.network.relay_url, "wss://relay.ksana.net");
+        assert_eq!(config.info.name.as_deref(), Some("ksana"));
+        assert_eq!(config.limits.subscriber_channel_size, 32);
+        assert_eq!(config.database.backend, StoreBackend::Sqlite);
+        let info = serde_json::to_value(config.relay_information()).unwrap();
+        assert_eq!(
+            info["retention"],
+            serde_json::json!([{"kinds": [1, [30000, 39999]], "time": 3600}])
+        );
+        assert_eq!(info["limitation"]["max_subid_length"], 64);
+        assert_eq!(info["limitation"]["auth_required"], true);
+
+        config.network.relay_url = "https://relay.ksana.net".to_string();
+        assert!(matches!(
+            config.validate(),
+            Err(Error::Invalid("network.relay_url", _))
+        ));
+
+        assert!(toml::from_str::<Config>("[network]\nlisten_addr = []").is_err());
+        let config: Config = toml::from_str("[database]\nbackend = \"memory\"").unwrap();
+        assert_eq!(config.database.backend, StoreBackend::Memory);
+    }
+
+    #[test]
+    fn test_validate_rate_burst() {
+        let mut config: Config = toml::from_str("[database]\nbackend = \"memory\"").unwrap();
+        config.validate().unwrap();
+        // burst 小于 1 时桶里永远不够一个令牌，每条消息都会被限流
+        config.rate_limit.pubkey.count = Rate::new(1.0, 0.5);
+        assert!(matches!(
+            config.validate(),
+            Err(Error::Invalid("rate_limit", _))
+        ));
+    }
+}
@@ -0,0 +1,225 @@
+use super::{Database, Error};
+use crate::nostr::Event;
+use log::info;
+use serde::Serialize;
+use sqlx::{sqlite::SqliteConnectOptions, ConnectOptions, Connection};
+use std::{
+    fs, io,
+    path::{Path, PathBuf},
+    time::{SystemTime, UNIX_EPOCH},
+};
+use tokio::task;
+
+/// 快照文件名的前缀和后缀，轮转时只处理符合这个格式的文件
+const SNAPSHOT_PREFIX: &str = "ksana-";
+const SNAPSHOT_SUFFIX: &str = ".db";
+
+/// 一次备份的结果
+#[derive(Debug, Clone, Serialize)]
+pub struct BackupReport {
+    /// 快照文件
+    pub path: PathBuf,
+    /// 快照中的 event 数
+    pub events: u64,
+    /// 备份完成后源数据库中仍然存在的、快照时已经写入的 event 数
+    pub source_events: u64,
+    /// 抽样校验过签名的 event 数
+    pub verified: usize,
+    /// 轮转时删除的旧快照
+    pub removed: Vec<PathBuf>,
+}
+
+impl Database {
+    /// 在 relay 运行时把数据库的一致快照写到 dir 中
+    ///
+    /// 快照先写到临时文件，校验通过后才改成正式的文件名，然后只保留最新的 keep 个快照，
+    /// keep 为 0 时不删除旧快照。
+    ///
+    /// 校验时除了检查快照本身，还和源数据库比较 event 数：rowid 只增不减，源数据库中 rowid
+    /// 不大于快照中最大 rowid 的 event 都是快照之前写入的，快照中的 event 不能比它们少。
+    /// 备份期间删除的 event 会让源数据库的数量变少，不会造成误报
+    pub async fn backup(
+        &self,
+        dir: &Path,
+        keep: usize,
+        sample_size: usize,
+    ) -> Result<BackupReport, Error> {
+        tokio::fs::create_dir_all(dir).await?;
+        let millis = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_millis();
+        let name = format!("{}{}{}", SNAPSHOT_PREFIX, millis, SNAPSHOT_SUFFIX);
+        let path = dir.join(&name);
+        let tmp = dir.join(format!("{}.tmp", name));
+
+        // VACUUM INTO 在一个读事务中完成，不会阻塞写入
+        sqlx::query("VACUUM INTO ?")
+            .bind(tmp.to_string_lossy().as_ref())
+            .execute(&self.reader)
+            .await?;
+        let (events, source_events, verified) = match self.verify_backup(&tmp, sample_size).await {
+            Ok(r) => r,
+            Err(e) => {
+                let _ = tokio::fs::remove_file(&tmp).await;
+                return Err(e);
+            }
+        };
+        tokio::fs::rename(&tmp, &path).await?;
+        info!(
+            "backup to {} with {} events, {} signatures verified",
+            path.display(),
+            events,
+            verified
+        );
+
+        let removed = if keep > 0 {
+            let dir = dir.to_path_buf();
+            task::spawn_blocking(move || rotate(&dir, keep))
+                .await
+                .map_err(|e| Error::Backup(format!("rotate backups faild: {}", e)))??
+        } else {
+            vec![]
+        };
+        Ok(BackupReport {
+            path,
+            events,
+            source_events,
+            verified,
+            removed,
+        })
+    }
+
+    /// 校验快照，并和源数据库比较 event 数，返回快照的 event 数、源数据库的 event 数和校验过签名的数量
+    async fn verify_backup(
+        &self,
+        path: &Path,
+        sample_size: usize,
+    ) -> Result<(u64, u64, usize), Error> {
+        let (events, verified) = verify_snapshot(path, sample_size).await?;
+        let mut conn = SqliteConnectOptions::new()
+            .filename(path)
+            .read_only(true)
+            .connect()
+            .await?;
+        let last_rowid: Option<i64> = sqlx::query_scalar("SELECT MAX(rowid) FROM nostr_events")
+            .fetch_one(&mut conn)
+            .await?;
+        conn.close().await?;
+        let source_events: i64 =
+            sqlx::query_scalar("SELECT COUNT(*) FROM nostr_events WHERE rowid <= ?")
+                .bind(last_rowid.unwrap_or(0))
+                .fetch_one(&self.reader)
+                .await?;
+        let source_events = source_events as u64;
+        if events < source_events {
+            return Err(Error::Backup(format!(
+                "snapshot has {} events, but the source has {}",
+                events, source_events
+            )));
+        }
+        Ok((events, source_events, verified))
+    }
+}
+
+/// 只读打开快照，检查完整性、统计 event 数，并随机抽取 sample_size 个 event 校验签名
+///
+/// 返回 event 数和校验过的 event 数
+pub async fn verify_snapshot(path: &Path, sample_size: usize) -> Result<(u64, usize), Error> {
+    let mut conn = SqliteConnectOptions::new()
+        .filename(path)
+        .read_only(true)
+        .connect()
+        .await?;
+    let integrity: String = sqlx::query_scalar("PRAGMA integrity_check")
+        .fetch_one(&mut conn)
+        .await?;
+    if integrity != "ok" {
+        return Err(Error::Backup(format!(
+            "integrity check faild: {}",
+            integrity
+        )));
+    }
+    let events: i64 = sqlx::query_scalar("SELECT COUNT(*) FROM nostr_events")
+        .fetch_one(&mut conn)
+        .await?;
+    let samples: Vec<Option<String>> =
+        sqlx::query_scalar("SELECT raw FROM nostr_events ORDER BY RANDOM() LIMIT ?")
+            .bind(sample_size as i64)
+            .fetch_all(&mut conn)
+            .await?;
+    for raw in &samples {
+        let raw = raw
+            .as_deref()
+            .ok_or_else(|| Error::Backup("raw event not found in snapshot".to_string()))?;
+        let event = Event::from_raw(raw)?;
+        event.verify().map_err(|e| {
+            Error::Backup(format!(
+                "event {} verify faild: {}",
+                event.id().as_hex_string(),
+                e
+            ))
+        })?;
+    }
+    conn.close().await?;
+    Ok((events as u64, samples.len()))
+}
+
+/// 删除最旧的快照，只保留 keep 个，返回被删除的文件
+///
+/// 使用同步的文件操作，需要在 blocking 线程中调用
+fn rotate(dir: &Path, keep: usize) -> io::Result<Vec<PathBuf>> {
+    let mut snapshots: Vec<PathBuf> = fs::read_dir(dir)?
+        .filter_map(|entry| entry.ok().map(|e| e.path()))
+        .filter(|path| {
+            path.file_name()
+                .and_then(|name| name.to_str())
+                .is_some_and(|name| {
+                    name.starts_with(SNAPSHOT_PREFIX) && name.ends_with(SNAPSHOT_SUFFIX)
+                })
+        })
+        .collect();
+    // 文件名中的时间戳位数相同，按名字排序就是按时间排序
+    snapshots.sort();
+    let count = snapshots.len().saturating_sub(keep);
+    let removed: Vec<PathBuf> = snapshots.drain(..count).collect();
+    for path in &removed {
+        fs::remove_file(path)?;
+        info!("remove old backup {}", path.display());
+    }
+    Ok(removed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        database::EventStore,
+        nostr::{EventKind, PreEvent, PrivateKey},
+    };
+
+    #[tokio::test]
+    async fn test_backup_rotate() {
+        let base = std::env::temp_dir().join(format!("ksana-backup-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&base);
+        fs::create_dir_all(&base).unwrap();
+        let db = Database::connect(&format!("sqlite://{}", base.join("relay.db").display()))
+            .await
+            .unwrap();
+        let key = PrivateKey::gen();
+        let pre = PreEvent::new(key.public_key(), EventKind::TextNote, vec![], "hi".into());
+        db.save(&Event::new(pre, &key).unwrap()).await.unwrap();
+
+        let dir = base.join("backups");
+        let mut reports = vec![];
+        for _ in 0..3 {
+            reports.push(db.backup(&dir, 2, 10).await.unwrap());
+            tokio::time::sleep(std::time::Duration::from_millis(2)).await;
+        }
+        assert_eq!((reports[2].events, reports[2].verified), (1, 1));
+        assert_eq!(reports[2].source_events, 1);
+        assert_eq!(reports[2].removed, vec![reports[0].path.clone()]);
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 2);
+        let _ = fs::remove_dir_all(&base);
+    }
+}
@@ -0,0 +1,318 @@
+use super::{Database, Error, INDEXED_TAGS_SQL};
+use crate::nostr::{Event, Unixtime};
+use log::{info, warn};
+use sqlx::{sqlite::SqliteRow, Row};
+
+/// 每次读取的 event 数
+const CHECK_PAGE: i64 = 1000;
+
+/// 发现损坏的 event 时怎么处理
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CheckAction {
+    /// 只报告
+    Report,
+    /// 移到 quarantined_events 表中，并修复 tag 索引
+    Quarantine,
+    /// 直接删除，并修复 tag 索引
+    Delete,
+}
+
+/// 检查结果
+#[derive(Debug, Default, Clone)]
+pub struct CheckReport {
+    /// 检查过的 event 数
+    pub checked: u64,
+    /// 损坏的 event 数
+    pub bad_events: u64,
+    /// tag 索引与 event 不一致的 event 数
+    pub bad_indexes: u64,
+    /// 没有对应 event 的 tag 索引行数
+    pub orphan_tags: u64,
+    /// 已经隔离、删除或修复的问题数
+    pub fixed: u64,
+}
+
+impl CheckReport {
+    /// 是否还有没处理的问题
+    pub fn has_problems(&self) -> bool {
+        self.bad_events + self.bad_indexes + self.orphan_tags > self.fixed
+    }
+}
+
+impl Database {
+    /// 逐行检查所有 event：原始 JSON、id 和签名、各列与原始 JSON 是否一致，以及 tag 索引
+    ///
+    /// 每个问题都会打印到日志中，action 不是 Report 时同时处理
+    pub async fn check(&self, action: CheckAction) -> Result<CheckReport, Error> {
+        let mut report = CheckReport::default();
+        // 按 rowid 分页读取，检查和修复时不会有一直占用的连接；只有一个连接时也不会互相等待
+        let mut last_rowid = i64::MIN;
+        loop {
+            let rows = sqlx::query(
+                "SELECT rowid, id, pubkey, created_at, kind, tags, content, sig, raw FROM nostr_events
+                WHERE rowid > ? ORDER BY rowid LIMIT ?",
+            )
+            .bind(last_rowid)
+            .bind(CHECK_PAGE)
+            .fetch_all(&self.reader)
+            .await?;
+            let Some(last) = rows.last() else {
+                break;
+            };
+            last_rowid = last.try_get("rowid")?;
+            for row in &rows {
+                self.check_one(row, action, &mut report).await?;
+            }
+        }
+
+        let orphans: i64 = sqlx::query_scalar(
+            "SELECT COUNT(*) FROM tags WHERE event_id NOT IN (SELECT id FROM nostr_events)",
+        )
+        .fetch_one(&self.reader)
+        .await?;
+        if orphans > 0 {
+            warn!("{} tag index rows have no event", orphans);
+            report.orphan_tags = orphans as u64;
+            if action != CheckAction::Report {
+                sqlx::query("DELETE FROM tags WHERE event_id NOT IN (SELECT id FROM nostr_events)")
+                    .execute(&self.writer)
+                    .await?;
+                report.fixed += orphans as u64;
+            }
+        }
+        Ok(report)
+    }
+
+    /// 检查一行 event，结果记在 report 中
+    async fn check_one(
+        &self,
+        row: &SqliteRow,
+        action: CheckAction,
+        report: &mut CheckReport,
+    ) -> Result<(), Error> {
+        report.checked += 1;
+        let rowid: i64 = row.try_get("rowid")?;
+        let event = match check_row(row) {
+            Ok(event) => event,
+            Err(reason) => {
+                warn!("event at rowid {} is bad: {}", rowid, reason);
+                report.bad_events += 1;
+                if action != CheckAction::Report {
+                    self.remove_bad_row(rowid, &reason, action).await?;
+                    report.fixed += 1;
+                }
+                return Ok(());
+            }
+        };
+        if !self.tag_index_consistent(&event).await? {
+            warn!(
+                "tag index of event {} is inconsistent",
+                event.id().as_hex_string()
+            );
+            report.bad_indexes += 1;
+            if action != CheckAction::Report {
+                self.rebuild_tag_index(&event).await?;
+                report.fixed += 1;
+            }
+        }
+        if report.checked.is_multiple_of(10000) {
+            info!("checked {} events", report.checked);
+        }
+        Ok(())
+    }
+
+    /// tags 表中该 event 的索引是否与按 event 的 tag 计算出来的一致
+    async fn tag_index_consistent(&self, event: &Event) -> Result<bool, Error> {
+        let id = &event.id().0[..];
+        let stored: Vec<(String, String, i64)> = sqlx::query_as(
+            "SELECT name, value, position FROM tags WHERE event_id = ? ORDER BY position",
+        )
+        .bind(id)
+        .fetch_all(&self.reader)
+        .await?;
+        let expected: Vec<(Vec<u8>, String, String, i64)> =
+            sqlx::query_as(&format!("{} ORDER BY key", INDEXED_TAGS_SQL))
+                .bind(id)
+                .bind(event.raw())
+                .fetch_all(&self.reader)
+                .await?;
+        Ok(stored.len() == expected.len()
+            && stored
+                .iter()
+                .zip(&expected)
+                .all(|((n, v, p), (_, en, ev, ep))| n == en && v == ev && p == ep))
+    }
+
+    async fn rebuild_tag_index(&self, event: &Event) -> Result<(), Error> {
+        let id = &event.id().0[..];
+        let mut tx = self.writer.begin().await?;
+        sqlx::query("DELETE FROM tags WHERE event_id = ?")
+            .bind(id)
+            .execute(&mut *tx)
+            .await?;
+        Self::insert_tags(&mut tx, id, event.raw()).await?;
+        tx.commit().await?;
+        Ok(())
+    }
+
+    async fn remove_bad_row(
+        &self,
+        rowid: i64,
+        reason: &str,
+        action: CheckAction,
+    ) -> Result<(), Error> {
+        let mut tx = self.writer.begin().await?;
+        if action == CheckAction::Quarantine {
+            sqlx::query(
+                r#"
+                INSERT INTO quarantined_events
+                    (id, pubkey, created_at, kind, tags, content, sig, raw, reason, quarantined_at)
+                SELECT id, pubkey, created_at, kind, tags, content, sig, raw, ?, ?
+                FROM nostr_events WHERE rowid = ?
+                "#,
+            )
+            .bind(reason)
+            .bind(Unixtime::now().0)
+            .bind(rowid)
+            .execute(&mut *tx)
+            .await?;
+        }
+        sqlx::query("DELETE FROM nostr_events WHERE rowid = ?")
+            .bind(rowid)
+            .execute(&mut *tx)
+            .await?;
+        tx.commit().await?;
+        Ok(())
+    }
+}
+
+/// 解析并校验一行 event，返回损坏的原因
+fn check_row(row: &SqliteRow) -> Result<Event, String> {
+    let raw: Option<String> = row.try_get("raw").map_err(|e| format!("bad raw: {}", e))?;
+    let raw = raw.ok_or("raw event is missing")?;
+    let event = Event::from_raw(&raw).map_err(|e| format!("bad raw json: {}", e))?;
+    event.verify().map_err(|e| format!("verify faild: {}", e))?;
+
+    // 查询和索引使用的列必须与原始 JSON 一致
+    let id: Vec<u8> = row.try_get("id").map_err(|e| format!("bad id: {}", e))?;
+    let pubkey: Vec<u8> = row
+        .try_get("pubkey")
+        .map_err(|e| format!("bad pubkey: {}", e))?;
+    let created_at: i64 = row
+        .try_get("created_at")
+        .map_err(|e| format!("bad created_at: {}", e))?;
+    let kind: i64 = row
+        .try_get("kind")
+        .map_err(|e| format!("bad kind: {}", e))?;
+    let tags: String = row
+        .try_get("tags")
+        .map_err(|e| format!("bad tags: {}", e))?;
+    let tags: serde_json::Value =
+        serde_json::from_str(&tags).map_err(|e| format!("bad tags json: {}", e))?;
+    let expected_tags = serde_json::to_value(event.tags()).map_err(|e| e.to_string())?;
+    let mismatched = [
+        ("id", id != event.id().0),
+        ("pubkey", pubkey != event.pubkey().0),
+        ("created_at", created_at != event.created_at().0),
+        ("kind", kind as u64 != u64::from(event.kind())),
+        ("tags", tags != expected_tags),
+    ];
+    match mismatched.iter().find(|(_, bad)| *bad) {
+        Some((column, _)) => Err(format!("column {} does not match raw event", column)),
+        None => Ok(event),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        database::EventStore,
+        nostr::{EventKind, PreEvent, PrivateKey},
+    };
+
+    #[tokio::test]
+    async fn test_check() {
+        let path = std::env::temp_dir().join(format!("ksana-check-{}.db", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let db = Database::connect(&format!("sqlite://{}", path.display()))
+            .await
+            .unwrap();
+        let key = PrivateKey::gen();
+        for content in ["good", "bad"] {
+            let pre = PreEvent::new(
+                key.public_key(),
+                EventKind::TextNote,
+                vec![],
+                content.into(),
+            );
+            db.save(&Event::new(pre, &key).unwrap()).await.unwrap();
+        }
+        // 篡改内容让签名失效，再插入一行孤立的 tag 索引
+        sqlx::query("UPDATE nostr_events SET raw = replace(raw, '\"bad\"', '\"evil\"')")
+            .execute(&db.writer)
+            .await
+            .unwrap();
+        sqlx::query("PRAGMA foreign_keys = OFF")
+            .execute(&db.writer)
+            .await
+            .unwrap();
+        sqlx::query("INSERT INTO tags VALUES (x'00', 'p', 'x', 0)")
+            .execute(&db.writer)
+            .await
+            .unwrap();
+
+        let report = db.check(CheckAction::Report).await.unwrap();
+        assert_eq!(
+            (report.checked, report.bad_events, report.orphan_tags),
+            (2, 1, 1)
+        );
+        assert!(report.has_problems());
+
+        let report = db.check(CheckAction::Quarantine).await.unwrap();
+        assert!(!report.has_problems());
+        let quarantined: i64 = sqlx::query_scalar("SELECT COUNT(*) FROM quarantined_events")
+            .fetch_one(&db.reader)
+            .await
+            .unwrap();
+        assert_eq!(quarantined, 1);
+        let report = db.check(CheckAction::Report).await.unwrap();
+        assert_eq!(
+            (report.checked, report.bad_events, report.orphan_tags),
+            (1, 0, 0)
+        );
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[tokio::test]
+    async fn test_check_single_connection() {
+        // 内存数据库的读写共用一个连接
+        let db = Database::connect("sqlite::memory:").await.unwrap();
+        let key = PrivateKey::gen();
+        for content in ["good", "bad"] {
+            let pre = PreEvent::new(
+                key.public_key(),
+                EventKind::TextNote,
+                vec![],
+                content.into(),
+            );
+            db.save(&Event::new(pre, &key).unwrap()).await.unwrap();
+        }
+        sqlx::query("UPDATE nostr_events SET raw = replace(raw, '\"bad\"', '\"evil\"')")
+            .execute(&db.writer)
+            .await
+            .unwrap();
+        sqlx::query("DELETE FROM tags")
+            .execute(&db.writer)
+            .await
+            .unwrap();
+
+        let check = db.check(CheckAction::Delete);
+        let report = tokio::time::timeout(std::time::Duration::from_secs(10), check)
+            .await
+            .expect("check should not wait on its own connection")
+            .unwrap();
+        assert_eq!((report.checked, report.bad_events), (2, 1));
+        assert!(!report.has_problems());
+    }
+}
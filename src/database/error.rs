@@ -7,4 +7,30 @@ pub enum Error {
 
     #[error("database serde faild: {0}")]
     DatabaseSerdeJsonFaild(#[from] serde_json::Error),
+
+    #[error("database migrate faild: {0}")]
+    Migrate(#[from] sqlx::migrate::MigrateError),
+
+    #[error(
+        "database schema version {0} is newer than the latest known version {1}, upgrade the relay"
+    )]
+    SchemaTooNew(i64, i64),
+
+    #[error("database schema version {0} is older than {1}, migrations are not applied")]
+    SchemaOutdated(i64, i64),
+
+    #[error("event {0} already exists")]
+    Duplicate(String),
+
+    #[error("database write batch faild: {0}")]
+    BatchFaild(String),
+
+    #[error("database backup faild: {0}")]
+    Backup(String),
+
+    #[error("io faild: {0}")]
+    Io(#[from] std::io::Error),
+
+    #[error("database event decode faild: {0}")]
+    DecodeEvent(#[from] crate::nostr::Error),
 }
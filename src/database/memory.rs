@@ -0,0 +1,174 @@
+use super::{
+    store::{is_newer, same_slot},
+    Error, EventStore,
+};
+use crate::{
+    nostr::{Event, Filter, Id, PublicKey},
+    relay::EventFilter,
+};
+use async_trait::async_trait;
+use std::{
+    cmp::Reverse,
+    collections::{HashMap, HashSet},
+    sync::RwLock,
+};
+
+/// 纯内存的 event 存储，用于测试和不需要持久化的 relay
+#[derive(Default)]
+pub struct MemoryStore {
+    events: RwLock<HashMap<Id, Event>>,
+}
+
+impl MemoryStore {
+    pub fn new() -> Self {
+        MemoryStore::default()
+    }
+}
+
+#[async_trait]
+impl EventStore for MemoryStore {
+    async fn save(&self, event: &Event) -> Result<(), Error> {
+        let mut events = self.events.write().expect("memory store lock poisoned");
+        if events.contains_key(&event.id()) {
+            return Err(Error::Duplicate(event.id().as_hex_string()));
+        }
+        events.insert(event.id(), event.clone());
+        Ok(())
+    }
+
+    async fn query(&self, filters: &[Filter]) -> Result<Vec<Event>, Error> {
+        let events = self.events.read().expect("memory store lock poisoned");
+        let mut ids = HashSet::new();
+        let mut result = vec![];
+        for filter in filters {
+            let mut matched: Vec<&Event> = events
+                .values()
+                .filter(|e| EventFilter::filter(e, filter))
+                .collect();
+            matched.sort_by_key(|e| Reverse(e.created_at().0));
+            if let Some(limit) = filter.limit {
+                matched.truncate(limit);
+            }
+            for e in matched {
+                if ids.insert(e.id()) {
+                    result.push(e.clone());
+                }
+            }
+        }
+        result.sort_by_key(|e| Reverse(e.created_at().0));
+        Ok(result)
+    }
+
+    async fn count(&self, filters: &[Filter]) -> Result<u64, Error> {
+        let events = self.events.read().expect("memory store lock poisoned");
+        let count = events
+            .values()
+            .filter(|e| EventFilter::any_filter(e, filters))
+            .count();
+        Ok(count as u64)
+    }
+
+    async fn delete(&self, id: &Id, pubkey: &PublicKey) -> Result<u64, Error> {
+        let mut events = self.events.write().expect("memory store lock poisoned");
+        match events.get(id) {
+            Some(e) if e.pubkey() == pubkey => {
+                events.remove(id);
+                Ok(1)
+            }
+            _ => Ok(0),
+        }
+    }
+
+    async fn replace(&self, event: &Event) -> Result<bool, Error> {
+        let mut events = self.events.write().expect("memory store lock poisoned");
+        let old: Vec<&Event> = events.values().filter(|e| same_slot(e, event)).collect();
+        if old.iter().any(|e| is_newer(e, event)) {
+            return Ok(false);
+        }
+        let old_ids: Vec<Id> = old.iter().map(|e| e.id()).collect();
+        for id in old_ids {
+            events.remove(&id);
+        }
+        events.insert(event.id(), event.clone());
+        Ok(true)
+    }
+
+    async fn exists(&self, id: &Id) -> Result<bool, Error> {
+        Ok(self
+            .events
+            .read()
+            .expect("memory store lock poisoned")
+            .contains_key(id))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::nostr::{EventKind, PreEvent, PrivateKey, Tag, Unixtime};
+
+    fn event(key: &PrivateKey, kind: EventKind, created_at: i64, tags: Vec<Tag>) -> Event {
+        let mut pre = PreEvent::new(key.public_key(), kind, tags, "".to_string());
+        pre.created_at = Unixtime(created_at);
+        Event::new(pre, key).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_memory_store() {
+        let store = MemoryStore::new();
+        let key = PrivateKey::gen();
+        let note1 = event(&key, EventKind::TextNote, 100, vec![]);
+        let note2 = event(&key, EventKind::TextNote, 200, vec![]);
+        store.save(&note1).await.unwrap();
+        store.save(&note2).await.unwrap();
+        assert!(store.exists(&note1.id()).await.unwrap());
+        // 和 SQLite 一样，重复保存返回 Duplicate
+        assert!(matches!(store.save(&note1).await, Err(Error::Duplicate(_))));
+
+        let all = store.query(&[Filter::default()]).await.unwrap();
+        assert_eq!(
+            all.iter().map(|e| e.id()).collect::<Vec<_>>(),
+            vec![note2.id(), note1.id()]
+        );
+        let limited = Filter {
+            limit: Some(1),
+            ..Default::default()
+        };
+        assert_eq!(store.query(&[limited]).await.unwrap()[0].id(), note2.id());
+        assert_eq!(store.count(&[Filter::default()]).await.unwrap(), 2);
+
+        // 只能删除自己的 event
+        let other = PrivateKey::gen();
+        assert_eq!(
+            store
+                .delete(&note1.id(), &other.public_key())
+                .await
+                .unwrap(),
+            0
+        );
+        assert_eq!(
+            store.delete(&note1.id(), &key.public_key()).await.unwrap(),
+            1
+        );
+
+        // 旧的 metadata 不能替换新的
+        let meta_new = event(&key, EventKind::Metadata, 300, vec![]);
+        let meta_old = event(&key, EventKind::Metadata, 250, vec![]);
+        assert!(store.replace(&meta_new).await.unwrap());
+        assert!(!store.replace(&meta_old).await.unwrap());
+        let newer = event(&key, EventKind::Metadata, 400, vec![]);
+        assert!(store.replace(&newer).await.unwrap());
+        assert!(!store.exists(&meta_new.id()).await.unwrap());
+
+        // 参数化的 replaceable event 按 d tag 区分
+        let d = |v: &str| Tag::Other {
+            tag: "d".to_string(),
+            data: vec![v.to_string()],
+        };
+        let a = event(&key, EventKind::Other(30001), 100, vec![d("a")]);
+        let b = event(&key, EventKind::Other(30001), 200, vec![d("b")]);
+        assert!(store.replace(&a).await.unwrap());
+        assert!(store.replace(&b).await.unwrap());
+        assert!(store.exists(&a.id()).await.unwrap());
+    }
+}
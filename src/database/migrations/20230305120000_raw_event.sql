@@ -0,0 +1,13 @@
+-- 保存 event 收到时的原始 JSON，查询时原样返回
+ALTER TABLE nostr_events ADD COLUMN raw TEXT;
+
+-- 旧数据没有原始 JSON，用已有的列拼出来
+UPDATE nostr_events SET raw = json_object(
+    'id', lower(hex(id)),
+    'pubkey', lower(hex(pubkey)),
+    'created_at', created_at,
+    'kind', kind,
+    'tags', json(tags),
+    'content', content,
+    'sig', lower(hex(sig))
+) WHERE raw IS NULL;
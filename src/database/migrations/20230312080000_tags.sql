@@ -0,0 +1,17 @@
+-- 单字母 tag 单独建表，按 tag 查询时不再需要解析每一行的 JSON
+CREATE TABLE IF NOT EXISTS tags (
+    event_id BLOB NOT NULL REFERENCES nostr_events (id) ON DELETE CASCADE,
+    name TEXT NOT NULL,
+    value TEXT NOT NULL,
+    position INTEGER NOT NULL, -- tag 在 event 中的下标
+    PRIMARY KEY (event_id, position)
+);
+
+CREATE INDEX IF NOT EXISTS tags_name_value ON tags (name, value, event_id);
+
+-- 回填已有 event 的 tag
+INSERT INTO tags (event_id, name, value, position)
+SELECT e.id, json_extract(t.value, '$[0]'), json_extract(t.value, '$[1]'), t.key
+FROM nostr_events e, json_each(e.tags) t
+WHERE json_extract(t.value, '$[0]') GLOB '[A-Za-z]'
+    AND json_type(t.value, '$[1]') = 'text';
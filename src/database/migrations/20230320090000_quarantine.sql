@@ -0,0 +1,13 @@
+-- check 子命令隔离出来的损坏 event，保留原始的列以便人工处理
+CREATE TABLE IF NOT EXISTS quarantined_events (
+    id BLOB,
+    pubkey BLOB,
+    created_at INTEGER,
+    kind INTEGER,
+    tags TEXT,
+    content TEXT,
+    sig BLOB,
+    raw TEXT,
+    reason TEXT NOT NULL,
+    quarantined_at INTEGER NOT NULL -- Unix timestamp
+);
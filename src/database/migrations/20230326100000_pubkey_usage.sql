@@ -0,0 +1,31 @@
+-- 每个作者已经保存的 event 数和原始 JSON 的字节数，由触发器随 event 的增删增量维护
+CREATE TABLE IF NOT EXISTS pubkey_usage (
+    pubkey BLOB PRIMARY KEY,
+    events INTEGER NOT NULL DEFAULT 0,
+    bytes INTEGER NOT NULL DEFAULT 0
+);
+
+CREATE INDEX IF NOT EXISTS pubkey_usage_bytes ON pubkey_usage (bytes);
+
+CREATE TRIGGER IF NOT EXISTS pubkey_usage_insert AFTER INSERT ON nostr_events
+BEGIN
+    INSERT INTO pubkey_usage (pubkey, events, bytes)
+    VALUES (NEW.pubkey, 1, COALESCE(length(CAST(NEW.raw AS BLOB)), 0))
+    ON CONFLICT (pubkey) DO UPDATE SET
+        events = events + 1,
+        bytes = bytes + excluded.bytes;
+END;
+
+CREATE TRIGGER IF NOT EXISTS pubkey_usage_delete AFTER DELETE ON nostr_events
+BEGIN
+    UPDATE pubkey_usage SET
+        events = events - 1,
+        bytes = bytes - COALESCE(length(CAST(OLD.raw AS BLOB)), 0)
+    WHERE pubkey = OLD.pubkey;
+END;
+
+-- 回填已有 event 的用量
+INSERT INTO pubkey_usage (pubkey, events, bytes)
+SELECT pubkey, COUNT(*), SUM(COALESCE(length(CAST(raw AS BLOB)), 0))
+FROM nostr_events
+GROUP BY pubkey;
@@ -0,0 +1,8 @@
+-- tag 索引改为从原始 JSON 建立，tags 列是重新序列化的，可能和作者签名的 tag 不一致
+DELETE FROM tags;
+
+INSERT INTO tags (event_id, name, value, position)
+SELECT e.id, json_extract(t.value, '$[0]'), json_extract(t.value, '$[1]'), t.key
+FROM nostr_events e, json_each(e.raw, '$.tags') t
+WHERE json_extract(t.value, '$[0]') GLOB '[A-Za-z]'
+    AND json_type(t.value, '$[1]') = 'text';
@@ -1,109 +1,675 @@
+mod backup;
+mod check;
 mod error;
+mod memory;
+mod quota;
+mod retention;
+mod store;
+mod transfer;
 
-use crate::nostr::{self, Event, EventKind, Id, PublicKey, Signature, Tag, Unixtime};
+use crate::{
+    config::{DatabaseConfig, Synchronous},
+    metrics::METRICS,
+    nostr::{self, Event, Filter},
+};
+use async_trait::async_trait;
+pub use backup::{verify_snapshot, BackupReport};
+pub use check::{CheckAction, CheckReport};
 pub use error::Error;
-use sqlx::SqlitePool;
+use log::{error, info};
+pub use memory::MemoryStore;
+pub use quota::PubkeyUsage;
+pub use retention::PruneReport;
+use sqlx::{
+    migrate::Migrator,
+    sqlite::{
+        SqliteConnectOptions, SqliteJournalMode, SqlitePoolOptions, SqliteRow, SqliteSynchronous,
+    },
+    QueryBuilder, Row, Sqlite, SqliteConnection, SqlitePool,
+};
+use std::{cmp::Reverse, collections::HashSet, str::FromStr, time::Duration};
+use store::deleted_ids;
+pub(crate) use store::{is_newer, same_slot};
+pub use store::{EventStore, Usage, WriteOp};
+pub use transfer::ImportReport;
 
+/// 编译进二进制的数据库迁移
+static MIGRATOR: Migrator = sqlx::migrate!("src/database/migrations");
+
+/// 从 event 的原始 JSON（?2）的 tags 中选出需要索引的 tag，结果列依次为 event id（?1）、名称、值和下标
+///
+/// 与 [`Event::indexed_tags`] 的规则一致：名称是单个字母，第一个值是字符串
+const INDEXED_TAGS_SQL: &str = r#"
+    SELECT ?1, json_extract(value, '$[0]'), json_extract(value, '$[1]'), key
+    FROM json_each(?2, '$.tags')
+    WHERE json_extract(value, '$[0]') GLOB '[A-Za-z]'
+        AND json_type(value, '$[1]') = 'text'
+"#;
+
+/// 基于 SQLite 的 event 存储
+///
+/// SQLite 同一时间只允许一个写入者，所以写入用单连接的 writer，查询用只读的 reader 连接池
 #[derive(Clone)]
 pub struct Database {
-    pool: SqlitePool,
+    reader: SqlitePool,
+    writer: SqlitePool,
 }
 
 // pub struct DBEvent(Event);
 
 impl Database {
+    /// 使用默认配置连接数据库
     pub async fn connect(url: &str) -> Result<Self, Error> {
-        let pool = SqlitePool::connect(url).await?;
-        Ok(Database { pool })
+        let config = DatabaseConfig {
+            url: url.to_string(),
+            ..Default::default()
+        };
+        Self::connect_with(&config).await
+    }
+
+    /// 连接数据库并执行迁移，数据库文件不存在时自动创建
+    ///
+    /// 同时启动定期执行 `PRAGMA optimize` 和 WAL checkpoint 的任务，需要在 tokio runtime 中调用
+    pub async fn connect_with(config: &DatabaseConfig) -> Result<Self, Error> {
+        let synchronous = match config.synchronous {
+            Synchronous::Off => SqliteSynchronous::Off,
+            Synchronous::Normal => SqliteSynchronous::Normal,
+            Synchronous::Full => SqliteSynchronous::Full,
+            Synchronous::Extra => SqliteSynchronous::Extra,
+        };
+        let options = SqliteConnectOptions::from_str(&config.url)?
+            .synchronous(synchronous)
+            .busy_timeout(Duration::from_millis(config.busy_timeout_ms))
+            .pragma("cache_size", format!("-{}", config.cache_size_kib))
+            .pragma("mmap_size", config.mmap_size.to_string());
+        let journal_mode = if config.wal {
+            SqliteJournalMode::Wal
+        } else {
+            SqliteJournalMode::Delete
+        };
+
+        let writer = SqlitePoolOptions::new()
+            .max_connections(1)
+            .connect_with(
+                options
+                    .clone()
+                    .create_if_missing(true)
+                    .journal_mode(journal_mode),
+            )
+            .await?;
+        // 迁移完成之后再打开只读连接；内存数据库的每个连接都是一个独立的空数据库，
+        // 只能和写入共用同一个连接
+        let reader = if is_in_memory(&config.url) {
+            writer.clone()
+        } else {
+            SqlitePoolOptions::new()
+                .max_connections(config.read_connections)
+                .connect_lazy_with(options.read_only(true))
+        };
+        let db = Database { reader, writer };
+        db.migrate().await?;
+
+        if config.optimize_interval > 0 {
+            let period = Duration::from_secs(config.optimize_interval);
+            tokio::spawn(run_periodically(
+                db.writer.clone(),
+                "PRAGMA optimize",
+                period,
+            ));
+        }
+        if config.wal && config.checkpoint_interval > 0 {
+            let period = Duration::from_secs(config.checkpoint_interval);
+            tokio::spawn(run_periodically(
+                db.writer.clone(),
+                "PRAGMA wal_checkpoint(PASSIVE)",
+                period,
+            ));
+        }
+        Ok(db)
+    }
+
+    /// 关闭所有连接，最后一个连接关闭时 SQLite 会把 WAL 合并回数据库文件
+    pub async fn close(&self) {
+        self.reader.close().await;
+        self.writer.close().await;
+    }
+
+    /// 更新连接池和数据库大小的指标，在抓取 /metrics 时调用
+    pub(crate) async fn update_metrics(&self) {
+        for (name, pool) in [("reader", &self.reader), ("writer", &self.writer)] {
+            let idle = pool.num_idle() as i64;
+            let gauge = |state| METRICS.db_connections.with_label_values(&[name, state]);
+            gauge("idle").set(idle);
+            gauge("active").set(pool.size() as i64 - idle);
+        }
+        match self.used_size().await {
+            Ok(size) => METRICS.db_size.set(size),
+            Err(e) => error!("query database size faild: {}", e),
+        }
+    }
+
+    /// 执行还没有应用的迁移
+    ///
+    /// 数据库的版本比程序认识的更新时拒绝启动，避免旧版本的程序写坏新的表结构
+    async fn migrate(&self) -> Result<(), Error> {
+        let latest = MIGRATOR.iter().map(|m| m.version).max().unwrap_or(0);
+        if let Some(version) = self.schema_version().await? {
+            if version > latest {
+                return Err(Error::SchemaTooNew(version, latest));
+            }
+        }
+        MIGRATOR.run(&self.writer).await?;
+        info!("database schema version: {}", latest);
+        Ok(())
+    }
+
+    /// 就绪检查：读写连接都可用，并且已经应用了程序认识的全部迁移
+    pub async fn check_ready(&self) -> Result<(), Error> {
+        sqlx::query("SELECT 1").execute(&self.reader).await?;
+        let latest = MIGRATOR.iter().map(|m| m.version).max().unwrap_or(0);
+        match self.schema_version().await?.unwrap_or(0) {
+            version if version < latest => Err(Error::SchemaOutdated(version, latest)),
+            _ => Ok(()),
+        }
+    }
+
+    /// 已经应用的最新迁移版本，还没有执行过迁移时为 None
+    pub async fn schema_version(&self) -> Result<Option<i64>, Error> {
+        let table = sqlx::query(
+            "SELECT name FROM sqlite_master WHERE type = 'table' AND name = '_sqlx_migrations'",
+        )
+        .fetch_optional(&self.writer)
+        .await?;
+        if table.is_none() {
+            return Ok(None);
+        }
+        let version: Option<i64> =
+            sqlx::query_scalar("SELECT MAX(version) FROM _sqlx_migrations WHERE success = 1")
+                .fetch_one(&self.writer)
+                .await?;
+        Ok(version)
     }
 
     pub async fn save_event(&self, e: &Event) -> Result<(), Error> {
-        let mut conn = self.pool.acquire().await?;
-        let id = e.id.0.as_slice();
-        let pubkey = e.pubkey.0.as_slice();
-        let created_at = e.created_at.0;
-        let kind: u64 = e.kind.into();
+        let mut conn = self.writer.acquire().await?;
+        Self::insert_event(&mut conn, e).await
+    }
+
+    async fn insert_event(conn: &mut SqliteConnection, e: &Event) -> Result<(), Error> {
+        let id = &e.id().0[..];
+        let pubkey = e.pubkey().0.as_slice();
+        let created_at = e.created_at().0;
+        let kind: u64 = e.kind().into();
         let kind_u32 = kind as u32;
-        let tags = serde_json::ser::to_string(&e.tags)
-            .expect("save event faild, serialization tags faild!");
-        let content = &e.content;
-        let sig_bytes = e.sig.0.to_bytes();
+        let tags = serde_json::ser::to_string(e.tags())?;
+        let content = e.content();
+        let sig_bytes = e.sig().0.to_bytes();
         let sig = sig_bytes.as_slice();
-        sqlx::query!(
+        let raw = e.raw();
+        sqlx::query(
             r#"
-            INSERT INTO nostr_events (id, pubkey, created_at, kind, tags, content, sig)
-            VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)
+            INSERT INTO nostr_events (id, pubkey, created_at, kind, tags, content, sig, raw)
+            VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8)
             "#,
-            id,
-            pubkey,
-            created_at,
-            kind_u32,
-            tags,
-            content,
-            sig
         )
-        .execute(&mut conn)
-        .await?
-        .last_insert_rowid();
+        .bind(id)
+        .bind(pubkey)
+        .bind(created_at)
+        .bind(kind_u32)
+        .bind(&tags)
+        .bind(content)
+        .bind(sig)
+        .bind(raw)
+        .execute(&mut *conn)
+        .await
+        .map_err(|err| match &err {
+            // SQLITE_CONSTRAINT_PRIMARYKEY，和 MemoryStore 返回同样的错误
+            sqlx::Error::Database(db) if db.code().as_deref() == Some("1555") => {
+                Error::Duplicate(e.id().as_hex_string())
+            }
+            _ => err.into(),
+        })?;
+        Self::insert_tags(conn, id, raw).await
+    }
+
+    /// 把单字母 tag 写入 tags 表，与迁移中的回填使用同样的规则
+    async fn insert_tags(conn: &mut SqliteConnection, id: &[u8], raw: &str) -> Result<(), Error> {
+        sqlx::query(&format!(
+            "INSERT INTO tags (event_id, name, value, position) {}",
+            INDEXED_TAGS_SQL
+        ))
+        .bind(id)
+        .bind(raw)
+        .execute(conn)
+        .await?;
         Ok(())
     }
 
-    pub async fn get_events(&self) -> Result<Vec<Event>, Error> {
-        let mut coon = self.pool.acquire().await?;
-        let events = sqlx::query!("SELECT * FROM nostr_events")
-            .map(|row| {
-                if let Some(id) = row.id {
-                    let tags = serde_json::from_str::<Vec<Tag>>(&row.tags)
-                        .expect("serde tags faild from database row!");
-                    Event {
-                        id: Id(id.try_into().expect("serde id faild from database row!")),
-                        pubkey: PublicKey(
-                            row.pubkey
-                                .try_into()
-                                .expect("serde pubkey faild from database row!"),
-                        ),
-                        created_at: Unixtime(row.created_at),
-                        kind: EventKind::from(row.kind as u64),
-                        tags,
-                        content: row.content,
-                        sig: Signature::try_from_vec_u8(row.sig)
-                            .expect("serde sig faild from database row!"),
-                    }
-                } else {
-                    panic!("serde id faild from database row!");
-                }
-            })
-            .fetch_all(&mut coon)
+    /// 数据库中是否已有该 event
+    pub async fn event_exists(&self, id: &nostr::Id) -> Result<bool, Error> {
+        let mut conn = self.reader.acquire().await?;
+        let id_slice = id.0.as_slice();
+        let row = sqlx::query("SELECT id FROM nostr_events WHERE id = ?")
+            .bind(id_slice)
+            .fetch_optional(&mut conn)
             .await?;
-        Ok(events)
+        Ok(row.is_some())
     }
 
     pub async fn delete_event(
-        &mut self,
+        &self,
+        id: &nostr::Id,
+        pubkey: &nostr::PublicKey,
+    ) -> Result<u64, Error> {
+        let mut conn = self.writer.acquire().await?;
+        Self::delete_in(&mut conn, id, pubkey).await
+    }
+
+    async fn delete_in(
+        conn: &mut SqliteConnection,
         id: &nostr::Id,
         pubkey: &nostr::PublicKey,
     ) -> Result<u64, Error> {
-        let mut conn = self.pool.acquire().await?;
         let id_slice = id.0.as_slice();
         let pubkey_slice = pubkey.0.as_slice();
-        let r = sqlx::query!(
-            "DELETE FROM nostr_events WHERE id = ? AND pubkey = ?",
-            id_slice,
-            pubkey_slice
-        )
-        .execute(&mut conn)
-        .await?
-        .rows_affected();
+        let r = sqlx::query("DELETE FROM nostr_events WHERE id = ? AND pubkey = ?")
+            .bind(id_slice)
+            .bind(pubkey_slice)
+            .execute(conn)
+            .await?
+            .rows_affected();
         Ok(r)
     }
 
-    #[allow(dead_code)]
-    pub async fn get_event_by_id(&self, id: &nostr::Id) -> Result<(), Error> {
-        let mut coon = self.pool.acquire().await?;
+    /// 在 conn 上替换 replaceable event，调用方负责事务
+    async fn replace_in(conn: &mut SqliteConnection, event: &Event) -> Result<bool, Error> {
+        let pubkey = event.pubkey().0.as_slice();
+        let kind = u64::from(event.kind()) as i64;
+        let rows = sqlx::query("SELECT raw FROM nostr_events WHERE pubkey = ? AND kind = ?")
+            .bind(pubkey)
+            .bind(kind)
+            .fetch_all(&mut *conn)
+            .await?;
+        let old: Vec<Event> = rows
+            .iter()
+            .filter_map(Self::event_from_row)
+            .filter(|e| same_slot(e, event))
+            .collect();
+        if old.iter().any(|e| is_newer(e, event)) {
+            return Ok(false);
+        }
+        for e in &old {
+            sqlx::query("DELETE FROM nostr_events WHERE id = ?")
+                .bind(e.id().0.as_slice())
+                .execute(&mut *conn)
+                .await?;
+        }
+        Self::insert_event(conn, event).await?;
+        Ok(true)
+    }
+
+    async fn apply(conn: &mut SqliteConnection, op: &WriteOp) -> Result<bool, Error> {
+        match op {
+            WriteOp::Save(e) => Self::insert_event(conn, e).await.map(|_| true),
+            WriteOp::Replace(e) => Self::replace_in(conn, e).await,
+            WriteOp::Deletion(e) => {
+                for id in deleted_ids(e) {
+                    Self::delete_in(&mut *conn, id, e.pubkey()).await?;
+                }
+                Self::insert_event(conn, e).await.map(|_| true)
+            }
+        }
+    }
+
+    /// 在一个事务中执行整批写入，每个操作用 savepoint 隔开，单个失败只回滚它自己
+    async fn write_batch(&self, ops: &[WriteOp]) -> Result<Vec<Result<bool, Error>>, Error> {
+        let mut tx = self.writer.begin().await?;
+        let mut results = Vec::with_capacity(ops.len());
+        for op in ops {
+            sqlx::query("SAVEPOINT write_op").execute(&mut *tx).await?;
+            let result = Self::apply(&mut tx, op).await;
+            if result.is_err() {
+                sqlx::query("ROLLBACK TO write_op")
+                    .execute(&mut *tx)
+                    .await?;
+            }
+            sqlx::query("RELEASE write_op").execute(&mut *tx).await?;
+            results.push(result);
+        }
+        tx.commit().await?;
+        Ok(results)
+    }
+
+    pub async fn get_event_by_id(&self, id: &nostr::Id) -> Result<Option<Event>, Error> {
+        let mut coon = self.reader.acquire().await?;
         let id_slice = id.0.as_slice();
-        let _events = sqlx::query!("SELECT * FROM nostr_events WHERE id = ?", id_slice)
-            .fetch_one(&mut coon)
-            .await;
-        todo!()
+        let raw: Option<Option<String>> =
+            sqlx::query_scalar("SELECT raw FROM nostr_events WHERE id = ?")
+                .bind(id_slice)
+                .fetch_optional(&mut coon)
+                .await?;
+        match raw.flatten() {
+            Some(raw) => Ok(Some(Event::from_raw(&raw)?)),
+            None => Ok(None),
+        }
+    }
+
+    /// 把 filter 的条件拼接到 WHERE 之后
+    fn push_filter_conditions(qb: &mut QueryBuilder<Sqlite>, filter: &Filter) {
+        qb.push(" WHERE 1 = 1");
+        if !filter.ids.is_empty() {
+            qb.push(" AND id IN (");
+            let mut ids = qb.separated(", ");
+            for id in &filter.ids {
+                ids.push_bind(id.0.to_vec());
+            }
+            qb.push(")");
+        }
+        if !filter.authors.is_empty() {
+            qb.push(" AND pubkey IN (");
+            let mut authors = qb.separated(", ");
+            for pubkey in &filter.authors {
+                authors.push_bind(pubkey.0.to_vec());
+            }
+            qb.push(")");
+        }
+        if !filter.kinds.is_empty() {
+            qb.push(" AND kind IN (");
+            let mut kinds = qb.separated(", ");
+            for kind in &filter.kinds {
+                kinds.push_bind(u64::from(*kind) as i64);
+            }
+            qb.push(")");
+        }
+        for (name, values) in &filter.tags {
+            if values.is_empty() {
+                continue;
+            }
+            qb.push(" AND id IN (SELECT event_id FROM tags WHERE name = ");
+            qb.push_bind(name.to_string());
+            qb.push(" AND value IN (");
+            let mut tag_values = qb.separated(", ");
+            for v in values {
+                tag_values.push_bind(v.clone());
+            }
+            qb.push("))");
+        }
+        if let Some(since) = &filter.since {
+            qb.push(" AND created_at > ").push_bind(since.0);
+        }
+        if let Some(until) = &filter.until {
+            qb.push(" AND created_at < ").push_bind(until.0);
+        }
+    }
+
+    fn event_from_row(row: &SqliteRow) -> Option<Event> {
+        let raw: Option<String> = row.try_get("raw").ok()?;
+        match raw.as_deref().map(Event::from_raw) {
+            Some(Ok(e)) => Some(e),
+            Some(Err(e)) => {
+                error!("parse raw event faild from database row: {}", e);
+                None
+            }
+            None => {
+                error!("raw event not found in database row");
+                None
+            }
+        }
+    }
+}
+
+/// `sqlite::memory:`、`sqlite://:memory:` 或者 `mode=memory` 指定的内存数据库
+fn is_in_memory(url: &str) -> bool {
+    let path = url.trim_start_matches("sqlite:").trim_start_matches("//");
+    path.starts_with(":memory:") || path.contains("mode=memory")
+}
+
+/// 每隔 period 在 pool 上执行一次 sql，pool 关闭后退出
+async fn run_periodically(pool: SqlitePool, sql: &'static str, period: Duration) {
+    let mut interval = tokio::time::interval(period);
+    // 第一次 tick 立即返回，跳过
+    interval.tick().await;
+    loop {
+        interval.tick().await;
+        if pool.is_closed() {
+            break;
+        }
+        if let Err(e) = sqlx::query(sql).execute(&pool).await {
+            error!("{} faild: {}", sql, e);
+        }
+    }
+}
+
+#[async_trait]
+impl EventStore for Database {
+    async fn save(&self, event: &Event) -> Result<(), Error> {
+        self.save_event(event).await
+    }
+
+    async fn query(&self, filters: &[Filter]) -> Result<Vec<Event>, Error> {
+        let mut ids = HashSet::new();
+        let mut events = vec![];
+        for filter in filters {
+            let mut qb = QueryBuilder::new("SELECT raw FROM nostr_events");
+            Self::push_filter_conditions(&mut qb, filter);
+            qb.push(" ORDER BY created_at DESC");
+            if let Some(limit) = filter.limit {
+                qb.push(" LIMIT ").push_bind(limit as i64);
+            }
+            let rows = qb.build().fetch_all(&self.reader).await?;
+            for e in rows.iter().filter_map(Self::event_from_row) {
+                if ids.insert(e.id()) {
+                    events.push(e);
+                }
+            }
+        }
+        events.sort_by_key(|e| Reverse(e.created_at().0));
+        Ok(events)
+    }
+
+    async fn count(&self, filters: &[Filter]) -> Result<u64, Error> {
+        let mut ids = HashSet::new();
+        for filter in filters {
+            let mut qb = QueryBuilder::new("SELECT id FROM nostr_events");
+            Self::push_filter_conditions(&mut qb, filter);
+            let rows = qb.build().fetch_all(&self.reader).await?;
+            for row in rows {
+                ids.insert(row.try_get::<Vec<u8>, _>("id")?);
+            }
+        }
+        Ok(ids.len() as u64)
+    }
+
+    async fn delete(&self, id: &nostr::Id, pubkey: &nostr::PublicKey) -> Result<u64, Error> {
+        self.delete_event(id, pubkey).await
+    }
+
+    async fn replace(&self, event: &Event) -> Result<bool, Error> {
+        let mut tx = self.writer.begin().await?;
+        let replaced = Self::replace_in(&mut tx, event).await?;
+        tx.commit().await?;
+        Ok(replaced)
+    }
+
+    async fn write(&self, ops: &[WriteOp]) -> Vec<Result<bool, Error>> {
+        match self.write_batch(ops).await {
+            Ok(results) => results,
+            Err(e) => {
+                error!("write batch of {} faild: {}", ops.len(), e);
+                let reason = e.to_string();
+                ops.iter()
+                    .map(|_| Err(Error::BatchFaild(reason.clone())))
+                    .collect()
+            }
+        }
+    }
+
+    async fn exists(&self, id: &nostr::Id) -> Result<bool, Error> {
+        self.event_exists(id).await
+    }
+
+    async fn usage(&self, pubkey: &nostr::PublicKey) -> Result<Usage, Error> {
+        self.usage_of(pubkey).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::nostr::{EventKind, PreEvent, PrivateKey, Tag, Unixtime};
+    use std::collections::BTreeMap;
+
+    #[tokio::test]
+    async fn test_sqlite_store() {
+        let path = std::env::temp_dir().join(format!("ksana-test-{}.db", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let url = format!("sqlite://{}", path.display());
+
+        // 文件不存在时自动创建并执行迁移
+        let db = Database::connect(&url).await.unwrap();
+        let latest = MIGRATOR.iter().map(|m| m.version).max();
+        assert_eq!(db.schema_version().await.unwrap(), latest);
+        db.check_ready().await.unwrap();
+        let mode: String = sqlx::query_scalar("PRAGMA journal_mode")
+            .fetch_one(&db.reader)
+            .await
+            .unwrap();
+        assert_eq!(mode, "wal");
+
+        let key = PrivateKey::gen();
+        let other = PrivateKey::gen();
+        let mut pre = PreEvent::new(
+            key.public_key(),
+            EventKind::TextNote,
+            vec![Tag::Pubkey {
+                pubkey: other.public_key(),
+                recommended_relay_url: None,
+                petname: None,
+            }],
+            "hi".to_string(),
+        );
+        pre.created_at = Unixtime(100);
+        let note = Event::new(pre, &key).unwrap();
+        db.save(&note).await.unwrap();
+        let by_tag = |name, value: String| Filter {
+            tags: BTreeMap::from([(name, vec![value])]),
+            ..Default::default()
+        };
+        let hex = other.public_key().as_hex_string();
+        let events = db.query(&[by_tag('p', hex.clone())]).await.unwrap();
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0].raw(), note.raw());
+        // 任意单字母 tag 都可以查询，索引取自原始 JSON，不受 Tag 解析时规范化的影响
+        let mut pre = PreEvent::new(
+            key.public_key(),
+            EventKind::Other(30023),
+            vec![
+                Tag::Other {
+                    tag: "d".to_string(),
+                    data: vec!["post".to_string()],
+                },
+                Tag::Pubkey {
+                    pubkey: other.public_key(),
+                    recommended_relay_url: None,
+                    petname: None,
+                },
+            ],
+            "".into(),
+        );
+        pre.created_at = Unixtime(50);
+        let raw = Event::new(pre, &key).unwrap().raw().to_string();
+        let article = Event::from_raw(&raw.replace(&hex, &hex.to_uppercase())).unwrap();
+        db.save(&article).await.unwrap();
+        let events = db.query(&[by_tag('d', "post".into())]).await.unwrap();
+        assert_eq!(events[0].id(), article.id());
+        let events = db.query(&[by_tag('p', hex.to_uppercase())]).await.unwrap();
+        assert_eq!(events[0].id(), article.id());
+        db.delete(&article.id(), &key.public_key()).await.unwrap();
+        assert_eq!(db.count(&[Filter::default()]).await.unwrap(), 1);
+
+        // 批量写入：重复的 note 单独失败，不影响同一批的其他操作，deletion 按顺序生效
+        let mut pre = PreEvent::new(key.public_key(), EventKind::TextNote, vec![], "".into());
+        pre.created_at = Unixtime(200);
+        let note2 = Event::new(pre, &key).unwrap();
+        let deletion = Event::new(
+            PreEvent::new(
+                key.public_key(),
+                EventKind::EventDeletion,
+                vec![Tag::Event {
+                    id: note2.id(),
+                    recommended_relay_url: None,
+                    marker: None,
+                }],
+                "".into(),
+            ),
+            &key,
+        )
+        .unwrap();
+        let ops = [
+            WriteOp::Save(note.clone()),
+            WriteOp::Save(note2.clone()),
+            WriteOp::Deletion(deletion.clone()),
+        ];
+        let results = db.write(&ops).await;
+        assert!(matches!(results[0], Err(Error::Duplicate(_))));
+        assert!(matches!(results[1], Ok(true)));
+        assert!(matches!(results[2], Ok(true)));
+        assert!(!db.exists(&note2.id()).await.unwrap());
+        assert!(db.exists(&deletion.id()).await.unwrap());
+
+        // tag 随 event 一起删除
+        let count_tags = || {
+            sqlx::query_scalar::<_, i64>("SELECT COUNT(*) FROM tags WHERE event_id = ?")
+                .bind(note.id().0.to_vec())
+                .fetch_one(&db.reader)
+        };
+        assert_eq!(count_tags().await.unwrap(), 1);
+        db.delete(&note.id(), &key.public_key()).await.unwrap();
+        assert_eq!(count_tags().await.unwrap(), 0);
+
+        // 缺少迁移时没有就绪
+        sqlx::query("DELETE FROM _sqlx_migrations WHERE version = ?")
+            .bind(latest)
+            .execute(&db.writer)
+            .await
+            .unwrap();
+        assert!(matches!(
+            db.check_ready().await,
+            Err(Error::SchemaOutdated(_, _))
+        ));
+
+        // 比程序更新的数据库版本拒绝启动
+        sqlx::query(
+            "INSERT INTO _sqlx_migrations (version, description, success, checksum, execution_time) \
+             VALUES (99999999999999, 'future', 1, x'00', 0)",
+        )
+        .execute(&db.writer)
+        .await
+        .unwrap();
+        assert!(matches!(
+            Database::connect(&url).await,
+            Err(Error::SchemaTooNew(99999999999999, _))
+        ));
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[tokio::test]
+    async fn test_in_memory() {
+        assert!(is_in_memory("sqlite::memory:"));
+        assert!(is_in_memory("sqlite://:memory:"));
+        assert!(is_in_memory("sqlite://relay?mode=memory&cache=shared"));
+        assert!(!is_in_memory("sqlite://ksana.db"));
+
+        // 读写共用一个连接，查询能看到写入的 event
+        let db = Database::connect("sqlite::memory:").await.unwrap();
+        let key = PrivateKey::gen();
+        let note = Event::new(
+            PreEvent::new(key.public_key(), EventKind::TextNote, vec![], "hi".into()),
+            &key,
+        )
+        .unwrap();
+        db.save(&note).await.unwrap();
+        assert_eq!(db.query(&[Filter::default()]).await.unwrap().len(), 1);
+        db.check_ready().await.unwrap();
     }
 }
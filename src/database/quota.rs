@@ -0,0 +1,102 @@
+use super::{Database, Error, Usage};
+use crate::nostr::PublicKey;
+use serde::Serialize;
+
+/// 一个作者的用量，用于列出占用最多的作者
+#[derive(Debug, Clone, Serialize)]
+pub struct PubkeyUsage {
+    /// hex 公钥
+    pub pubkey: String,
+    #[serde(flatten)]
+    pub usage: Usage,
+}
+
+impl Database {
+    /// pubkey_usage 表中记录的用量，由触发器随 event 的增删维护
+    pub async fn usage_of(&self, pubkey: &PublicKey) -> Result<Usage, Error> {
+        let row: Option<(i64, i64)> =
+            sqlx::query_as("SELECT events, bytes FROM pubkey_usage WHERE pubkey = ?")
+                .bind(pubkey.0.as_slice())
+                .fetch_optional(&self.reader)
+                .await?;
+        let (events, bytes) = row.unwrap_or_default();
+        Ok(Usage {
+            events: events as u64,
+            bytes: bytes as u64,
+        })
+    }
+
+    /// 按字节数从多到少列出前 limit 个作者
+    pub async fn top_consumers(&self, limit: usize) -> Result<Vec<PubkeyUsage>, Error> {
+        let rows: Vec<(Vec<u8>, i64, i64)> = sqlx::query_as(
+            r#"
+            SELECT pubkey, events, bytes FROM pubkey_usage
+            WHERE events > 0
+            ORDER BY bytes DESC, events DESC
+            LIMIT ?
+            "#,
+        )
+        .bind(limit as i64)
+        .fetch_all(&self.reader)
+        .await?;
+        Ok(rows
+            .into_iter()
+            .map(|(pubkey, events, bytes)| PubkeyUsage {
+                pubkey: hex::encode(pubkey),
+                usage: Usage {
+                    events: events as u64,
+                    bytes: bytes as u64,
+                },
+            })
+            .collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        database::EventStore,
+        nostr::{Event, EventKind, PreEvent, PrivateKey},
+    };
+
+    #[tokio::test]
+    async fn test_usage() {
+        let path = std::env::temp_dir().join(format!("ksana-quota-{}.db", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let db = Database::connect(&format!("sqlite://{}", path.display()))
+            .await
+            .unwrap();
+        let (alice, bob) = (PrivateKey::gen(), PrivateKey::gen());
+        let mut events = vec![];
+        for (key, content) in [(&alice, "a"), (&alice, "bb"), (&bob, "c")] {
+            let pre = PreEvent::new(
+                key.public_key(),
+                EventKind::TextNote,
+                vec![],
+                content.into(),
+            );
+            let event = Event::new(pre, key).unwrap();
+            db.save(&event).await.unwrap();
+            events.push(event);
+        }
+        let bytes = (events[0].raw().len() + events[1].raw().len()) as u64;
+        assert_eq!(
+            db.usage_of(&alice.public_key()).await.unwrap(),
+            Usage { events: 2, bytes }
+        );
+
+        // 删除后用量随之减少
+        db.delete(&events[1].id(), &alice.public_key())
+            .await
+            .unwrap();
+        let top = db.top_consumers(10).await.unwrap();
+        assert_eq!(top.len(), 2);
+        assert_eq!(top[0].usage.events + top[1].usage.events, 2);
+        assert_eq!(
+            db.usage_of(&alice.public_key()).await.unwrap().bytes,
+            events[0].raw().len() as u64
+        );
+        let _ = std::fs::remove_file(&path);
+    }
+}
@@ -0,0 +1,315 @@
+use super::{Database, Error};
+use crate::{
+    config::{Config, RetentionConfig},
+    nostr::{Id, PublicKey, Unixtime},
+    relay::RetentionRule,
+};
+use log::{error, info};
+use sqlx::{QueryBuilder, Row, Sqlite};
+use std::{sync::Arc, time::Duration};
+use tokio::sync::watch;
+
+/// 每批删除的 event 数，分批删除并在批次之间让出唯一的写连接，避免长时间阻塞 event 写入
+const DELETE_CHUNK: i64 = 1000;
+
+/// 一次清理的结果
+#[derive(Debug, Default, Clone)]
+pub struct PruneReport {
+    /// 超过保留时间被删除的 event 数
+    pub expired: u64,
+    /// 超过每个作者每个 kind 的数量上限被删除的 event 数
+    pub over_count: u64,
+    /// 超出空间预算被删除的 event 数
+    pub evicted: u64,
+}
+
+impl Database {
+    /// 按保留策略删除 event
+    ///
+    /// 每个 kind 只受第一条包含它的规则约束；exempt_pubkeys 中作者的 event 永远不会被删除。
+    /// 最后数据库仍然超出空间预算时，从最旧的 event 开始删除。
+    /// 每删除一批 event 就用它们的 id 调用 on_deleted，调用方可以据此清理缓存
+    pub async fn prune(
+        &self,
+        config: &RetentionConfig,
+        on_deleted: &mut (dyn FnMut(&[Id]) + Send),
+    ) -> Result<PruneReport, Error> {
+        let exempt: Vec<Vec<u8>> = config
+            .exempt_pubkeys
+            .iter()
+            .filter_map(|hex| PublicKey::try_from_hex_string(hex).ok())
+            .map(|pubkey| pubkey.0.to_vec())
+            .collect();
+        let mut report = PruneReport::default();
+        let now = Unixtime::now().0;
+
+        for (i, rule) in config.rules.iter().enumerate() {
+            if let Some(time) = rule.time {
+                report.expired += self
+                    .delete_all(
+                        |qb| {
+                            qb.push("SELECT id FROM nostr_events");
+                            push_scope(qb, &config.rules[..i], rule, &exempt);
+                            qb.push(" AND created_at < ")
+                                .push_bind(now.saturating_sub(time as i64));
+                        },
+                        on_deleted,
+                    )
+                    .await?;
+            }
+            if let Some(count) = rule.count {
+                report.over_count += self
+                    .delete_all(
+                        |qb| {
+                            qb.push(
+                                r#"
+                                SELECT id FROM (
+                                    SELECT id, ROW_NUMBER() OVER (
+                                        PARTITION BY pubkey, kind ORDER BY created_at DESC, id
+                                    ) AS n
+                                    FROM nostr_events"#,
+                            );
+                            push_scope(qb, &config.rules[..i], rule, &exempt);
+                            qb.push(") WHERE n > ").push_bind(count as i64);
+                        },
+                        on_deleted,
+                    )
+                    .await?;
+            }
+        }
+
+        if config.max_db_size_mb > 0 {
+            let budget = (config.max_db_size_mb * 1024 * 1024) as i64;
+            while self.used_size().await? > budget {
+                let deleted = self
+                    .delete_chunk(
+                        |qb| {
+                            qb.push("SELECT id FROM nostr_events WHERE 1 = 1");
+                            push_exempt(qb, &exempt);
+                            qb.push(" ORDER BY created_at ASC, id");
+                        },
+                        on_deleted,
+                    )
+                    .await?;
+                if deleted == 0 {
+                    break;
+                }
+                report.evicted += deleted;
+                tokio::task::yield_now().await;
+            }
+        }
+        Ok(report)
+    }
+
+    /// 分批删除 select 选出的全部 event，返回删除的数量
+    async fn delete_all(
+        &self,
+        select: impl Fn(&mut QueryBuilder<Sqlite>),
+        on_deleted: &mut (dyn FnMut(&[Id]) + Send),
+    ) -> Result<u64, Error> {
+        let mut total = 0;
+        loop {
+            let deleted = self.delete_chunk(&select, on_deleted).await?;
+            total += deleted;
+            if deleted < DELETE_CHUNK as u64 {
+                return Ok(total);
+            }
+            tokio::task::yield_now().await;
+        }
+    }
+
+    /// 删除 select 选出的前 DELETE_CHUNK 个 event，select 生成的是不带 LIMIT 的 id 子查询
+    async fn delete_chunk(
+        &self,
+        select: impl Fn(&mut QueryBuilder<Sqlite>),
+        on_deleted: &mut (dyn FnMut(&[Id]) + Send),
+    ) -> Result<u64, Error> {
+        let mut qb = QueryBuilder::new("DELETE FROM nostr_events WHERE id IN (");
+        select(&mut qb);
+        qb.push(" LIMIT ")
+            .push_bind(DELETE_CHUNK)
+            .push(") RETURNING id");
+        let rows = qb.build().fetch_all(&self.writer).await?;
+        let ids: Vec<Id> = rows
+            .iter()
+            .filter_map(|row| row.try_get::<Vec<u8>, _>("id").ok())
+            .filter_map(|id| id.try_into().ok().map(Id))
+            .collect();
+        if !ids.is_empty() {
+            on_deleted(&ids);
+        }
+        Ok(ids.len() as u64)
+    }
+
+    /// 按 `retention.interval` 定期执行 [`Database::prune`]，需要在 tokio runtime 中调用
+    ///
+    /// 配置重新加载后使用新的规则，并重新开始计时；配置的发送端关闭或数据库关闭后结束。
+    /// on_deleted 的含义和 [`Database::prune`] 相同
+    pub async fn prune_periodically(
+        self,
+        mut config: watch::Receiver<Arc<Config>>,
+        mut on_deleted: impl FnMut(&[Id]) + Send + 'static,
+    ) {
+        loop {
+            let interval = config.borrow_and_update().retention.interval;
+            let wait = async {
+                if interval > 0 {
+                    tokio::time::sleep(Duration::from_secs(interval)).await;
+                } else {
+                    // 不清理，只等待配置变化
+                    std::future::pending::<()>().await;
+                }
+            };
+            tokio::select! {
+                _ = wait => {}
+                r = config.changed() => match r {
+                    Ok(_) => continue,
+                    Err(_) => break,
+                },
+            }
+            if self.writer.is_closed() {
+                break;
+            }
+            let retention = config.borrow().retention.clone();
+            if retention.rules.is_empty() && retention.max_db_size_mb == 0 {
+                continue;
+            }
+            match self.prune(&retention, &mut on_deleted).await {
+                Ok(r) if r.expired + r.over_count + r.evicted > 0 => info!(
+                    "pruned {} expired, {} over count, {} evicted events",
+                    r.expired, r.over_count, r.evicted
+                ),
+                Ok(_) => {}
+                Err(e) => error!("prune events faild: {}", e),
+            }
+        }
+    }
+
+    /// 数据库实际使用的空间（字节），不包括空闲页
+    pub(crate) async fn used_size(&self) -> Result<i64, Error> {
+        let size = sqlx::query_scalar(
+            r#"
+            SELECT (page_count - freelist_count) * page_size
+            FROM pragma_page_count(), pragma_freelist_count(), pragma_page_size()
+            "#,
+        )
+        .fetch_one(&self.writer)
+        .await?;
+        Ok(size)
+    }
+}
+
+/// 规则适用的 event：kind 在这条规则中、不在前面的规则中，并且作者不在豁免列表中
+fn push_scope(
+    qb: &mut QueryBuilder<Sqlite>,
+    earlier: &[RetentionRule],
+    rule: &RetentionRule,
+    exempt: &[Vec<u8>],
+) {
+    qb.push(" WHERE ");
+    push_kinds(qb, rule);
+    for r in earlier {
+        qb.push(" AND NOT ");
+        push_kinds(qb, r);
+    }
+    push_exempt(qb, exempt);
+}
+
+fn push_kinds(qb: &mut QueryBuilder<Sqlite>, rule: &RetentionRule) {
+    if rule.kinds.is_empty() {
+        qb.push("(1 = 1)");
+        return;
+    }
+    qb.push("(");
+    for (i, range) in rule.kinds.iter().enumerate() {
+        let (start, end) = range.bounds();
+        if i > 0 {
+            qb.push(" OR ");
+        }
+        qb.push("kind BETWEEN ")
+            .push_bind(start as i64)
+            .push(" AND ")
+            .push_bind(end as i64);
+    }
+    qb.push(")");
+}
+
+fn push_exempt(qb: &mut QueryBuilder<Sqlite>, exempt: &[Vec<u8>]) {
+    if exempt.is_empty() {
+        return;
+    }
+    qb.push(" AND pubkey NOT IN (");
+    let mut pubkeys = qb.separated(", ");
+    for pubkey in exempt {
+        pubkeys.push_bind(pubkey.clone());
+    }
+    qb.push(")");
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        database::EventStore,
+        nostr::{Event, EventKind, PreEvent, PrivateKey},
+        relay::KindRange,
+    };
+
+    #[tokio::test]
+    async fn test_prune() {
+        let path = std::env::temp_dir().join(format!("ksana-prune-{}.db", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let db = Database::connect(&format!("sqlite://{}", path.display()))
+            .await
+            .unwrap();
+        let (alice, bob) = (PrivateKey::gen(), PrivateKey::gen());
+        let now = Unixtime::now().0;
+        for key in [&alice, &bob] {
+            // 每人 4 个 text note 和 2 个 reaction，时间依次变旧
+            for (i, kind) in [1, 1, 1, 1, 7, 7].into_iter().enumerate() {
+                let mut pre = PreEvent::new(
+                    key.public_key(),
+                    EventKind::from(kind),
+                    vec![],
+                    i.to_string(),
+                );
+                pre.created_at = Unixtime(now - 100 * i as i64);
+                db.save(&Event::new(pre, key).unwrap()).await.unwrap();
+            }
+        }
+
+        let config = RetentionConfig {
+            exempt_pubkeys: vec![bob.public_key().as_hex_string()],
+            rules: vec![
+                RetentionRule {
+                    kinds: vec![KindRange::Kind(1)],
+                    count: Some(2),
+                    ..Default::default()
+                },
+                RetentionRule {
+                    kinds: vec![KindRange::Range(0, 10)],
+                    time: Some(450),
+                    ..Default::default()
+                },
+            ],
+            ..Default::default()
+        };
+        let mut deleted = vec![];
+        let report = db
+            .prune(&config, &mut |ids| deleted.extend_from_slice(ids))
+            .await
+            .unwrap();
+        // kind 1 只受第一条规则约束；kind 7 中 500 秒前的被删除；bob 的都不删除
+        assert_eq!((report.expired, report.over_count), (1, 2));
+        assert_eq!(deleted.len(), 3);
+        for id in &deleted {
+            assert!(!db.exists(id).await.unwrap());
+        }
+        let count: i64 = sqlx::query_scalar("SELECT COUNT(*) FROM nostr_events")
+            .fetch_one(&db.reader)
+            .await
+            .unwrap();
+        assert_eq!(count, 9);
+        let _ = std::fs::remove_file(&path);
+    }
+}
@@ -0,0 +1,128 @@
+use super::Error;
+use crate::nostr::{Event, EventKind, Filter, Id, PublicKey, Tag};
+use async_trait::async_trait;
+use serde::Serialize;
+
+/// event 存储
+///
+/// Relay 只通过这个 trait 访问存储，目前有 SQLite（[`super::Database`]）和内存
+/// （[`super::MemoryStore`]）两种实现
+#[async_trait]
+pub trait EventStore: Send + Sync {
+    /// 保存一个普通 event
+    async fn save(&self, event: &Event) -> Result<(), Error>;
+
+    /// 按 filter 查询，每个 filter 各自应用 limit，结果去重后按 created_at 从新到旧排列
+    async fn query(&self, filters: &[Filter]) -> Result<Vec<Event>, Error>;
+
+    /// 满足任意一个 filter 的 event 数量，忽略 limit
+    async fn count(&self, filters: &[Filter]) -> Result<u64, Error>;
+
+    /// 删除 pubkey 发布的 id 对应的 event，返回删除的数量
+    async fn delete(&self, id: &Id, pubkey: &PublicKey) -> Result<u64, Error>;
+
+    /// 保存一个 replaceable event，同时删除被它替换的旧 event
+    ///
+    /// 已经存在更新的 event 时不保存，返回 false
+    async fn replace(&self, event: &Event) -> Result<bool, Error>;
+
+    /// 是否已经保存过该 event
+    async fn exists(&self, id: &Id) -> Result<bool, Error>;
+
+    /// pubkey 已经保存的 event 数和字节数，用于配额检查
+    ///
+    /// 默认查出该作者的全部 event 再统计，能增量维护用量的存储应该覆盖这个方法
+    async fn usage(&self, pubkey: &PublicKey) -> Result<Usage, Error> {
+        let filter = Filter {
+            authors: vec![pubkey.clone()],
+            ..Default::default()
+        };
+        let events = self.query(&[filter]).await?;
+        Ok(Usage {
+            events: events.len() as u64,
+            bytes: events.iter().map(|e| e.raw().len() as u64).sum(),
+        })
+    }
+
+    /// 按顺序执行一批写操作，返回每个操作各自的结果
+    ///
+    /// Save 和 Deletion 成功时为 true，Replace 的结果同 [`EventStore::replace`]。
+    /// 默认逐个执行，支持事务的存储应该在一个事务中完成整批写入
+    async fn write(&self, ops: &[WriteOp]) -> Vec<Result<bool, Error>> {
+        let mut results = Vec::with_capacity(ops.len());
+        for op in ops {
+            let result = match op {
+                WriteOp::Save(e) => self.save(e).await.map(|_| true),
+                WriteOp::Replace(e) => self.replace(e).await,
+                WriteOp::Deletion(e) => {
+                    async {
+                        for id in deleted_ids(e) {
+                            self.delete(id, e.pubkey()).await?;
+                        }
+                        self.save(e).await.map(|_| true)
+                    }
+                    .await
+                }
+            };
+            results.push(result);
+        }
+        results
+    }
+}
+
+/// 一个作者占用的存储
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct Usage {
+    /// event 数
+    pub events: u64,
+    /// 原始 JSON 的字节数
+    pub bytes: u64,
+}
+
+/// 批量写入中的一个操作
+#[derive(Debug, Clone)]
+pub enum WriteOp {
+    /// 保存普通 event
+    Save(Event),
+    /// 保存 replaceable event，替换旧的
+    Replace(Event),
+    /// 删除 deletion event 引用的 event，再保存 deletion event 本身
+    Deletion(Event),
+}
+
+impl WriteOp {
+    /// 按 event 的 kind 选择写操作，ephemeral event 不保存，返回 None
+    pub fn for_event(event: Event) -> Option<WriteOp> {
+        let kind = event.kind();
+        if kind.is_ephemeral() {
+            None
+        } else if kind.is_replaceable() || kind.is_parameterized_replaceable() {
+            Some(WriteOp::Replace(event))
+        } else if kind == EventKind::EventDeletion {
+            Some(WriteOp::Deletion(event))
+        } else {
+            Some(WriteOp::Save(event))
+        }
+    }
+}
+
+/// deletion event 中 `e` tag 引用的 event id
+pub(crate) fn deleted_ids(event: &Event) -> impl Iterator<Item = &Id> {
+    event.tags().iter().filter_map(|tag| match tag {
+        Tag::Event { id, .. } => Some(id),
+        _ => None,
+    })
+}
+
+/// new 和 old 是否属于同一个 replaceable 位置：同作者、同 kind，参数化的还要求 `d` tag 相同
+pub(crate) fn same_slot(old: &Event, new: &Event) -> bool {
+    old.pubkey() == new.pubkey()
+        && old.kind() == new.kind()
+        && (!new.kind().is_parameterized_replaceable() || old.d_tag() == new.d_tag())
+}
+
+/// old 是否比 new 更新：created_at 更大，相同时保留 id 较小的
+pub(crate) fn is_newer(old: &Event, new: &Event) -> bool {
+    old.created_at() > new.created_at()
+        || (old.created_at() == new.created_at() && old.id().0 < new.id().0)
+}
@@ -0,0 +1,215 @@
+use super::{Database, Error, EventStore, WriteOp};
+use crate::nostr::{Event, Filter};
+use futures::TryStreamExt;
+use log::{info, warn};
+use sqlx::{QueryBuilder, Row};
+use std::collections::HashSet;
+use tokio::io::{AsyncBufRead, AsyncBufReadExt, AsyncWrite, AsyncWriteExt};
+
+/// 导入的统计结果
+#[derive(Debug, Default, Clone)]
+pub struct ImportReport {
+    /// 新写入的 event 数
+    pub imported: u64,
+    /// 已经存在的 event 数
+    pub duplicates: u64,
+    /// 因为格式、签名等原因跳过的行数，空行也计入
+    pub skipped: u64,
+    /// 按 skip_lines 直接跳过、没有处理的行数
+    pub resumed: u64,
+    /// 最后处理的行号，中断后可以用它继续导入
+    pub last_line: usize,
+}
+
+impl Database {
+    /// 把满足 filter 的 event 按 JSONL 格式写入 out，每行一个原始 event，返回写出的数量
+    ///
+    /// 按 created_at 从旧到新导出，导入时 replaceable 和 deletion event 能按原来的顺序生效。
+    /// 和 REQ 一样，filter 中的 limit 取最新的 N 个 event
+    pub async fn export(
+        &self,
+        filter: &Filter,
+        out: &mut (dyn AsyncWrite + Send + Unpin),
+    ) -> Result<u64, Error> {
+        let mut qb =
+            QueryBuilder::new("SELECT raw FROM (SELECT raw, created_at, id FROM nostr_events");
+        Self::push_filter_conditions(&mut qb, filter);
+        qb.push(" ORDER BY created_at DESC, id DESC");
+        if let Some(limit) = filter.limit {
+            qb.push(" LIMIT ").push_bind(limit as i64);
+        }
+        qb.push(") ORDER BY created_at ASC, id ASC");
+        let mut rows = qb.build().fetch(&self.reader);
+        let mut count = 0;
+        while let Some(row) = rows.try_next().await? {
+            match row.try_get::<Option<String>, _>("raw")? {
+                Some(raw) => {
+                    out.write_all(raw.as_bytes()).await?;
+                    out.write_all(b"\n").await?;
+                    count += 1;
+                }
+                None => warn!("raw event not found in database row, skip it"),
+            }
+        }
+        out.flush().await?;
+        Ok(count)
+    }
+
+    /// 从 JSONL 导入 event，校验签名并按 replaceable 和 deletion 的规则写入
+    ///
+    /// 前 skip_lines 行直接跳过；已经存在的 event 不会重复写入，所以中断后重新导入同一个文件是安全的。
+    /// 跳过的行会连同原因打印到日志中
+    pub async fn import(
+        &self,
+        input: impl AsyncBufRead + Unpin,
+        skip_lines: usize,
+        batch_size: usize,
+    ) -> Result<ImportReport, Error> {
+        let mut report = ImportReport::default();
+        let mut ids = HashSet::new();
+        let mut batch: Vec<(usize, WriteOp)> = vec![];
+        let mut line_no = 0;
+        let mut lines = input.lines();
+        while let Some(line) = lines.next_line().await? {
+            line_no += 1;
+            if line_no <= skip_lines {
+                report.resumed += 1;
+                continue;
+            }
+            if line.trim().is_empty() {
+                report.skipped += 1;
+                continue;
+            }
+            let event = match Event::from_raw(line.trim()) {
+                Ok(event) => event,
+                Err(e) => {
+                    warn!("line {}: invalid event: {}", line_no, e);
+                    report.skipped += 1;
+                    continue;
+                }
+            };
+            if let Err(e) = event.verify() {
+                warn!("line {}: verify faild: {}", line_no, e);
+                report.skipped += 1;
+                continue;
+            }
+            if !ids.insert(event.id()) || self.exists(&event.id()).await? {
+                report.duplicates += 1;
+                continue;
+            }
+            match WriteOp::for_event(event) {
+                Some(op) => batch.push((line_no, op)),
+                None => {
+                    warn!("line {}: ephemeral event is not stored", line_no);
+                    report.skipped += 1;
+                }
+            }
+            if batch.len() >= batch_size {
+                self.import_batch(&mut batch, line_no, &mut report).await?;
+                ids.clear();
+            }
+        }
+        self.import_batch(&mut batch, line_no, &mut report).await?;
+        Ok(report)
+    }
+
+    /// 在一个事务中写入一批 event，写完之后 line_no 及之前的行都已经处理完
+    ///
+    /// 整个事务失败时直接返回错误，report.last_line 停在上一批
+    async fn import_batch(
+        &self,
+        batch: &mut Vec<(usize, WriteOp)>,
+        line_no: usize,
+        report: &mut ImportReport,
+    ) -> Result<(), Error> {
+        let (lines, ops): (Vec<usize>, Vec<WriteOp>) = batch.drain(..).unzip();
+        if !ops.is_empty() {
+            let results = self.write_batch(&ops).await?;
+            for (line, result) in lines.into_iter().zip(results) {
+                match result {
+                    Ok(true) => report.imported += 1,
+                    Ok(false) => {
+                        warn!("line {}: a newer replaceable event exists", line);
+                        report.skipped += 1;
+                    }
+                    Err(e) => {
+                        warn!("line {}: save faild: {}", line, e);
+                        report.skipped += 1;
+                    }
+                }
+            }
+        }
+        report.last_line = line_no;
+        info!(
+            "imported {} events up to line {}",
+            report.imported, report.last_line
+        );
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::nostr::{EventKind, PreEvent, PrivateKey, Unixtime};
+
+    async fn temp_db(name: &str) -> Database {
+        let path = std::env::temp_dir().join(format!("ksana-{}-{}.db", name, std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        Database::connect(&format!("sqlite://{}", path.display()))
+            .await
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_export_import() {
+        let src = temp_db("export").await;
+        let key = PrivateKey::gen();
+        for (i, content) in ["a", "b", "c"].into_iter().enumerate() {
+            let mut pre = PreEvent::new(
+                key.public_key(),
+                EventKind::TextNote,
+                vec![],
+                content.into(),
+            );
+            pre.created_at = Unixtime(100 + i as i64);
+            src.save(&Event::new(pre, &key).unwrap()).await.unwrap();
+        }
+        let mut out = vec![];
+        assert_eq!(src.export(&Filter::default(), &mut out).await.unwrap(), 3);
+
+        // limit 取最新的 N 个，输出仍然从旧到新
+        let mut newest = vec![];
+        let filter = Filter {
+            limit: Some(2),
+            ..Default::default()
+        };
+        assert_eq!(src.export(&filter, &mut newest).await.unwrap(), 2);
+        let contents: Vec<String> = String::from_utf8(newest)
+            .unwrap()
+            .lines()
+            .map(|l| Event::from_raw(l).unwrap().content().to_string())
+            .collect();
+        assert_eq!(contents, ["b", "c"]);
+
+        // 签名错误和格式错误的行被跳过，其余正常导入
+        let mut jsonl = String::from_utf8(out).unwrap();
+        let tampered = jsonl
+            .lines()
+            .next()
+            .unwrap()
+            .replace("\"content\":\"", "\"content\":\"x");
+        jsonl.push_str(&tampered);
+        jsonl.push_str("\nnot json\n\n");
+        let dst = temp_db("import").await;
+        let report = dst.import(jsonl.as_bytes(), 0, 2).await.unwrap();
+        assert_eq!(report.imported, 3);
+        assert_eq!(report.skipped, 3);
+        assert_eq!(report.last_line, 6);
+
+        // 重新导入时已有的 event 记为重复，skip_lines 跳过的行单独计数
+        let report = dst.import(jsonl.as_bytes(), 1, 2).await.unwrap();
+        assert_eq!((report.imported, report.duplicates), (0, 2));
+        assert_eq!((report.resumed, report.skipped), (1, 3));
+    }
+}
@@ -1,12 +1,27 @@
+use crate::config::Error as ConfigError;
 use crate::database::Error as DatabaseError;
 use crate::nostr::Error as NostrError;
+use std::{io, path::PathBuf};
 use thiserror::Error;
 
+/// 启动和运行 relay 时的错误
 #[derive(Error, Debug)]
 pub enum RelayError {
+    #[error("{0}")]
+    ConfigError(#[from] ConfigError),
+
     #[error("relay error: {0}")]
     DatabaseError(#[from] DatabaseError),
 
     #[error("nostr error: {0}")]
     NostrError(#[from] NostrError),
+
+    #[error("can't listen on {0}: {1}")]
+    Listen(String, #[source] io::Error),
+
+    #[error("read {0} faild: {1}")]
+    ReadCertificate(PathBuf, #[source] io::Error),
+
+    #[error("load tls certificate faild: {0}")]
+    Tls(#[from] native_tls::Error),
 }
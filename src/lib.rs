@@ -0,0 +1,19 @@
+//! ksana relay：一个 Nostr relay 的实现
+//!
+//! - [`nostr`]：协议类型，event、公私钥、tag、filter 以及客户端和 relay 之间的消息
+//! - [`RelayBuilder`]：在宿主程序自己的 tokio runtime 中运行 relay，可以指定存储、监听和准入策略
+
+mod builder;
+pub mod config;
+pub mod database;
+mod error;
+mod metrics;
+pub mod nostr;
+pub mod relay;
+mod reload;
+mod server;
+
+pub use builder::{RelayBuilder, RelayServer};
+pub use error::RelayError;
+pub use reload::{ConfigLoader, ConfigReloader, ReloadReport};
+pub use server::Listener;
@@ -1,48 +1,95 @@
-mod database;
-mod error;
-mod nostr;
-mod relay;
+mod cli;
+mod commands;
+use clap::Parser;
+use cli::{Cli, Command};
 use dotenv::dotenv;
-use error::RelayError;
+use ksana_relay::{config::Config, RelayBuilder, RelayError};
 use log::*;
-use nostr::Event;
-use relay::{Relay, Subscriber, SubscriberEvent};
-use tokio::{
-    net::TcpListener,
-    sync::{broadcast, mpsc},
-};
-use tokio_tungstenite::{self, accept_async, tungstenite::Result};
+use std::process;
 
 #[tokio::main]
-async fn main() -> Result<(), RelayError> {
+async fn main() {
     dotenv().ok();
-    env_logger::init();
+    let mut cli = Cli::parse();
+    let config = match cli.load_config() {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("{}", e);
+            process::exit(1);
+        }
+    };
+    env_logger::Builder::new()
+        .parse_filters(&config.logging.level)
+        .init();
 
-    let db = database::Database::connect(
-        &dotenv::var("DATABASE_URL").expect("can't found DATABASE_URL in env."),
-    )
-    .await?;
-    let (subscriber_msg_sender, subscriber_msg_receiver) = mpsc::channel::<SubscriberEvent>(32);
-    let (broadcast_sender, broadcast_receiver) = broadcast::channel::<Event>(32);
+    let result = match cli.command.take() {
+        None => serve(config, cli).await.map_err(Into::into),
+        Some(Command::Export(args)) => commands::export(&config, args).await,
+        Some(Command::Import(args)) => commands::import(&config, args).await,
+        Some(Command::Backup(args)) => commands::backup(&config, args).await,
+        Some(Command::Check(args)) => commands::check(&config, args).await,
+        Some(Command::Quota(args)) => commands::quota(&config, args).await,
+    };
+    if let Err(e) = result {
+        error!("{}", e);
+        process::exit(1);
+    }
+}
 
-    let addr = "127.0.0.1:9002";
-    let listener = TcpListener::bind(&addr).await.expect("Can't listen");
-    info!("Listening on: {}", addr);
-    Relay::new(db, subscriber_msg_receiver, broadcast_sender).start();
+async fn serve(config: Config, cli: Cli) -> Result<(), RelayError> {
+    let server = RelayBuilder::new(config)
+        .config_loader(move || cli.load_config())
+        .build()
+        .await?;
+    #[cfg(unix)]
+    tokio::spawn(reload_on_sighup(server.config_reloader()));
+    server.serve_with_shutdown(shutdown_signal()).await
+}
 
-    while let Ok((stream, _)) = listener.accept().await {
-        let peer = stream
-            .peer_addr()
-            .expect("connected streams should have a peer address");
-        let ws_stream = accept_async(stream).await.expect("Failed to accept");
+/// 收到 SIGHUP 时重新读取配置文件
+#[cfg(unix)]
+async fn reload_on_sighup(reloader: ksana_relay::ConfigReloader) {
+    use tokio::signal::unix::{signal, SignalKind};
+    let mut sighup = match signal(SignalKind::hangup()) {
+        Ok(sighup) => sighup,
+        Err(e) => {
+            error!("listen for SIGHUP faild: {}", e);
+            return;
+        }
+    };
+    while sighup.recv().await.is_some() {
+        info!("received SIGHUP, reloading config");
+        if let Err(e) = reloader.reload() {
+            error!("reload config faild: {}", e);
+        }
+    }
+}
 
-        Subscriber::new(
-            peer,
-            ws_stream,
-            subscriber_msg_sender.clone(),
-            broadcast_receiver.resubscribe(),
-        )
-        .start();
+/// 等待 SIGINT 或 SIGTERM
+async fn shutdown_signal() {
+    let ctrl_c = async {
+        if let Err(e) = tokio::signal::ctrl_c().await {
+            error!("listen for ctrl-c faild: {}", e);
+            std::future::pending::<()>().await;
+        }
+    };
+    #[cfg(unix)]
+    let terminate = async {
+        use tokio::signal::unix::{signal, SignalKind};
+        match signal(SignalKind::terminate()) {
+            Ok(mut sigterm) => {
+                sigterm.recv().await;
+            }
+            Err(e) => {
+                error!("listen for SIGTERM faild: {}", e);
+                std::future::pending::<()>().await;
+            }
+        }
+    };
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+    tokio::select! {
+        _ = ctrl_c => info!("received SIGINT"),
+        _ = terminate => info!("received SIGTERM"),
     }
-    Ok(())
 }
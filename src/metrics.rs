@@ -0,0 +1,163 @@
+use prometheus::{
+    Encoder, Histogram, HistogramOpts, IntCounter, IntCounterVec, IntGauge, IntGaugeVec, Opts,
+    Registry, TextEncoder,
+};
+use std::sync::LazyLock;
+
+/// 进程内所有 relay 共享的 Prometheus 指标
+pub(crate) static METRICS: LazyLock<Metrics> = LazyLock::new(Metrics::new);
+
+/// OK 消息中 NIP-20 前缀之外的原因都归为 other，避免标签无限增长
+const OK_PREFIXES: [&str; 7] = [
+    "duplicate",
+    "pow",
+    "blocked",
+    "rate-limited",
+    "invalid",
+    "restricted",
+    "error",
+];
+
+pub(crate) struct Metrics {
+    registry: Registry,
+    /// 当前的 websocket 和 HTTP 连接数，抓取时从 Connections 更新
+    pub connections: IntGauge,
+    /// 当前所有连接的订阅数
+    pub subscriptions: IntGauge,
+    /// 收到的客户端消息，按类型
+    pub messages_received: IntCounterVec,
+    /// 发送给客户端的消息，按类型
+    pub messages_sent: IntCounterVec,
+    /// 回复的 OK 消息，按结果
+    pub events: IntCounterVec,
+    /// Relay 查询存储的耗时
+    pub query_duration: Histogram,
+    /// Relay 每批写入存储的耗时
+    pub write_duration: Histogram,
+    /// 连接处理太慢，来不及转发而丢掉的广播 event 数
+    pub broadcast_lagged: IntCounter,
+    /// SQLite 连接池的连接数，按连接池和状态，抓取时更新
+    pub db_connections: IntGaugeVec,
+    /// 数据库使用的空间（字节），抓取时更新
+    pub db_size: IntGauge,
+}
+
+impl Metrics {
+    fn new() -> Self {
+        let registry = Registry::new_custom(Some("ksana".to_string()), None)
+            .expect("create metrics registry faild!");
+        let latency = || {
+            vec![
+                0.0005, 0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0,
+            ]
+        };
+        let metrics = Metrics {
+            connections: IntGauge::new("connections", "Open client connections").unwrap(),
+            subscriptions: IntGauge::new("subscriptions", "Active subscriptions").unwrap(),
+            messages_received: IntCounterVec::new(
+                Opts::new(
+                    "messages_received_total",
+                    "Client messages received by type",
+                ),
+                &["type"],
+            )
+            .unwrap(),
+            messages_sent: IntCounterVec::new(
+                Opts::new("messages_sent_total", "Relay messages sent by type"),
+                &["type"],
+            )
+            .unwrap(),
+            events: IntCounterVec::new(
+                Opts::new(
+                    "events_total",
+                    "OK results sent to clients by result (accepted or NIP-20 prefix)",
+                ),
+                &["result"],
+            )
+            .unwrap(),
+            query_duration: Histogram::with_opts(
+                HistogramOpts::new("query_duration_seconds", "Store query latency")
+                    .buckets(latency()),
+            )
+            .unwrap(),
+            write_duration: Histogram::with_opts(
+                HistogramOpts::new("write_duration_seconds", "Store batch write latency")
+                    .buckets(latency()),
+            )
+            .unwrap(),
+            broadcast_lagged: IntCounter::new(
+                "broadcast_lagged_total",
+                "Broadcast events dropped because a connection fell behind",
+            )
+            .unwrap(),
+            db_connections: IntGaugeVec::new(
+                Opts::new(
+                    "db_connections",
+                    "SQLite pool connections by pool and state",
+                ),
+                &["pool", "state"],
+            )
+            .unwrap(),
+            db_size: IntGauge::new("db_size_bytes", "Bytes used by the SQLite database").unwrap(),
+            registry,
+        };
+        let collectors: [Box<dyn prometheus::core::Collector>; 10] = [
+            Box::new(metrics.connections.clone()),
+            Box::new(metrics.subscriptions.clone()),
+            Box::new(metrics.messages_received.clone()),
+            Box::new(metrics.messages_sent.clone()),
+            Box::new(metrics.events.clone()),
+            Box::new(metrics.query_duration.clone()),
+            Box::new(metrics.write_duration.clone()),
+            Box::new(metrics.broadcast_lagged.clone()),
+            Box::new(metrics.db_connections.clone()),
+            Box::new(metrics.db_size.clone()),
+        ];
+        for c in collectors {
+            metrics
+                .registry
+                .register(c)
+                .expect("register metric faild!");
+        }
+        metrics
+    }
+
+    /// 按 OK 消息的结果计数
+    pub fn count_ok(&self, accepted: bool, reason: &str) {
+        let prefix = reason.split_once(':').map(|(p, _)| p.trim());
+        let result = match prefix {
+            Some(p) if OK_PREFIXES.contains(&p) => p,
+            _ if accepted => "accepted",
+            _ => "other",
+        };
+        self.events.with_label_values(&[result]).inc();
+    }
+
+    /// Prometheus 文本格式
+    pub fn encode(&self) -> String {
+        let mut buf = vec![];
+        TextEncoder::new()
+            .encode(&self.registry.gather(), &mut buf)
+            .expect("encode metrics faild!");
+        String::from_utf8(buf).expect("metrics are utf-8")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_metrics() {
+        METRICS.count_ok(true, "");
+        METRICS.count_ok(true, "duplicate: already have this event");
+        METRICS.count_ok(false, "something went wrong");
+        METRICS.messages_received.with_label_values(&["REQ"]).inc();
+        let text = METRICS.encode();
+        assert!(text.contains("ksana_events_total{result=\"accepted\"}"));
+        assert!(text.contains("ksana_events_total{result=\"duplicate\"}"));
+        assert!(text.contains("ksana_events_total{result=\"other\"}"));
+        assert!(text.contains("ksana_messages_received_total{type=\"REQ\"}"));
+        assert!(text.contains("# TYPE ksana_query_duration_seconds histogram"));
+    }
+}
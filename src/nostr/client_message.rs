@@ -1,7 +1,9 @@
 use super::{event::Event, EventKind, Id, PublicKey, Unixtime};
 
 use serde::{de::Visitor, ser::SerializeSeq, Deserialize, Serialize, Serializer};
+use std::collections::BTreeMap;
 
+/// 订阅的过滤条件（NIP-01），空的字段匹配所有 event
 #[derive(Serialize, Deserialize, Debug, Clone, Default)]
 pub struct Filter {
     #[serde(default)]
@@ -13,14 +15,6 @@ pub struct Filter {
     #[serde(skip_serializing_if = "Vec::is_empty")]
     #[serde(default)]
     pub kinds: Vec<EventKind>,
-    #[serde(skip_serializing_if = "Vec::is_empty")]
-    #[serde(rename = "#e")]
-    #[serde(default)]
-    pub e: Vec<Id>,
-    #[serde(skip_serializing_if = "Vec::is_empty")]
-    #[serde(rename = "#p")]
-    #[serde(default)]
-    pub p: Vec<PublicKey>,
     #[serde(skip_serializing_if = "Option::is_none")]
     #[serde(default)]
     pub since: Option<Unixtime>,
@@ -30,14 +24,60 @@ pub struct Filter {
     #[serde(skip_serializing_if = "Option::is_none")]
     #[serde(default)]
     pub limit: Option<usize>,
+    /// `#<单个字母>`：有这个名称的 tag 并且 tag 的第一个值在列表中，例如 `#e`、`#p`、`#d`
+    #[serde(flatten, with = "tag_filters")]
+    pub tags: BTreeMap<char, Vec<String>>,
+}
+
+/// filter 中 `#<单个字母>` 形式的字段，其他不认识的字段忽略
+mod tag_filters {
+    use serde::{de, ser::SerializeMap, Deserialize, Deserializer, Serializer};
+    use std::collections::{BTreeMap, HashMap};
+
+    pub fn serialize<S>(
+        tags: &BTreeMap<char, Vec<String>>,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        let mut map = serializer.serialize_map(Some(tags.len()))?;
+        for (name, values) in tags {
+            map.serialize_entry(&format!("#{}", name), values)?;
+        }
+        map.end()
+    }
+
+    pub fn deserialize<'de, D>(deserializer: D) -> Result<BTreeMap<char, Vec<String>>, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        let fields = HashMap::<String, serde_json::Value>::deserialize(deserializer)?;
+        let mut tags = BTreeMap::new();
+        for (key, value) in fields {
+            let mut chars = key.chars();
+            let name = match (chars.next(), chars.next(), chars.next()) {
+                (Some('#'), Some(c), None) if c.is_ascii_alphabetic() => c,
+                _ => continue,
+            };
+            let values = Vec::<String>::deserialize(value)
+                .map_err(|e| de::Error::custom(format!("{}: {}", key, e)))?;
+            tags.insert(name, values);
+        }
+        Ok(tags)
+    }
 }
 
+/// 客户端发给 relay 的消息
 #[derive(Debug, Clone)]
 pub enum ClientMessage {
     Auth(Event),
     Event(Event),
+    #[allow(clippy::upper_case_acronyms)]
     REQ(String, Vec<Filter>),
     Close(String),
+    /// NIP-45：统计满足 filter 的 event 数
+    Count(String, Vec<Filter>),
 }
 
 impl<'de> Deserialize<'de> for ClientMessage {
@@ -70,18 +110,27 @@ impl<'de> Visitor<'de> for ClientMessageVisitor {
                 let mut filters: Vec<Filter> = vec![];
 
                 if let Some(id) = oid {
-                    loop {
-                        if let Some(f) = seq.next_element()? {
-                            filters.push(f);
-                        } else {
-                            break;
-                        }
+                    while let Some(f) = seq.next_element()? {
+                        filters.push(f);
                     }
                     Ok(ClientMessage::REQ(id, filters))
                 } else {
                     panic!("unknown REQ msg")
                 }
             }
+            "COUNT" => {
+                let oid = seq.next_element::<String>()?;
+                let mut filters: Vec<Filter> = vec![];
+
+                if let Some(id) = oid {
+                    while let Some(f) = seq.next_element()? {
+                        filters.push(f);
+                    }
+                    Ok(ClientMessage::Count(id, filters))
+                } else {
+                    Err(serde::de::Error::invalid_length(1, &self))
+                }
+            }
             "AUTH" => {
                 let oe = seq.next_element::<Event>()?;
                 if let Some(e) = oe {
@@ -135,6 +184,13 @@ impl Serialize for ClientMessage {
                 seq.serialize_element("CLOSE")?;
                 seq.serialize_element(id)?;
             }
+            ClientMessage::Count(id, filters) => {
+                seq.serialize_element("COUNT")?;
+                seq.serialize_element(id)?;
+                for filter in filters {
+                    seq.serialize_element(filter)?;
+                }
+            }
         }
         seq.end()
     }
@@ -142,8 +198,9 @@ impl Serialize for ClientMessage {
 
 #[cfg(test)]
 mod tests {
-    use super::Filter;
-    use crate::nostr::{EventKind, Id, PublicKey};
+    use super::{ClientMessage, Filter};
+    use crate::nostr::{EventKind, Id, PublicKey, RelayMessage};
+    use std::collections::BTreeMap;
 
     #[test]
     fn test_serde_filter() {
@@ -157,20 +214,56 @@ mod tests {
             )
             .unwrap()],
             kinds: vec![EventKind::Metadata],
-            e: vec![Id::try_from_hex_string(
-                "5cd7d34f0ad72dac07cae33c4ed784a835f766343a3e7e74f9c7d6b8e9cca449",
-            )
-            .unwrap()],
-            p: vec![PublicKey::try_from_hex_string(
-                "9ab2f6b34894c95e7e36cea26fecf8dea88f383ed8d6e652b1a8d749695825e4",
-            )
-            .unwrap()],
+            tags: BTreeMap::from([(
+                'e',
+                vec![
+                    "5cd7d34f0ad72dac07cae33c4ed784a835f766343a3e7e74f9c7d6b8e9cca449".to_string(),
+                ],
+            )]),
             since: None,
             until: None,
             limit: Some(200),
         };
         let str = serde_json::to_string(&filter);
         println!("{:?}", str);
+        assert!(str.unwrap().contains(r##""#e":["5cd7d34f"##));
+    }
+
+    #[test]
+    fn test_tag_filters() {
+        let filter: Filter = serde_json::from_str(
+            r##"{"kinds":[30023],"#d":["post"],"#a":["30023:abc:post"],"#tt":["x"],"search":"x"}"##,
+        )
+        .unwrap();
+        assert_eq!(filter.kinds, vec![EventKind::Other(30023)]);
+        assert_eq!(
+            filter.tags,
+            BTreeMap::from([
+                ('a', vec!["30023:abc:post".to_string()]),
+                ('d', vec!["post".to_string()]),
+            ])
+        );
+        assert!(serde_json::from_str::<Filter>(r##"{"#e":[1]}"##).is_err());
+    }
+
+    #[test]
+    fn test_count() {
+        let msg: ClientMessage =
+            serde_json::from_str(r#"["COUNT","c1",{"kinds":[3]},{"kinds":[7]}]"#).unwrap();
+        let ClientMessage::Count(id, filters) = &msg else {
+            panic!("not a COUNT message");
+        };
+        assert_eq!((id.as_str(), filters.len()), ("c1", 2));
+        assert_eq!(
+            serde_json::to_string(&msg).unwrap(),
+            r#"["COUNT","c1",{"kinds":[3]},{"kinds":[7]}]"#
+        );
+        let reply = RelayMessage::Count("c1".to_string(), 42);
+        assert_eq!(
+            serde_json::to_string(&reply).unwrap(),
+            r#"["COUNT","c1",{"count":42}]"#
+        );
+        assert!(serde_json::from_str::<ClientMessage>(r#"["COUNT"]"#).is_err());
     }
     #[test]
     fn test_serde_event() {
@@ -184,14 +277,12 @@ mod tests {
             )
             .unwrap()],
             kinds: vec![EventKind::Metadata],
-            e: vec![Id::try_from_hex_string(
-                "5cd7d34f0ad72dac07cae33c4ed784a835f766343a3e7e74f9c7d6b8e9cca449",
-            )
-            .unwrap()],
-            p: vec![PublicKey::try_from_hex_string(
-                "9ab2f6b34894c95e7e36cea26fecf8dea88f383ed8d6e652b1a8d749695825e4",
-            )
-            .unwrap()],
+            tags: BTreeMap::from([(
+                'e',
+                vec![
+                    "5cd7d34f0ad72dac07cae33c4ed784a835f766343a3e7e74f9c7d6b8e9cca449".to_string(),
+                ],
+            )]),
             since: None,
             until: None,
             limit: Some(200),
@@ -1,9 +1,9 @@
 use thiserror::Error;
 
+/// 协议类型的错误
 #[derive(Error, Debug)]
 pub enum Error {
     /// Wrong length hex string
-    #[allow(dead_code)]
     #[error("Wrong length hex string")]
     WrongLengthHexString,
     /// Hex string decoding error
@@ -17,7 +17,7 @@ pub enum Error {
     Signature(#[from] k256::ecdsa::Error),
 
     #[error("verifier error")]
-    #[allow(dead_code)]
+    #[allow(clippy::enum_variant_names)]
     VerifierError,
 
     #[error("event verify error")]
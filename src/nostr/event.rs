@@ -2,9 +2,11 @@ use super::{Error, EventKind, Id, PrivateKey, PublicKey, Signature, Tag, Unixtim
 use k256::schnorr::signature::DigestVerifier;
 use k256::schnorr::VerifyingKey;
 use k256::sha2::{Digest, Sha256};
-use serde::{Deserialize, Serialize};
+use serde::{de, Deserialize, Deserializer, Serialize, Serializer};
+use serde_json::{value::RawValue, Value};
+use std::sync::Arc;
 
-#[allow(dead_code)]
+/// 还没有签名的 event，见 [`Event::new`]
 pub struct PreEvent {
     /// The public key of the actor who is creating the event
     pub pubkey: PublicKey,
@@ -18,25 +20,77 @@ pub struct PreEvent {
     pub content: String,
 }
 
-#[derive(Clone, Serialize, Deserialize, Debug)]
+impl PreEvent {
+    /// `pubkey` 在当前时间创建的 event
+    pub fn new(pubkey: PublicKey, kind: EventKind, tags: Vec<Tag>, content: String) -> Self {
+        PreEvent {
+            pubkey,
+            created_at: Unixtime::now(),
+            kind,
+            tags,
+            content,
+        }
+    }
+}
+
+/// 签过名的 event（NIP-01）
+///
+/// 保留解析时的原始 JSON，再次序列化时原样输出，转发给客户端的就是作者签名的那份数据。
+///
+/// 字段只读，修改后原始 JSON 就和字段对不上了；需要不同的内容时用 [`PreEvent`] 重新创建
+#[derive(Clone, Debug)]
 pub struct Event {
     // 32-bytes lowercase hex-encoded sha256 of the the serialized event data
-    pub id: Id,
+    id: Id,
 
     // <32-bytes lowercase hex-encoded public key of the event creator>,
-    pub pubkey: PublicKey,
+    pubkey: PublicKey,
 
     // <unix timestamp in seconds>
-    pub created_at: Unixtime,
+    created_at: Unixtime,
 
-    pub kind: EventKind,
+    kind: EventKind,
 
-    pub tags: Vec<Tag>,
+    tags: Vec<Tag>,
 
-    pub content: String,
+    content: String,
 
     // "sig": <64-bytes signature of the sha256 hash of the serialized event data, which is the same as the "id" field>
-    pub sig: Signature,
+    sig: Signature,
+
+    // 收到 event 时的原始 JSON，序列化时原样输出，保证客户端拿到的就是签名时的那份数据
+    raw: Arc<RawValue>,
+
+    // 原始 JSON 中的 tag，计算 id 时使用；Tag 会丢掉 marker 之类的字段，不能用来校验
+    raw_tags: Vec<Vec<Value>>,
+
+    // 原始 JSON 中可以按 `#<字母>` 查询的 tag：名称和第一个值
+    indexed_tags: Vec<(char, String)>,
+}
+
+/// Event 的各个字段，用于从原始 JSON 中解析
+#[derive(Deserialize)]
+struct EventFields {
+    id: Id,
+    pubkey: PublicKey,
+    created_at: Unixtime,
+    kind: EventKind,
+    // 先按原样解析，索引用原始的值，Tag 解析时可能会规范化
+    tags: Vec<Vec<serde_json::Value>>,
+    content: String,
+    sig: Signature,
+}
+
+/// 借用 Event 各个字段，用于生成新 event 的原始 JSON
+#[derive(Serialize)]
+struct EventFieldsRef<'a> {
+    id: &'a Id,
+    pubkey: &'a PublicKey,
+    created_at: &'a Unixtime,
+    kind: &'a EventKind,
+    tags: &'a Vec<Tag>,
+    content: &'a String,
+    sig: &'a Signature,
 }
 
 macro_rules! serialize_inner_event {
@@ -53,48 +107,158 @@ macro_rules! serialize_inner_event {
 }
 
 impl Event {
-    #[allow(dead_code)]
+    /// 计算 `input` 的 id 并用 `privkey` 签名
     pub fn new(input: PreEvent, privkey: &PrivateKey) -> Result<Event, Error> {
         let id = Self::hash(&input)?;
         let sig = privkey.sign_id(id)?;
+        let raw = serde_json::value::to_raw_value(&EventFieldsRef {
+            id: &id,
+            pubkey: &input.pubkey,
+            created_at: &input.created_at,
+            kind: &input.kind,
+            tags: &input.tags,
+            content: &input.content,
+            sig: &sig,
+        })?;
+        Self::from_raw_value(raw)
+    }
+
+    /// 从原始 JSON 解析 event，并保留原始字节
+    pub fn from_raw(json: &str) -> Result<Event, Error> {
+        let raw = RawValue::from_string(json.to_owned())?;
+        Self::from_raw_value(raw)
+    }
+
+    fn from_raw_value(raw: Box<RawValue>) -> Result<Event, Error> {
+        let fields: EventFields = serde_json::from_str(raw.get())?;
+        let indexed_tags = fields
+            .tags
+            .iter()
+            .filter_map(|tag| match tag.as_slice() {
+                [Value::String(name), Value::String(value), ..] => {
+                    let mut chars = name.chars();
+                    match (chars.next(), chars.next()) {
+                        (Some(c), None) if c.is_ascii_alphabetic() => Some((c, value.clone())),
+                        _ => None,
+                    }
+                }
+                _ => None,
+            })
+            .collect();
+        let tags = fields
+            .tags
+            .iter()
+            .map(|tag| serde_json::from_value(Value::Array(tag.clone())))
+            .collect::<Result<_, _>>()?;
         Ok(Event {
-            id,
-            pubkey: input.pubkey,
-            created_at: input.created_at,
-            kind: input.kind,
-            tags: input.tags,
-            content: input.content,
-            sig,
+            id: fields.id,
+            pubkey: fields.pubkey,
+            created_at: fields.created_at,
+            kind: fields.kind,
+            tags,
+            content: fields.content,
+            sig: fields.sig,
+            raw: raw.into(),
+            raw_tags: fields.tags,
+            indexed_tags,
         })
     }
 
+    /// 收到 event 时的原始 JSON
+    pub fn raw(&self) -> &str {
+        self.raw.get()
+    }
+
+    /// event 的 id，即序列化后的 event 数据的 sha256
+    pub fn id(&self) -> Id {
+        self.id
+    }
+
+    /// 作者的公钥
+    pub fn pubkey(&self) -> &PublicKey {
+        &self.pubkey
+    }
+
+    /// 创建时间
+    pub fn created_at(&self) -> Unixtime {
+        self.created_at
+    }
+
+    pub fn kind(&self) -> EventKind {
+        self.kind
+    }
+
+    pub fn tags(&self) -> &[Tag] {
+        &self.tags
+    }
+
+    /// 名称是单个字母的 tag 的名称和第一个值，取自原始 JSON，与存储中的 tag 索引一致
+    pub fn indexed_tags(&self) -> &[(char, String)] {
+        &self.indexed_tags
+    }
+
+    pub fn content(&self) -> &str {
+        &self.content
+    }
+
+    /// 对 id 的签名
+    pub fn sig(&self) -> &Signature {
+        &self.sig
+    }
+
+    /// 第一个 `d` tag 的值，用于参数化的 replaceable event（NIP-33）
+    pub fn d_tag(&self) -> &str {
+        self.tags
+            .iter()
+            .find_map(|t| match t {
+                Tag::Other { tag, data } if tag == "d" => {
+                    Some(data.first().map_or("", |d| d.as_str()))
+                }
+                _ => None,
+            })
+            .unwrap_or("")
+    }
+
+    /// 校验 id 与内容一致并且签名有效
     pub fn verify(&self) -> Result<(), Error> {
-        let serialized = serialize_inner_event!(
-            &self.pubkey,
-            &self.created_at,
-            &self.kind,
-            &self.tags,
-            &self.content
-        );
+        self.verify_with(&self.pubkey.verifying_key()?)
+    }
 
+    /// 使用已解析好的公钥校验，避免每次都重新解析 pubkey
+    pub fn verify_with(&self, verifier: &VerifyingKey) -> Result<(), Error> {
         //  检验签名
-        let digest = Sha256::new_with_prefix(&serialized);
-        let verifier = VerifyingKey::from_bytes(&self.pubkey.0)?;
-        verifier.verify_digest(digest, &self.sig)?;
+        let digest = Sha256::new_with_prefix(self.serialize_inner()?);
+        verifier.verify_digest(digest.clone(), &self.sig)?;
 
         // 上述签名校验保证了消息内容和签名是相符的，但是换个 Id 但不改变内容和签名的情况下上述校验依旧能够
         // 所以还要校验 ID
-        // 校验 id 是否一致
-        let mut hasher = Sha256::new();
-        hasher.update(serialized.as_bytes());
-        let id = hasher.finalize();
-        if *id != self.id.0 {
+        Self::check_id(&self.id, digest)
+    }
+
+    /// 只校验 id 是否与内容的 hash 一致，不校验签名
+    pub fn verify_id(&self) -> Result<(), Error> {
+        Self::check_id(&self.id, Sha256::new_with_prefix(self.serialize_inner()?))
+    }
+
+    fn check_id(id: &Id, digest: Sha256) -> Result<(), Error> {
+        if *digest.finalize() != id.0 {
             Err(Error::HashMismatch)
         } else {
             Ok(())
         }
     }
 
+    /// 按原始 JSON 中的 tag 序列化，和作者签名的数据一致
+    fn serialize_inner(&self) -> Result<String, Error> {
+        Ok(serialize_inner_event!(
+            &self.pubkey,
+            &self.created_at,
+            &self.kind,
+            &self.raw_tags,
+            &self.content
+        ))
+    }
+
     // hash 计算出 id
     pub fn hash(input: &PreEvent) -> Result<Id, Error> {
         let serialized: String = serialize_inner_event!(
@@ -111,3 +275,86 @@ impl Event {
         Ok(Id(id))
     }
 }
+
+impl Serialize for Event {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        self.raw.serialize(serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for Event {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        let raw = Box::<RawValue>::deserialize(deserializer)?;
+        Event::from_raw_value(raw).map_err(de::Error::custom)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::nostr::RelayMessage;
+
+    #[test]
+    fn test_raw_preserved() {
+        let privkey = PrivateKey::gen();
+        let pre = PreEvent {
+            pubkey: PublicKey::try_from_hex_string(&privkey.get_public_key_string()).unwrap(),
+            created_at: Unixtime(1677600000),
+            kind: EventKind::TextNote,
+            tags: vec![],
+            content: "hello".to_string(),
+        };
+        let event = Event::new(pre, &privkey).unwrap();
+        event.verify().unwrap();
+
+        // 客户端发来的 JSON 可能带空白，转发时应保持原样
+        let json = serde_json::to_string_pretty(
+            &serde_json::from_str::<serde_json::Value>(event.raw()).unwrap(),
+        )
+        .unwrap();
+        let parsed: Event = serde_json::from_str(&json).unwrap();
+        parsed.verify().unwrap();
+        assert_eq!(parsed.raw(), json);
+        assert_eq!(serde_json::to_string(&parsed).unwrap(), json);
+
+        let frame = RelayMessage::Event("sub".to_string(), parsed.clone())
+            .to_json()
+            .unwrap();
+        assert_eq!(frame, format!("[\"EVENT\",\"sub\",{}]", json));
+        assert_eq!(
+            frame,
+            serde_json::to_string(&RelayMessage::Event("sub".to_string(), parsed)).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_verify_raw_tags() {
+        let privkey = PrivateKey::gen();
+        let root = Id([1; 32]).as_hex_string();
+        let pre = PreEvent {
+            pubkey: PublicKey::try_from_hex_string(&privkey.get_public_key_string()).unwrap(),
+            created_at: Unixtime(1677600000),
+            kind: EventKind::TextNote,
+            tags: vec![Tag::Other {
+                tag: "e".to_string(),
+                data: vec![root.clone(), "wss://r".to_string(), "reply".to_string()],
+            }],
+            content: "hi".to_string(),
+        };
+        // 解析成 Tag::Event 时 marker 会丢失，校验仍然要按原始的 tag
+        let event = Event::new(pre, &privkey).unwrap();
+        assert!(event.raw().contains(r#""wss://r","reply""#));
+        event.verify().unwrap();
+
+        // 改动 marker 后 id 和签名都对不上
+        let tampered = Event::from_raw(&event.raw().replace("reply", "root")).unwrap();
+        assert!(matches!(tampered.verify_id(), Err(Error::HashMismatch)));
+        assert!(tampered.verify().is_err());
+    }
+}
@@ -2,6 +2,7 @@ use std::fmt;
 
 use serde::{de::Visitor, Deserialize, Deserializer, Serialize};
 
+/// event 的 kind，不认识的 kind 保存为 `Other`
 #[derive(Clone, Copy, Debug, PartialEq)]
 pub enum EventKind {
     Metadata,
@@ -13,13 +14,33 @@ pub enum EventKind {
     Other(u64),
 }
 
+impl EventKind {
+    /// replaceable event（NIP-16）：每个作者每个 kind 只保留最新的一个
+    pub fn is_replaceable(&self) -> bool {
+        let k: u64 = (*self).into();
+        k == 0 || k == 3 || (10000..20000).contains(&k)
+    }
+
+    /// ephemeral event（NIP-16）：只广播，不保存
+    pub fn is_ephemeral(&self) -> bool {
+        let k: u64 = (*self).into();
+        (20000..30000).contains(&k)
+    }
+
+    /// 参数化的 replaceable event（NIP-33）：每个作者、kind 和 `d` tag 只保留最新的一个
+    pub fn is_parameterized_replaceable(&self) -> bool {
+        let k: u64 = (*self).into();
+        (30000..40000).contains(&k)
+    }
+}
+
 impl From<EventKind> for u64 {
     fn from(value: EventKind) -> Self {
         use EventKind::*;
         match value {
             Metadata => 0,
             TextNote => 1,
-            RecommendRelay => 3,
+            RecommendRelay => 2,
             EncryptedDirectMessage => 4,
             EventDeletion => 5,
             Auth => 22242,
@@ -78,3 +99,19 @@ impl Visitor<'_> for EventKindVisitor {
         Ok(From::<u64>::from(v))
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_kind_round_trip() {
+        for k in [0, 1, 2, 3, 4, 5, 22242, 30023] {
+            assert_eq!(u64::from(EventKind::from(k)), k);
+        }
+        // kind 2 不是 replaceable event，不能被当成 kind 3 覆盖掉联系人列表
+        assert_eq!(EventKind::from(2), EventKind::RecommendRelay);
+        assert!(!EventKind::RecommendRelay.is_replaceable());
+        assert!(EventKind::from(3).is_replaceable());
+    }
+}
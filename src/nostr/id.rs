@@ -3,15 +3,14 @@ use std::fmt;
 
 use super::Error;
 
-#[derive(Copy, Clone, PartialEq, Debug)]
+/// event 的 id，32 字节的 sha256
+#[derive(Copy, Clone, PartialEq, Eq, Hash, Debug)]
 pub struct Id(pub [u8; 32]);
 
 impl Id {
-    #[allow(dead_code)]
     pub fn as_hex_string(&self) -> String {
-        hex::encode(&self.0)
+        hex::encode(self.0)
     }
-    #[allow(dead_code)]
     pub fn try_from_hex_string(v: &str) -> Result<Id, Error> {
         let vec = hex::decode(v)?;
 
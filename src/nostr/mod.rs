@@ -1,3 +1,5 @@
+//! Nostr 协议类型：event、公私钥、tag、filter 以及客户端和 relay 之间的消息
+
 mod id;
 pub use id::Id;
 
@@ -23,7 +25,7 @@ mod private_key;
 pub use private_key::PrivateKey;
 
 mod event;
-pub use event::Event;
+pub use event::{Event, PreEvent};
 
 mod client_message;
 mod relay_message;
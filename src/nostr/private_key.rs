@@ -4,12 +4,13 @@ use k256::{
 };
 use rand_core::OsRng;
 
-use super::{Error, Id, Signature};
+use super::{Error, Id, PublicKey, Signature};
 
+/// 用于给 event 签名的 secp256k1 私钥
 pub struct PrivateKey(SigningKey);
 
 impl PrivateKey {
-    #[allow(dead_code)]
+    /// 随机生成一个新的私钥
     pub fn gen() -> PrivateKey {
         let signing_key = SigningKey::random(&mut OsRng);
         PrivateKey(signing_key)
@@ -19,18 +20,19 @@ impl PrivateKey {
         let sig = self.0.sign_prehash(&id.0)?;
         Ok(Signature(sig))
     }
-    #[allow(dead_code)]
+    /// 私钥对应的公钥
+    pub fn public_key(&self) -> PublicKey {
+        PublicKey(self.0.verifying_key().to_bytes().into())
+    }
     pub fn get_public_key_string(&self) -> String {
         let pubkey = self.0.verifying_key();
         hex::encode(pubkey.to_bytes())
     }
 
     // 警告：这将导致你的密码从内存中被暴露出来！！
-    #[allow(dead_code)]
     pub fn as_hex_string(&self) -> String {
         hex::encode(self.0.to_bytes())
     }
-    #[allow(dead_code)]
     pub fn try_from_hex_string(v: &str) -> Result<PrivateKey, Error> {
         let vec = hex::decode(v)?;
         Ok(PrivateKey(SigningKey::from_bytes(&vec)?))
@@ -1,12 +1,13 @@
+use k256::schnorr::VerifyingKey;
 use serde::{de::Visitor, Deserialize, Serialize, Serializer};
 
 use super::Error;
 
-#[derive(Debug, Clone, PartialEq)]
+/// 32 字节的 x-only secp256k1 公钥
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
 pub struct PublicKey(pub [u8; 32]);
 
 impl PublicKey {
-    #[allow(dead_code)]
     pub fn try_from_hex_string(v: &str) -> Result<PublicKey, Error> {
         let vec = hex::decode(v)?;
         Ok(PublicKey(
@@ -14,7 +15,11 @@ impl PublicKey {
         ))
     }
     pub fn as_hex_string(&self) -> String {
-        hex::encode(&self.0)
+        hex::encode(self.0)
+    }
+    /// 解析为用于校验签名的公钥
+    pub fn verifying_key(&self) -> Result<VerifyingKey, Error> {
+        Ok(VerifyingKey::from_bytes(&self.0)?)
     }
 }
 
@@ -1,12 +1,45 @@
 use serde::{de::Visitor, ser::SerializeSeq, Deserialize, Serialize};
 
-use super::event::Event;
+use super::{event::Event, Id};
 
+/// relay 发给客户端的消息
 #[derive(Debug, Clone)]
+#[allow(clippy::large_enum_variant)]
 pub enum RelayMessage {
     Auth(String),
     Event(String, Event),
     Notice(String),
+    /// NIP-20 命令结果：event id、是否接受、原因
+    Ok(Id, bool, String),
+    /// 订阅被 relay 拒绝或关闭：subscription id、原因
+    Closed(String, String),
+    /// NIP-45 COUNT 的结果：subscription id、event 数
+    Count(String, u64),
+}
+
+impl RelayMessage {
+    /// 序列化为发送给客户端的 JSON 文本
+    ///
+    /// EVENT 消息直接拼接 event 的原始 JSON，不再经过 serde 重新序列化
+    pub fn to_json(&self) -> Result<String, serde_json::Error> {
+        match self {
+            RelayMessage::Event(id, e) => Ok(Self::event_frame(id, e)),
+            _ => serde_json::to_string(self),
+        }
+    }
+
+    /// 拼接 `["EVENT", <subscription id>, <raw event>]`
+    pub fn event_frame(subscription_id: &str, e: &Event) -> String {
+        let id = serde_json::to_string(subscription_id).expect("serialize str never fails");
+        let raw = e.raw();
+        let mut frame = String::with_capacity(id.len() + raw.len() + 12);
+        frame.push_str("[\"EVENT\",");
+        frame.push_str(&id);
+        frame.push(',');
+        frame.push_str(raw);
+        frame.push(']');
+        frame
+    }
 }
 
 impl Serialize for RelayMessage {
@@ -30,11 +63,33 @@ impl Serialize for RelayMessage {
                 seq.serialize_element("NOTICE")?;
                 seq.serialize_element(str)?;
             }
+            RelayMessage::Ok(id, accepted, message) => {
+                seq.serialize_element("OK")?;
+                seq.serialize_element(id)?;
+                seq.serialize_element(accepted)?;
+                seq.serialize_element(message)?;
+            }
+            RelayMessage::Closed(id, message) => {
+                seq.serialize_element("CLOSED")?;
+                seq.serialize_element(id)?;
+                seq.serialize_element(message)?;
+            }
+            RelayMessage::Count(id, count) => {
+                seq.serialize_element("COUNT")?;
+                seq.serialize_element(id)?;
+                seq.serialize_element(&CountResult { count: *count })?;
+            }
         }
         seq.end()
     }
 }
 
+/// COUNT 消息中的 `{"count": <integer>}`
+#[derive(Serialize, Deserialize)]
+struct CountResult {
+    count: u64,
+}
+
 struct RelayMessageVisitor;
 
 impl<'de> Deserialize<'de> for RelayMessage {
@@ -63,25 +118,52 @@ impl<'de> Visitor<'de> for RelayMessageVisitor {
         match tagname {
             "EVENT" => {
                 if let (Some(id), Some(event)) = (seq.next_element()?, seq.next_element()?) {
-                    return Ok(RelayMessage::Event(id, event));
+                    Ok(RelayMessage::Event(id, event))
                 } else {
                     panic!("id or envet not found in RelayMessage::Event");
                 }
             }
             "AUTH" => {
                 if let Some(str) = seq.next_element()? {
-                    return Ok(RelayMessage::Auth(str));
+                    Ok(RelayMessage::Auth(str))
                 } else {
                     panic!("invalid auth msg in RelayMessage::Auth");
                 }
             }
             "NOTICE" => {
                 if let Some(str) = seq.next_element()? {
-                    return Ok(RelayMessage::Notice(str));
+                    Ok(RelayMessage::Notice(str))
                 } else {
                     panic!("content not found in RelayMessage::Notice");
                 }
             }
+            "OK" => {
+                if let (Some(id), Some(accepted), Some(message)) = (
+                    seq.next_element()?,
+                    seq.next_element()?,
+                    seq.next_element()?,
+                ) {
+                    Ok(RelayMessage::Ok(id, accepted, message))
+                } else {
+                    panic!("id, status or message not found in RelayMessage::Ok");
+                }
+            }
+            "CLOSED" => {
+                if let (Some(id), Some(message)) = (seq.next_element()?, seq.next_element()?) {
+                    Ok(RelayMessage::Closed(id, message))
+                } else {
+                    panic!("id or message not found in RelayMessage::Closed");
+                }
+            }
+            "COUNT" => {
+                if let (Some(id), Some(result)) =
+                    (seq.next_element()?, seq.next_element::<CountResult>()?)
+                {
+                    Ok(RelayMessage::Count(id, result.count))
+                } else {
+                    panic!("id or count not found in RelayMessage::Count");
+                }
+            }
             _ => panic!("unknown RelayMessage"),
         }
     }
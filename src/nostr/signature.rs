@@ -3,16 +3,15 @@ use k256::schnorr::Signature as KSignature;
 use serde::{de::Visitor, Deserialize, Serialize};
 use std::{fmt, ops::Deref};
 
+/// 对 event id 的 64 字节 schnorr 签名
 #[derive(Clone, Debug)]
 pub struct Signature(pub KSignature);
 
 impl Signature {
-    #[allow(dead_code)]
     pub fn as_hex_string(&self) -> String {
         hex::encode(self.0.to_bytes())
     }
 
-    #[allow(dead_code)]
     pub fn try_from_hex_string(v: &str) -> Result<Signature, Error> {
         let vec: Vec<u8> = hex::decode(v)?;
         Ok(Signature(KSignature::try_from(&*vec)?))
@@ -7,6 +7,7 @@ use serde::{
     Deserialize, Deserializer, Serialize,
 };
 
+/// event 的 tag，序列化为字符串数组
 #[derive(Clone, Debug)]
 pub enum Tag {
     // 与其他事件相关
@@ -36,7 +37,6 @@ pub enum Tag {
 }
 
 impl Tag {
-    #[allow(dead_code)]
     pub fn tagname(&self) -> String {
         match self {
             Tag::Event { .. } => "e".to_string(),
@@ -148,11 +148,11 @@ impl<'de> Visitor<'de> for TagVisitor {
     where
         A: SeqAccess<'de>,
     {
-        let tagname: &str = match seq.next_element()? {
+        let tagname: String = match seq.next_element()? {
             Some(e) => e,
             None => return Ok(Tag::Empty),
         };
-        match tagname {
+        match tagname.as_str() {
             "e" => {
                 let id: Id = match seq.next_element()? {
                     Some(id) => id,
@@ -1,3 +1,16 @@
 use serde::{Deserialize, Serialize};
-#[derive(Serialize, Deserialize, PartialEq, PartialOrd, Clone, Debug)]
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Unix 时间戳，单位为秒
+#[derive(Serialize, Deserialize, PartialEq, PartialOrd, Clone, Copy, Debug)]
 pub struct Unixtime(pub i64);
+
+impl Unixtime {
+    /// 当前时间
+    pub fn now() -> Unixtime {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .expect("get now time faild!");
+        Unixtime(now.as_secs() as i64)
+    }
+}
@@ -3,8 +3,8 @@ use crate::nostr::{Event, Filter};
 pub struct EventFilter;
 
 impl EventFilter {
-    pub fn any_filter(evt: &Event, filters: &Vec<Filter>) -> bool {
-        filters.into_iter().any(|filter| Self::filter(&evt, filter))
+    pub fn any_filter(evt: &Event, filters: &[Filter]) -> bool {
+        filters.iter().any(|filter| Self::filter(evt, filter))
     }
 
     pub fn filter(evt: &Event, filter: &Filter) -> bool {
@@ -12,30 +12,102 @@ impl EventFilter {
             ids,
             authors,
             kinds,
-            e,
-            p,
             since,
             until,
             limit: _,
+            tags,
         } = filter;
 
-        macro_rules! check_filter_vec {
-            ($(($field:ident, $key:ident)),*) => {
-                $(
-                if $field.is_empty() {
-                    true
-                } else {
-                    $field.contains(&evt.$key)
-                } &&)* true
-            };
-        }
-        if check_filter_vec! {(ids, id), (authors, pubkey), (kinds, kind), (e, id), (p, pubkey)}
-            && since.as_ref().map_or(true, |s| evt.created_at > *s)
-            && until.as_ref().map_or(true, |s| evt.created_at < *s)
-        {
-            true
-        } else {
-            false
-        }
+        // #e、#p 等匹配的是 event 的 tag，而不是 event 本身的 id 和作者
+        let tagged = tags.iter().all(|(name, values)| {
+            values.is_empty()
+                || evt
+                    .indexed_tags()
+                    .iter()
+                    .any(|(n, v)| n == name && values.contains(v))
+        });
+        let matched = (ids.is_empty() || ids.contains(&evt.id()))
+            && (authors.is_empty() || authors.contains(evt.pubkey()))
+            && (kinds.is_empty() || kinds.contains(&evt.kind()));
+        matched
+            && tagged
+            && since.as_ref().is_none_or(|s| evt.created_at() > *s)
+            && until.as_ref().is_none_or(|s| evt.created_at() < *s)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::nostr::{EventKind, PreEvent, PrivateKey, Tag};
+    use std::collections::BTreeMap;
+
+    #[test]
+    fn test_tag_filter() {
+        let (alice, bob) = (PrivateKey::gen(), PrivateKey::gen());
+        let note = Event::new(
+            PreEvent::new(alice.public_key(), EventKind::TextNote, vec![], "".into()),
+            &alice,
+        )
+        .unwrap();
+        let reply = Event::new(
+            PreEvent::new(
+                bob.public_key(),
+                EventKind::TextNote,
+                vec![
+                    Tag::Event {
+                        id: note.id(),
+                        recommended_relay_url: None,
+                        marker: None,
+                    },
+                    Tag::Pubkey {
+                        pubkey: alice.public_key(),
+                        recommended_relay_url: None,
+                        petname: None,
+                    },
+                ],
+                "".into(),
+            ),
+            &bob,
+        )
+        .unwrap();
+
+        // #e 和 #p 匹配引用了它们的 event，而不是 event 本身
+        let by_tag = |name, value: String| Filter {
+            tags: BTreeMap::from([(name, vec![value])]),
+            ..Default::default()
+        };
+        let by_e = by_tag('e', note.id().as_hex_string());
+        assert!(EventFilter::filter(&reply, &by_e));
+        assert!(!EventFilter::filter(&note, &by_e));
+        let by_p = by_tag('p', alice.public_key().as_hex_string());
+        assert!(EventFilter::filter(&reply, &by_p));
+        assert!(!EventFilter::filter(&note, &by_p));
+
+        // 任意单字母 tag 都可以查询
+        let article = Event::new(
+            PreEvent::new(
+                alice.public_key(),
+                EventKind::Other(30023),
+                vec![Tag::Other {
+                    tag: "d".to_string(),
+                    data: vec!["post".to_string()],
+                }],
+                "".into(),
+            ),
+            &alice,
+        )
+        .unwrap();
+        assert!(EventFilter::filter(&article, &by_tag('d', "post".into())));
+        assert!(!EventFilter::filter(&article, &by_tag('d', "other".into())));
+
+        // 按原始 JSON 中的值匹配，不受 Tag 解析时规范化的影响
+        let hex = alice.public_key().as_hex_string();
+        let upper = Event::from_raw(&reply.raw().replace(&hex, &hex.to_uppercase())).unwrap();
+        assert!(EventFilter::filter(
+            &upper,
+            &by_tag('p', hex.to_uppercase())
+        ));
+        assert!(!EventFilter::filter(&upper, &by_p));
     }
 }
@@ -0,0 +1,95 @@
+use serde::{Deserialize, Serialize};
+
+/// NIP-11 的 relay 信息文档
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct RelayInformation {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub description: Option<String>,
+    /// 管理员的公钥（hex）
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub pubkey: Option<String>,
+    /// 管理员的联系方式
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub contact: Option<String>,
+    pub supported_nips: Vec<u32>,
+    pub software: String,
+    pub version: String,
+    /// 服务端的限制，由 `[limits]` 和 `[auth]` 配置生成，不能在 `[info]` 中指定
+    #[serde(skip_deserializing, skip_serializing_if = "Option::is_none")]
+    pub limitation: Option<Limitation>,
+    /// 保留策略，由 `[retention]` 配置生成，不能在 `[info]` 中指定
+    #[serde(skip_deserializing, skip_serializing_if = "Vec::is_empty")]
+    pub retention: Vec<RetentionRule>,
+}
+
+/// NIP-11 的 `limitation`，没有限制的项不输出
+#[derive(Serialize, Debug, Clone, Default, PartialEq, Eq)]
+pub struct Limitation {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_message_length: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_subscriptions: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_filters: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_limit: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_subid_length: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_event_tags: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_content_length: Option<usize>,
+    pub auth_required: bool,
+}
+
+/// 一条保留规则，格式与 NIP-11 的 `retention` 相同
+///
+/// 没有 kinds 时适用于所有 kind；一个 kind 只受第一条包含它的规则约束
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq, Eq)]
+#[serde(default, deny_unknown_fields)]
+pub struct RetentionRule {
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub kinds: Vec<KindRange>,
+    /// 最长保留时间（秒）
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub time: Option<u64>,
+    /// 每个作者的每个 kind 最多保留多少个 event
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub count: Option<u64>,
+}
+
+/// 单个 kind，或者闭区间 `[start, end]`
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(untagged)]
+pub enum KindRange {
+    Kind(u64),
+    Range(u64, u64),
+}
+
+impl KindRange {
+    pub fn bounds(&self) -> (u64, u64) {
+        match *self {
+            KindRange::Kind(kind) => (kind, kind),
+            KindRange::Range(start, end) => (start, end),
+        }
+    }
+}
+
+impl Default for RelayInformation {
+    fn default() -> Self {
+        RelayInformation {
+            name: None,
+            description: None,
+            pubkey: None,
+            contact: None,
+            supported_nips: vec![1, 9, 11, 20, 42, 45],
+            software: "https://github.com/lzcers/ksana-relay".to_string(),
+            version: env!("CARGO_PKG_VERSION").to_string(),
+            limitation: None,
+            retention: vec![],
+        }
+    }
+}
@@ -1,15 +1,63 @@
+mod cache;
 mod filter;
+mod info;
+mod policy;
+mod rate_limit;
 mod relayer;
+mod seen;
 mod subscriber;
+mod verifier;
 
 pub use filter::*;
-pub use relayer::*;
-pub use subscriber::*;
-use tokio::sync::oneshot::Sender;
+pub use info::*;
+pub use policy::*;
+pub(crate) use rate_limit::*;
+pub(crate) use relayer::*;
+pub(crate) use seen::*;
+pub(crate) use subscriber::*;
+pub use verifier::*;
 
-use crate::nostr::{Event, Filter, RelayMessage};
+use std::sync::Arc;
+use tokio::sync::{broadcast, mpsc, oneshot::Sender, watch};
 
-pub enum SubscriberEvent {
-    Event(Event),
+use crate::{
+    config::Config,
+    database::Database,
+    nostr::{Event, Filter, RelayMessage},
+    server::Connections,
+    ConfigReloader,
+};
+
+pub(crate) enum SubscriberEvent {
+    /// 客户端发布的 event，Relay 处理后通过 Sender 返回 OK 消息
+    Event(Box<Event>, Sender<RelayMessage>),
     Req(String, Vec<Filter>, Sender<Vec<RelayMessage>>),
+    /// NIP-45 COUNT，Relay 返回 COUNT 或 CLOSED 消息
+    Count(String, Vec<Filter>, Sender<RelayMessage>),
+    /// 就绪检查，Relay 收到后立即回复
+    Ping(Sender<()>),
+}
+
+/// 所有 Subscriber 共享的状态
+#[derive(Clone)]
+pub(crate) struct Context {
+    pub sender: mpsc::Sender<SubscriberEvent>,
+    pub broadcast_sender: broadcast::Sender<Event>,
+    pub seen: SeenEvents,
+    pub verifier: Verifier,
+    pub policy: Arc<dyn Policy>,
+    pub rate_limiter: RateLimiter,
+    pub connections: Connections,
+    /// 变为 true 时停止接受连接，已有的连接处理完当前消息后关闭
+    pub shutdown: watch::Receiver<bool>,
+    /// SQLite 存储，用于管理接口、指标和就绪检查，其他存储时为 None
+    pub database: Option<Database>,
+    pub reloader: ConfigReloader,
+}
+
+impl Context {
+    /// 当前的配置，重新加载后返回新的配置
+    pub fn config(&self) -> Arc<Config> {
+        self.reloader.current()
+    }
 }
@@ -0,0 +1,35 @@
+use crate::{
+    config::Config,
+    nostr::{Event, Filter, PublicKey},
+};
+
+/// 宿主程序自定义的准入策略
+///
+/// 在签名校验通过之后、交给 Relay 之前调用。返回 `Err(reason)` 时拒绝，
+/// reason 会原样发给客户端，按 NIP-20 约定最好以 `blocked:` 之类的前缀开头。
+/// `auth` 是当前连接通过 NIP-42 认证的公钥。
+/// 策略本身不会被替换，依赖配置的策略需要在 [`Policy::reload`] 中自行更新内部状态。
+pub trait Policy: Send + Sync {
+    /// 配置重新加载成功后调用，config 是新的配置
+    fn reload(&self, _config: &Config) {}
+
+    /// 是否接受客户端发布的 event
+    fn check_event(&self, _event: &Event, _auth: Option<&PublicKey>) -> Result<(), String> {
+        Ok(())
+    }
+
+    /// 是否接受客户端的订阅
+    fn check_req(
+        &self,
+        _subscription_id: &str,
+        _filters: &[Filter],
+        _auth: Option<&PublicKey>,
+    ) -> Result<(), String> {
+        Ok(())
+    }
+}
+
+/// 默认策略：全部接受
+pub struct AllowAll;
+
+impl Policy for AllowAll {}
@@ -0,0 +1,249 @@
+use crate::{
+    config::{MessageRates, Rate, RateLimitConfig},
+    nostr::{EventKind, PublicKey},
+};
+use std::{
+    collections::HashMap,
+    net::IpAddr,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+/// 令牌桶超过这个数量时清理长时间没有使用的桶
+const MAX_BUCKETS: usize = 10_000;
+/// 超过这个时间没有使用的桶会在清理时删除
+const BUCKET_IDLE: Duration = Duration::from_secs(600);
+
+/// 受限流的客户端消息
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub(crate) enum MessageType {
+    Event,
+    Req,
+    Count,
+    Auth,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+enum BucketKey {
+    Ip(IpAddr, MessageType),
+    Pubkey(PublicKey, MessageType),
+    /// IP 和 kind 规则的下标
+    Kind(IpAddr, usize),
+}
+
+struct TokenBucket {
+    tokens: f64,
+    last: Instant,
+}
+
+impl TokenBucket {
+    /// 按经过的时间补充令牌，返回当前的令牌数
+    fn refill(&mut self, rate: Rate, now: Instant) -> f64 {
+        let elapsed = now.saturating_duration_since(self.last).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * rate.rate).min(rate.burst);
+        self.last = now;
+        self.tokens
+    }
+}
+
+#[derive(Default)]
+struct State {
+    buckets: HashMap<BucketKey, TokenBucket>,
+    /// 被封禁的 IP 和解封的时间
+    bans: HashMap<IpAddr, Instant>,
+    /// 各 IP 被限流的次数和最后一次被限流的时间，同一个 IP 的所有连接共享
+    strikes: HashMap<IpAddr, (u32, Instant)>,
+}
+
+/// 按 IP、认证过的公钥和 kind 限流，并记录被封禁的 IP
+///
+/// 在所有连接之间共享，限流的参数在每次检查时传入，配置变化后立即生效
+#[derive(Clone, Default)]
+pub(crate) struct RateLimiter(Arc<Mutex<State>>);
+
+impl RateLimiter {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// 一条消息是否允许通过，通过时从所有相关的桶中各取走一个令牌
+    ///
+    /// 只要有一个桶没有令牌就拒绝，这时不会取走任何令牌。ip 为 None 时只按公钥限流
+    pub fn check(
+        &self,
+        config: &RateLimitConfig,
+        ip: Option<IpAddr>,
+        pubkey: Option<&PublicKey>,
+        msg: MessageType,
+        kind: Option<EventKind>,
+    ) -> bool {
+        let select = |rates: &MessageRates| match msg {
+            MessageType::Event => rates.event,
+            MessageType::Req => rates.req,
+            MessageType::Count => rates.count,
+            MessageType::Auth => rates.auth,
+        };
+        let mut keys = vec![];
+        if let Some(ip) = ip {
+            keys.push((BucketKey::Ip(ip, msg), select(&config.ip)));
+        }
+        if let Some(pubkey) = pubkey {
+            keys.push((
+                BucketKey::Pubkey(pubkey.clone(), msg),
+                select(&config.pubkey),
+            ));
+        }
+        if let (Some(ip), Some(kind)) = (ip, kind) {
+            let kind = u64::from(kind);
+            if let Some((i, rule)) = config
+                .kinds
+                .iter()
+                .enumerate()
+                .find(|(_, rule)| rule.contains(kind))
+            {
+                keys.push((BucketKey::Kind(ip, i), rule.rate()));
+            }
+        }
+        keys.retain(|(_, rate)| !rate.is_unlimited());
+        if keys.is_empty() {
+            return true;
+        }
+
+        let now = Instant::now();
+        let mut state = self.0.lock().expect("rate limiter lock poisoned");
+        if state.buckets.len() > MAX_BUCKETS {
+            state
+                .buckets
+                .retain(|_, b| now.saturating_duration_since(b.last) < BUCKET_IDLE);
+        }
+        let mut allowed = true;
+        for (key, rate) in &keys {
+            let bucket = state.buckets.entry(key.clone()).or_insert(TokenBucket {
+                tokens: rate.burst,
+                last: now,
+            });
+            if bucket.refill(*rate, now) < 1.0 {
+                allowed = false;
+            }
+        }
+        if allowed {
+            for (key, _) in &keys {
+                if let Some(bucket) = state.buckets.get_mut(key) {
+                    bucket.tokens -= 1.0;
+                }
+            }
+        }
+        allowed
+    }
+
+    /// 给 ip 记一次 strike，达到 max_strikes 时封禁 ip 并返回 true
+    ///
+    /// 超过 BUCKET_IDLE 没有再被限流时重新计数，所以偶尔被限流的客户端不会累积到封禁
+    pub fn strike(&self, config: &RateLimitConfig, ip: IpAddr) -> bool {
+        if config.max_strikes == 0 {
+            return false;
+        }
+        let now = Instant::now();
+        let mut state = self.0.lock().expect("rate limiter lock poisoned");
+        if state.strikes.len() > MAX_BUCKETS {
+            state
+                .strikes
+                .retain(|_, (_, last)| now.saturating_duration_since(*last) < BUCKET_IDLE);
+        }
+        let (count, last) = state.strikes.entry(ip).or_insert((0, now));
+        if now.saturating_duration_since(*last) >= BUCKET_IDLE {
+            *count = 0;
+        }
+        *count += 1;
+        *last = now;
+        if *count < config.max_strikes {
+            return false;
+        }
+        state.strikes.remove(&ip);
+        drop(state);
+        self.ban(ip, Duration::from_secs(config.ban_secs));
+        true
+    }
+
+    /// 封禁 ip 一段时间
+    pub fn ban(&self, ip: IpAddr, duration: Duration) {
+        let mut state = self.0.lock().expect("rate limiter lock poisoned");
+        state.bans.insert(ip, Instant::now() + duration);
+    }
+
+    /// ip 是否在封禁中，顺便清理已经到期的封禁
+    pub fn is_banned(&self, ip: IpAddr) -> bool {
+        let now = Instant::now();
+        let mut state = self.0.lock().expect("rate limiter lock poisoned");
+        state.bans.retain(|_, until| *until > now);
+        state.bans.contains_key(&ip)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        config::{KindRate, Rate},
+        nostr::PrivateKey,
+        relay::KindRange,
+    };
+
+    #[test]
+    fn test_rate_limiter() {
+        let mut config = RateLimitConfig::default();
+        config.ip.event = Rate::new(0.001, 3.0);
+        config.pubkey.event = Rate::new(0.001, 2.0);
+        config.kinds = vec![KindRate {
+            kinds: vec![KindRange::Range(30000, 39999)],
+            rate: 0.001,
+            burst: 1.0,
+        }];
+        let limiter = RateLimiter::new();
+        let ip: IpAddr = "127.0.0.1".parse().unwrap();
+        let other: IpAddr = "::1".parse().unwrap();
+        let pubkey = PrivateKey::gen().public_key();
+        let check = |ip, pubkey, kind| {
+            limiter.check(&config, Some(ip), pubkey, MessageType::Event, Some(kind))
+        };
+
+        // kind 规则的桶用完后，同一 IP 的其他 kind 不受影响
+        assert!(check(ip, None, EventKind::from(30001)));
+        assert!(!check(ip, None, EventKind::from(30001)));
+        assert!(check(ip, None, EventKind::TextNote));
+        assert!(check(ip, None, EventKind::TextNote));
+        assert!(!check(ip, None, EventKind::TextNote));
+        // 换一个 IP 仍然受公钥的限制
+        assert!(check(other, Some(&pubkey), EventKind::TextNote));
+        assert!(check(other, Some(&pubkey), EventKind::TextNote));
+        assert!(!check(other, Some(&pubkey), EventKind::TextNote));
+        assert!(check(other, None, EventKind::TextNote));
+        // REQ 使用单独的桶
+        assert!(limiter.check(&config, Some(ip), None, MessageType::Req, None));
+        // 没有 IP 的连接只受公钥的限制
+        assert!(limiter.check(
+            &config,
+            None,
+            None,
+            MessageType::Event,
+            Some(EventKind::TextNote)
+        ));
+        assert!(!limiter.check(
+            &config,
+            None,
+            Some(&pubkey),
+            MessageType::Event,
+            Some(EventKind::TextNote)
+        ));
+
+        limiter.ban(ip, Duration::from_secs(60));
+        assert!(limiter.is_banned(ip));
+        assert!(!limiter.is_banned(other));
+
+        // 同一个 IP 的 strike 在多个连接之间累计
+        config.max_strikes = 2;
+        assert!(!limiter.strike(&config, other));
+        assert!(limiter.strike(&config, other));
+        assert!(limiter.is_banned(other));
+    }
+}
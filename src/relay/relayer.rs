@@ -1,123 +1,436 @@
-use super::SubscriberEvent;
+use super::{SeenEvents, SubscriberEvent};
 use crate::{
-    database,
-    nostr::{Event, EventKind, Id, PublicKey, RelayMessage, Tag},
-    relay::EventFilter,
+    config::{Config, QuotaConfig},
+    database::{is_newer, same_slot, Error as DatabaseError, EventStore, Usage, WriteOp},
+    metrics::METRICS,
+    nostr::{Event, EventKind, Filter, Id, PublicKey, RelayMessage},
 };
 use log::{error, info};
-use tokio::sync::{broadcast::Sender, mpsc::Receiver};
+use std::{
+    collections::{HashMap, HashSet},
+    sync::Arc,
+    time::Duration,
+};
+use tokio::{
+    sync::{broadcast::Sender, mpsc::Receiver, oneshot, watch},
+    task::JoinHandle,
+    time::{timeout_at, Instant},
+};
 
-pub struct Relay {
-    db: database::Database,
+/// replaceable event 的位置：作者、kind 和参数化 replaceable event 的 `d` tag
+type Slot = (PublicKey, u64, String);
+
+/// evt 所在的位置，不是 replaceable event 时返回 None
+fn slot(evt: &Event) -> Option<Slot> {
+    let kind = evt.kind();
+    if kind.is_parameterized_replaceable() {
+        Some((evt.pubkey().clone(), kind.into(), evt.d_tag().to_string()))
+    } else if kind.is_replaceable() {
+        Some((evt.pubkey().clone(), kind.into(), String::new()))
+    } else {
+        None
+    }
+}
+
+pub(crate) struct Relay {
+    store: Arc<dyn EventStore>,
     subscriber_msg_receiver: Receiver<SubscriberEvent>,
     broadcast_sender: Sender<Event>,
-    events: Vec<Event>,
+    seen: SeenEvents,
+    config: watch::Receiver<Arc<Config>>,
 }
 
 impl Relay {
     pub fn new(
-        db: database::Database,
+        store: Arc<dyn EventStore>,
         rec: Receiver<SubscriberEvent>,
         broadcast_sender: Sender<Event>,
+        seen: SeenEvents,
+        config: watch::Receiver<Arc<Config>>,
     ) -> Relay {
         Relay {
-            db,
+            store,
             subscriber_msg_receiver: rec,
             broadcast_sender,
-            events: vec![],
+            seen,
+            config,
         }
     }
 
-    pub fn start(mut self) {
+    /// 在单独的任务中处理 Subscriber 的消息
+    ///
+    /// 所有 Subscriber 的发送端都关闭后，处理完队列中剩余的消息，任务结束
+    pub fn start(mut self) -> JoinHandle<()> {
         info!("relay start!");
         tokio::spawn(async move {
-            info!("init relay by get events from db!");
-            match self.db.get_events().await {
-                Ok(events) => self.events = events,
-                Err(e) => {
-                    error!("get events faild from db: {}", e)
-                }
-            }
             self.on_subscriber_event().await;
-        });
+        })
     }
 
     pub async fn on_subscriber_event(&mut self) {
         info!("on subscriber event...");
 
-        while let Some(v) = self.subscriber_msg_receiver.recv().await {
-            match v {
-                SubscriberEvent::Event(e) => {
-                    self.process_event(e).await;
+        let mut next = None;
+        loop {
+            let msg = match next.take() {
+                Some(msg) => msg,
+                None => match self.subscriber_msg_receiver.recv().await {
+                    Some(msg) => msg,
+                    None => break,
+                },
+            };
+            match msg {
+                SubscriberEvent::Event(e, sx) => {
+                    next = self.process_events(*e, sx).await;
                 }
                 SubscriberEvent::Req(id, filters, sx) => {
-                    let mut events: Vec<RelayMessage> = vec![];
-                    for event in &self.events {
-                        for filter in &filters {
-                            if EventFilter::filter(&event, filter) {
-                                events.push(RelayMessage::Event(id.clone(), event.clone()))
-                            }
+                    let timer = METRICS.query_duration.start_timer();
+                    let result = self.store.query(&filters).await;
+                    timer.observe_duration();
+                    let events = match result {
+                        Ok(events) => events
+                            .into_iter()
+                            .map(|e| RelayMessage::Event(id.clone(), e))
+                            .collect(),
+                        Err(e) => {
+                            error!("query events faild: {}", e);
+                            vec![]
                         }
+                    };
+                    if sx.send(events).is_err() {
+                        error!("relay msg send error");
                     }
-                    if let Err(_) = sx.send(events) {
+                }
+                SubscriberEvent::Count(id, filters, sx) => {
+                    let timer = METRICS.query_duration.start_timer();
+                    let result = self.store.count(&filters).await;
+                    timer.observe_duration();
+                    let msg = match result {
+                        Ok(count) => RelayMessage::Count(id, count),
+                        Err(e) => {
+                            error!("count events faild: {}", e);
+                            RelayMessage::Closed(id, "error: could not count events".to_string())
+                        }
+                    };
+                    if sx.send(msg).is_err() {
                         error!("relay msg send error");
                     }
                 }
+                SubscriberEvent::Ping(sx) => {
+                    let _ = sx.send(());
+                }
             }
         }
         info!("on_subscriber_event end");
     }
 
-    pub async fn process_event(&mut self, evt: Event) {
-        match evt.kind {
-            EventKind::TextNote => {
-                self.persist_event(&evt).await;
-                self.events.push(evt.clone());
+    /// 把连续到达的 event 凑成一批写入，再逐个回复 OK 并广播
+    ///
+    /// 批次按 batch_size 和 batch_linger 截断；中途收到 Req 时立即停止收集并返回它，
+    /// 由调用方在写入之后处理，保证 Req 能查到之前发布的 event
+    async fn process_events(
+        &mut self,
+        evt: Event,
+        sx: oneshot::Sender<RelayMessage>,
+    ) -> Option<SubscriberEvent> {
+        let config = self.config.borrow_and_update().clone();
+        let mut batch = vec![(evt, sx)];
+        let mut next = None;
+        let deadline = Instant::now() + Duration::from_millis(config.database.batch_linger_ms);
+        while batch.len() < config.database.batch_size {
+            // 已经到达的消息即使超过 deadline 也会立即返回
+            match timeout_at(deadline, self.subscriber_msg_receiver.recv()).await {
+                Ok(Some(SubscriberEvent::Event(e, sx))) => batch.push((*e, sx)),
+                Ok(Some(msg)) => {
+                    next = Some(msg);
+                    break;
+                }
+                Ok(None) | Err(_) => break,
+            }
+        }
+
+        let mut ids = HashSet::new();
+        let mut usages = HashMap::new();
+        let mut slots = HashMap::new();
+        let mut ops = vec![];
+        let mut pending = vec![];
+        for (evt, sx) in batch {
+            // 同一批中重复的 event 也按 duplicate 处理
+            if !ids.insert(evt.id()) || self.is_duplicate(&evt.id()).await {
+                let msg = RelayMessage::Ok(
+                    evt.id(),
+                    true,
+                    "duplicate: already have this event".to_string(),
+                );
+                if sx.send(msg).is_err() {
+                    error!("relay msg send error");
+                }
+                continue;
             }
-            EventKind::EventDeletion => {
-                let mut ids: Vec<&Id> = vec![];
-                evt.tags.iter().for_each(|tag| {
-                    if let Tag::Event { id, .. } = tag {
-                        ids.push(id);
-                    }
-                });
-                for id in ids {
-                    if self.delete_event(id, &evt.pubkey).await > 0 {
-                        self.events = self
-                            .events
-                            .iter()
-                            .filter(|e| e.id != *id)
-                            .map(|e| e.clone())
-                            .collect::<Vec<Event>>();
-                    }
+            let rejected = match self
+                .within_quota(&config.quota, &evt, &mut usages, &mut slots)
+                .await
+            {
+                Ok(true) => None,
+                Ok(false) => Some("blocked: quota exceeded"),
+                Err(e) => {
+                    error!("check quota faild: {}", e);
+                    Some("error: could not check quota")
                 }
+            };
+            if let Some(reason) = rejected {
+                let msg = RelayMessage::Ok(evt.id(), false, reason.to_string());
+                if sx.send(msg).is_err() {
+                    error!("relay msg send error");
+                }
+                continue;
             }
-            EventKind::EncryptedDirectMessage => {
-                self.persist_event(&evt).await;
-                self.events.push(evt.clone());
+            // ephemeral event 只转发，不保存
+            let op = WriteOp::for_event(evt.clone()).map(|op| {
+                ops.push(op);
+                ops.len() - 1
+            });
+            pending.push((evt, sx, op));
+        }
+
+        let results = if ops.is_empty() {
+            vec![]
+        } else {
+            let _timer = METRICS.write_duration.start_timer();
+            self.store.write(&ops).await
+        };
+        for (evt, sx, op) in pending {
+            let msg = match op.map(|i| &results[i]) {
+                None | Some(Ok(true)) => {
+                    self.seen.insert(evt.id());
+                    let id = evt.id();
+                    // 没有在线的 Subscriber 时发送会失败，忽略即可
+                    let _ = self.broadcast_sender.send(evt);
+                    RelayMessage::Ok(id, true, "".to_string())
+                }
+                Some(Ok(false)) => {
+                    RelayMessage::Ok(evt.id(), true, "duplicate: have a newer event".to_string())
+                }
+                Some(Err(DatabaseError::Duplicate(_))) => RelayMessage::Ok(
+                    evt.id(),
+                    true,
+                    "duplicate: already have this event".to_string(),
+                ),
+                Some(Err(e)) => {
+                    error!("new event save faild: {}", e);
+                    RelayMessage::Ok(evt.id(), false, "error: could not save event".to_string())
+                }
+            };
+            if sx.send(msg).is_err() {
+                error!("relay msg send error");
             }
-            _ => {}
         }
-        self.broadcast_sender
-            .send(evt)
-            .expect("broadcast event faild by relay");
+        next
+    }
+
+    /// 保存 evt 之后作者是否仍在配额之内，在配额之内时把 evt 计入 usages
+    ///
+    /// usages 缓存同一批中各作者的用量，slots 记录同一批中已经接受的 replaceable event，
+    /// 同一批中替换同一个位置的 event 按写入后的结果计算；ephemeral 和 deletion event 不受配额限制，
+    /// 这样超出配额的作者仍然可以删除自己的 event。替换已有 event 的 replaceable event
+    /// 只计入和旧 event 的字节数之差，达到配额的作者仍然可以更新 profile 等 event。
+    /// 查询用量失败时返回错误，由调用方拒绝这个 event
+    async fn within_quota(
+        &self,
+        quota: &QuotaConfig,
+        evt: &Event,
+        usages: &mut HashMap<PublicKey, Usage>,
+        slots: &mut HashMap<Slot, Event>,
+    ) -> Result<bool, DatabaseError> {
+        if !quota.is_enabled()
+            || evt.kind().is_ephemeral()
+            || evt.kind() == EventKind::EventDeletion
+        {
+            return Ok(true);
+        }
+        let (max_events, max_bytes) = quota.limits_for(evt.pubkey());
+        if max_events == 0 && max_bytes == 0 {
+            return Ok(true);
+        }
+        let usage = match usages.get(evt.pubkey()) {
+            Some(usage) => *usage,
+            None => self.store.usage(evt.pubkey()).await?,
+        };
+        let slot = slot(evt);
+        let old = match slot.as_ref().and_then(|slot| slots.get(slot)) {
+            Some(old) => Some(old.clone()),
+            None => self.replaced_event(evt).await?,
+        };
+        let usage = match old {
+            // 已有更新的 event，这个 event 不会被保存
+            Some(old) if is_newer(&old, evt) => return Ok(true),
+            Some(old) => Usage {
+                events: usage.events,
+                bytes: (usage.bytes + evt.raw().len() as u64)
+                    .saturating_sub(old.raw().len() as u64),
+            },
+            None => Usage {
+                events: usage.events + 1,
+                bytes: usage.bytes + evt.raw().len() as u64,
+            },
+        };
+        if (max_events > 0 && usage.events > max_events)
+            || (max_bytes > 0 && usage.bytes > max_bytes)
+        {
+            return Ok(false);
+        }
+        usages.insert(evt.pubkey().clone(), usage);
+        if let Some(slot) = slot {
+            slots.insert(slot, evt.clone());
+        }
+        Ok(true)
+    }
+
+    /// evt 保存后会替换掉的已有 event，evt 不是 replaceable event 时返回 None
+    async fn replaced_event(&self, evt: &Event) -> Result<Option<Event>, DatabaseError> {
+        let kind = evt.kind();
+        if !kind.is_replaceable() && !kind.is_parameterized_replaceable() {
+            return Ok(None);
+        }
+        let mut filter = Filter {
+            authors: vec![evt.pubkey().clone()],
+            kinds: vec![kind],
+            ..Default::default()
+        };
+        // 没有 `d` tag 的 event 不在 tag 索引中，只能按作者和 kind 查询后再比较
+        if kind.is_parameterized_replaceable() && !evt.d_tag().is_empty() {
+            filter.tags.insert('d', vec![evt.d_tag().to_string()]);
+        }
+        let events = self.store.query(&[filter]).await?;
+        Ok(events.into_iter().find(|old| same_slot(old, evt)))
     }
 
-    pub async fn delete_event(&mut self, id: &Id, pubkey: &PublicKey) -> u64 {
-        let result = self.db.delete_event(id, pubkey).await;
-        match result {
-            Ok(row) => row,
+    /// 先查内存中的 seen 集合，未命中再查数据库
+    async fn is_duplicate(&mut self, id: &Id) -> bool {
+        if self.seen.contains(id) {
+            return true;
+        }
+        match self.store.exists(id).await {
+            Ok(true) => {
+                self.seen.insert(*id);
+                true
+            }
+            Ok(false) => false,
             Err(e) => {
-                error!("delete event faild: {}", e);
-                0 as u64
+                error!("check event exists faild: {}", e);
+                false
             }
         }
     }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        config::QuotaConfig,
+        database::MemoryStore,
+        nostr::{PreEvent, PrivateKey, Unixtime},
+    };
+    use tokio::sync::{broadcast, mpsc};
+
+    async fn publish(relay: &mut Relay, event: Event) -> RelayMessage {
+        let (tx, rx) = oneshot::channel();
+        relay.process_events(event, tx).await;
+        rx.await.unwrap()
+    }
+
+    fn event(key: &PrivateKey, kind: EventKind, content: &str, created_at: i64) -> Event {
+        let mut pre = PreEvent::new(key.public_key(), kind, vec![], content.into());
+        pre.created_at = Unixtime(created_at);
+        Event::new(pre, key).unwrap()
+    }
+
+    fn relay(max_events: u64) -> (Relay, mpsc::Sender<SubscriberEvent>) {
+        let config = Config {
+            quota: QuotaConfig {
+                max_events,
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        let (_config_sender, config) = watch::channel(Arc::new(config));
+        let (sender, receiver) = mpsc::channel(4);
+        let (broadcast_sender, _) = broadcast::channel(1);
+        let relay = Relay::new(
+            Arc::new(MemoryStore::new()),
+            receiver,
+            broadcast_sender,
+            SeenEvents::new(10),
+            config,
+        );
+        (relay, sender)
+    }
+
+    fn ok(msg: RelayMessage) -> bool {
+        match msg {
+            RelayMessage::Ok(_, ok, _) => ok,
+            _ => panic!("unexpected message"),
+        }
+    }
 
-    /// 将收到的 event 持久化到数据库中
-    pub async fn persist_event(&mut self, e: &Event) {
-        if let Err(e) = self.db.save_event(&e).await {
-            error!("new event save faild: {}", e);
+    #[tokio::test]
+    async fn test_quota_allows_replacement() {
+        let (mut relay, _sender) = relay(2);
+        let key = PrivateKey::gen();
+
+        assert!(ok(publish(
+            &mut relay,
+            event(&key, EventKind::Metadata, "a", 1)
+        )
+        .await));
+        assert!(ok(publish(
+            &mut relay,
+            event(&key, EventKind::TextNote, "b", 2)
+        )
+        .await));
+        // 达到配额后不能再保存新的 event，但可以更新 kind 0
+        assert!(!ok(publish(
+            &mut relay,
+            event(&key, EventKind::TextNote, "c", 3)
+        )
+        .await));
+        assert!(ok(publish(
+            &mut relay,
+            event(&key, EventKind::Metadata, "d", 4)
+        )
+        .await));
+        let usage = relay.store.usage(&key.public_key()).await.unwrap();
+        assert_eq!(usage.events, 2);
+    }
+
+    #[tokio::test]
+    async fn test_quota_replacement_in_batch() {
+        let (mut relay, sender) = relay(2);
+        let key = PrivateKey::gen();
+
+        // 同一批中的两个 kind 0 只占一个位置，第三个 event 仍然在配额之内
+        let mut replies = vec![];
+        for e in [
+            event(&key, EventKind::Metadata, "b", 2),
+            event(&key, EventKind::TextNote, "c", 3),
+        ] {
+            let (tx, rx) = oneshot::channel();
+            sender
+                .send(SubscriberEvent::Event(Box::new(e), tx))
+                .await
+                .unwrap_or_else(|_| panic!("relay is not running"));
+            replies.push(rx);
+        }
+        assert!(ok(publish(
+            &mut relay,
+            event(&key, EventKind::Metadata, "a", 1)
+        )
+        .await));
+        for rx in replies {
+            assert!(ok(rx.await.unwrap()));
         }
+        let usage = relay.store.usage(&key.public_key()).await.unwrap();
+        assert_eq!(usage.events, 2);
     }
 }
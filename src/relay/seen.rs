@@ -0,0 +1,35 @@
+use super::cache::LruCache;
+use crate::nostr::Id;
+use std::sync::{Arc, Mutex};
+
+/// 最近见过的 event id
+///
+/// 在 Subscriber 和 Relay 之间共享，用于在校验签名、写库之前快速判断重复 event，
+/// 缓存未命中时再由 Relay 查询数据库
+#[derive(Clone)]
+pub(crate) struct SeenEvents(Arc<Mutex<LruCache<Id, ()>>>);
+
+impl SeenEvents {
+    pub fn new(capacity: usize) -> Self {
+        SeenEvents(Arc::new(Mutex::new(LruCache::new(capacity))))
+    }
+
+    pub fn contains(&self, id: &Id) -> bool {
+        self.0
+            .lock()
+            .expect("seen events lock poisoned")
+            .contains(id)
+    }
+
+    pub fn insert(&self, id: Id) {
+        self.0
+            .lock()
+            .expect("seen events lock poisoned")
+            .insert(id, ());
+    }
+
+    /// event 从数据库中删除后移出集合，之后重新提交时不再被当作重复
+    pub fn remove(&self, id: &Id) {
+        self.0.lock().expect("seen events lock poisoned").remove(id);
+    }
+}
@@ -1,86 +1,204 @@
-use super::{EventFilter, SubscriberEvent};
-use crate::nostr::{ClientMessage, Event, EventKind, Filter, PublicKey, RelayMessage, Tag};
+use super::{
+    Context, EventFilter, MessageType, Policy, RateLimiter, SeenEvents, SubscriberEvent, Verifier,
+};
+use crate::{
+    config::{Config, LimitsConfig},
+    metrics::METRICS,
+    nostr::{ClientMessage, Event, EventKind, Filter, PublicKey, RelayMessage, Tag},
+    server::ClientSocket,
+};
 use futures::{
     stream::{SplitSink, SplitStream},
     SinkExt, StreamExt,
 };
-use log::{error, info};
+use log::{error, info, warn};
 use std::{
     collections::HashMap,
-    net::SocketAddr,
-    time::{SystemTime, UNIX_EPOCH},
+    net::{IpAddr, SocketAddr},
+    sync::Arc,
+    time::{Duration, SystemTime, UNIX_EPOCH},
 };
+use tokio::time::{timeout, Instant, Interval};
 use tokio::{
-    net::TcpStream,
-    sync::broadcast::Receiver as BroadcastReceiver,
+    sync::broadcast::{error::RecvError, Receiver as BroadcastReceiver},
     sync::mpsc::Sender,
-    sync::{oneshot, oneshot::Receiver as OneshotReciver},
+    sync::{oneshot, oneshot::Receiver as OneshotReciver, watch},
 };
 use tokio_tungstenite::{
     self,
-    tungstenite::{Message, Result},
-    WebSocketStream,
+    tungstenite::{
+        protocol::{frame::coding::CloseCode, CloseFrame},
+        Error, Message, Result,
+    },
 };
+use url::Url;
+
+/// 等待下一次 tick，没有 ticker 时永远等待
+async fn next_tick(ticker: &mut Option<Interval>) {
+    match ticker {
+        Some(ticker) => {
+            ticker.tick().await;
+        }
+        None => std::future::pending().await,
+    }
+}
+
+/// relay 退出时关闭订阅和连接的原因
+const SHUTTING_DOWN: &str = "error: relay is shutting down";
+
+/// 退出时每次写入的超时时间，避免不读取数据的客户端拖住退出
+const SHUTDOWN_WRITE_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// 被限流时回复的原因
+const RATE_LIMITED: &str = "rate-limited: slow down";
 
 struct UserInfo {
-    #[allow(dead_code)]
     pubkey: PublicKey,
 }
 
 /// 一个消息的订阅者
 /// 发送、接收 Relay 的消息
-pub struct Subscriber {
+pub(crate) struct Subscriber {
     user_info: Option<UserInfo>,
     subscriptions: HashMap<String, Vec<Filter>>,
     socket_addr: SocketAddr,
-    writer: SplitSink<WebSocketStream<TcpStream>, Message>,
-    reader: SplitStream<WebSocketStream<TcpStream>>,
+    /// 按 IP 限流和封禁时使用的地址，来自 Unix socket 并且没有转发地址的连接为 None
+    client_ip: Option<IpAddr>,
+    writer: SplitSink<ClientSocket, Message>,
+    reader: SplitStream<ClientSocket>,
 
     sender: Sender<SubscriberEvent>,
     broadcast_receiver: BroadcastReceiver<Event>,
+    seen: SeenEvents,
+    verifier: Verifier,
+    policy: Arc<dyn Policy>,
+    rate_limiter: RateLimiter,
+    banned: bool,
+    shutdown: watch::Receiver<bool>,
+    /// 处理当前消息使用的配置，每条消息开始时从 config_receiver 更新
+    config: Arc<Config>,
+    config_receiver: watch::Receiver<Arc<Config>>,
 }
 
 impl Subscriber {
     pub fn new(
         socket_addr: SocketAddr,
-        socket_stream: WebSocketStream<TcpStream>,
-        sender: Sender<SubscriberEvent>,
-        broadcast_receiver: BroadcastReceiver<Event>,
+        client_ip: Option<IpAddr>,
+        socket_stream: ClientSocket,
+        ctx: &Context,
     ) -> Self {
         let (writer, reader) = socket_stream.split();
         Subscriber {
             user_info: None,
             subscriptions: HashMap::new(),
             socket_addr,
-            sender,
-            broadcast_receiver,
+            client_ip,
+            sender: ctx.sender.clone(),
+            broadcast_receiver: ctx.broadcast_sender.subscribe(),
+            seen: ctx.seen.clone(),
+            verifier: ctx.verifier.clone(),
+            policy: ctx.policy.clone(),
+            rate_limiter: ctx.rate_limiter.clone(),
+            banned: false,
+            shutdown: ctx.shutdown.clone(),
+            config: ctx.config(),
+            config_receiver: ctx.reloader.subscribe(),
             writer,
             reader,
         }
     }
     //todo: 考虑为 Subscriber 加入状态和身份，控制订阅权限
-    pub fn start(mut self) {
-        tokio::spawn(async move {
-            info!("New WebSocket connection: {}", &self.socket_addr);
-            if self.user_info.is_none() {
-                self.send_auth_event().await;
-            }
-            loop {
-                tokio::select! {
-                    r = self.reader.next() => {
-                        if let Some(Ok(msg)) = r {
-                            self.on_client_message(msg).await.expect("on client message error");
-                        } else {
-                            info!("Client disconnected: {}", &self.socket_addr);
+    /// 处理这个连接直到断开，需要在单独的任务中运行
+    pub async fn run(mut self) {
+        info!("New WebSocket connection: {}", &self.socket_addr);
+        if self.auth_required() {
+            self.send_auth_event().await;
+        }
+        // ping 和超时的参数在连接建立时确定，重新加载配置只影响新的连接
+        let network = &self.config.network;
+        let (pong_timeout, idle_timeout) = (
+            Duration::from_secs(network.pong_timeout),
+            Duration::from_secs(network.idle_timeout),
+        );
+        // ping_interval 为 0 时不发送 ping，也不检查 pong 超时；空闲超时单独计时，与 ping 无关
+        let mut ticker = (network.ping_interval > 0).then(|| {
+            let period = Duration::from_secs(network.ping_interval);
+            tokio::time::interval_at(Instant::now() + period, period)
+        });
+        // 最后一次收到客户端的 EVENT/REQ 等消息的时间，以及还没有得到回应的 ping
+        let mut last_message = Instant::now();
+        let mut ping_sent: Option<Instant> = None;
+        loop {
+            let idle = !idle_timeout.is_zero() && self.subscriptions.is_empty();
+            tokio::select! {
+                r = self.reader.next() => match r {
+                    Some(Ok(msg)) => {
+                        // 任何消息都说明连接还活着
+                        ping_sent = None;
+                        if msg.is_text() || msg.is_binary() {
+                            last_message = Instant::now();
+                        }
+                        self.on_client_message(msg).await.expect("on client message error");
+                        if self.banned {
+                            let _ = self.writer.close().await;
                             break;
                         }
-                    },
-                    Ok(evt) = self.broadcast_receiver.recv() => {
+                    }
+                    // 超过 limits.max_message_length 的消息，读取的位置已经错乱，只能断开
+                    Some(Err(Error::Capacity(e))) => {
+                        info!("message from {} is too large: {}", &self.socket_addr, e);
+                        let notice = RelayMessage::Notice(format!(
+                            "invalid: message is larger than {} bytes",
+                            self.config.limits.max_message_length
+                        ));
+                        self.send_relay_message(&notice).await;
+                        let _ = self.writer.close().await;
+                        break;
+                    }
+                    _ => {
+                        info!("Client disconnected: {}", &self.socket_addr);
+                        break;
+                    }
+                },
+                r = self.broadcast_receiver.recv() => match r {
+                    Ok(evt) => {
                         self.on_brodcast_message(&evt).await.expect("on brodcast message error");
                     }
+                    Err(RecvError::Lagged(n)) => {
+                        warn!("Client {} lagged behind, {} events dropped", &self.socket_addr, n);
+                        METRICS.broadcast_lagged.inc_by(n);
+                    }
+                    // Relay 已经结束，不会再有新的 event
+                    Err(RecvError::Closed) => {
+                        info!("broadcast closed, disconnect {}", &self.socket_addr);
+                        break;
+                    }
+                },
+                _ = self.shutdown.changed() => {
+                    self.close_for_shutdown().await;
+                    break;
+                }
+                _ = next_tick(&mut ticker) => {
+                    let timed_out = |t: Instant| !pong_timeout.is_zero() && t.elapsed() >= pong_timeout;
+                    if ping_sent.is_some_and(timed_out) {
+                        info!("Client {} did not answer ping, disconnect", &self.socket_addr);
+                        break;
+                    }
+                    if ping_sent.is_none() {
+                        ping_sent = Some(Instant::now());
+                    }
+                    if self.writer.send(Message::Ping(vec![])).await.is_err() {
+                        break;
+                    }
+                }
+                // 没有订阅时才检查空闲时间，收到消息后重新计时
+                _ = tokio::time::sleep_until(last_message + idle_timeout), if idle => {
+                    info!("Client {} is idle, disconnect", &self.socket_addr);
+                    let _ = self.writer.close().await;
+                    break;
                 }
             }
-        });
+        }
     }
 
     pub async fn on_brodcast_message(&mut self, evt: &Event) -> Result<()> {
@@ -93,14 +211,32 @@ impl Subscriber {
     }
 
     pub async fn on_client_message(&mut self, msg: Message) -> Result<()> {
+        self.config = self.config_receiver.borrow_and_update().clone();
         if let Message::Text(client_msg) = msg {
-            match serde_json::from_str::<ClientMessage>(&client_msg) {
+            let parsed = serde_json::from_str::<ClientMessage>(&client_msg);
+            let kind = match &parsed {
+                Ok(ClientMessage::Auth(_)) => "AUTH",
+                Ok(ClientMessage::Event(_)) => "EVENT",
+                Ok(ClientMessage::REQ(..)) => "REQ",
+                Ok(ClientMessage::Close(_)) => "CLOSE",
+                Ok(ClientMessage::Count(..)) => "COUNT",
+                Err(_) => "invalid",
+            };
+            METRICS.messages_received.with_label_values(&[kind]).inc();
+            match parsed {
                 Ok(client_msg) => {
                     match client_msg {
                         ClientMessage::Auth(e) => {
-                            if self.check_auth_event(&e) {
-                                let key_str = e.pubkey.as_hex_string();
-                                self.user_info = Some(UserInfo { pubkey: e.pubkey });
+                            if !self.allow(MessageType::Auth, None) {
+                                let ok = RelayMessage::Ok(e.id(), false, RATE_LIMITED.to_string());
+                                self.send_relay_message(&ok).await;
+                                return Ok(());
+                            }
+                            if self.check_auth_event(&e).await {
+                                let key_str = e.pubkey().as_hex_string();
+                                self.user_info = Some(UserInfo {
+                                    pubkey: e.pubkey().clone(),
+                                });
                                 let auth_info = RelayMessage::Notice(
                                     format!("Authentication success with pubkey: {}", key_str)
                                         .to_string(),
@@ -109,28 +245,99 @@ impl Subscriber {
                             }
                         }
                         ClientMessage::Event(e) => {
-                            if self.user_info.is_none() {
+                            if self.auth_required() {
                                 self.send_auth_event().await;
                                 return Ok(());
                             }
-                            if let Err(err) = e.verify() {
-                                error!("msg verify failed！{:?}, event: {:?}", err, e);
+                            if !self.allow(MessageType::Event, Some(e.kind())) {
+                                let ok = RelayMessage::Ok(e.id(), false, RATE_LIMITED.to_string());
+                                self.send_relay_message(&ok).await;
                                 return Ok(());
                             }
-                            // 持久化
-                            if let Err(e) = self.sender.send(SubscriberEvent::Event(e)).await {
-                                // 重发？
-                                error!("Subscriber send msg to relay faild : {}", e);
+                            if let Err(reason) = check_event_size(&self.config.limits, &e) {
+                                let ok = RelayMessage::Ok(e.id(), false, reason);
+                                self.send_relay_message(&ok).await;
+                                return Ok(());
+                            }
+                            // 已经见过的 event 不再校验签名
+                            if self.seen.contains(&e.id()) {
+                                let ok = RelayMessage::Ok(
+                                    e.id(),
+                                    true,
+                                    "duplicate: already have this event".to_string(),
+                                );
+                                self.send_relay_message(&ok).await;
+                                return Ok(());
+                            }
+                            let id = e.id();
+                            let e = match self.verifier.verify(e).await {
+                                Ok(e) => e,
+                                Err(err) => {
+                                    error!("msg verify failed！{:?}, event id: {:?}", err, id);
+                                    let ok =
+                                        RelayMessage::Ok(id, false, format!("invalid: {}", err));
+                                    self.send_relay_message(&ok).await;
+                                    return Ok(());
+                                }
                             };
+                            if let Err(reason) = self.policy.check_event(&e, self.auth_pubkey()) {
+                                let ok = RelayMessage::Ok(e.id(), false, reason);
+                                self.send_relay_message(&ok).await;
+                                return Ok(());
+                            }
+                            // 持久化
+                            let (tx, rx) = oneshot::channel();
+                            match self
+                                .sender
+                                .send(SubscriberEvent::Event(Box::new(e), tx))
+                                .await
+                            {
+                                Ok(_) => {
+                                    if let Ok(ok) = rx.await {
+                                        self.send_relay_message(&ok).await;
+                                    }
+                                }
+                                Err(e) => {
+                                    // 重发？
+                                    error!("Subscriber send msg to relay faild : {}", e);
+                                }
+                            }
                         }
                         // 订阅某个内容
                         // 需要向 Relay 一次性请求数据
-                        ClientMessage::REQ(id, filters) => {
-                            if self.user_info.is_none() {
+                        ClientMessage::REQ(id, mut filters) => {
+                            if self.auth_required() {
                                 self.send_auth_event().await;
                                 return Ok(());
                             }
-                            self.subscriptions.insert(id.clone(), filters.clone());
+                            if !self.allow(MessageType::Req, None) {
+                                let closed = RelayMessage::Closed(id, RATE_LIMITED.to_string());
+                                self.send_relay_message(&closed).await;
+                                return Ok(());
+                            }
+                            if let Err(reason) = check_req_size(
+                                &self.config.limits,
+                                &self.subscriptions,
+                                &id,
+                                &mut filters,
+                            ) {
+                                let closed = RelayMessage::Closed(id, reason);
+                                self.send_relay_message(&closed).await;
+                                return Ok(());
+                            }
+                            if let Err(reason) =
+                                self.policy.check_req(&id, &filters, self.auth_pubkey())
+                            {
+                                self.send_relay_message(&RelayMessage::Notice(reason)).await;
+                                return Ok(());
+                            }
+                            if self
+                                .subscriptions
+                                .insert(id.clone(), filters.clone())
+                                .is_none()
+                            {
+                                METRICS.subscriptions.inc();
+                            }
                             let (tx, rx) = oneshot::channel();
                             match self
                                 .sender
@@ -149,11 +356,55 @@ impl Subscriber {
                         }
                         // 取消订阅
                         ClientMessage::Close(id) => {
-                            self.subscriptions.remove(&id);
+                            if self.subscriptions.remove(&id).is_some() {
+                                METRICS.subscriptions.dec();
+                            }
+                        }
+                        // 只返回数量，不建立订阅
+                        ClientMessage::Count(id, filters) => {
+                            if self.auth_required() {
+                                self.send_auth_event().await;
+                                return Ok(());
+                            }
+                            if !self.allow(MessageType::Count, None) {
+                                let closed = RelayMessage::Closed(id, RATE_LIMITED.to_string());
+                                self.send_relay_message(&closed).await;
+                                return Ok(());
+                            }
+                            if let Err(reason) = check_filters(&self.config.limits, &id, &filters) {
+                                let closed = RelayMessage::Closed(id, reason);
+                                self.send_relay_message(&closed).await;
+                                return Ok(());
+                            }
+                            if let Err(reason) =
+                                self.policy.check_req(&id, &filters, self.auth_pubkey())
+                            {
+                                self.send_relay_message(&RelayMessage::Notice(reason)).await;
+                                return Ok(());
+                            }
+                            let (tx, rx) = oneshot::channel();
+                            match self
+                                .sender
+                                .send(SubscriberEvent::Count(id, filters, tx))
+                                .await
+                            {
+                                Ok(_) => {
+                                    if let Ok(msg) = rx.await {
+                                        self.send_relay_message(&msg).await;
+                                    }
+                                }
+                                Err(e) => {
+                                    error!("send msg to relay faild: {}", e);
+                                }
+                            }
                         }
                     }
                 }
-                Err(e) => error!("wrong client message format: {}", e),
+                Err(e) => {
+                    error!("wrong client message format: {}", e);
+                    let notice = RelayMessage::Notice(format!("error: invalid message: {}", e));
+                    self.send_relay_message(&notice).await;
+                }
             }
         }
         Ok(())
@@ -168,23 +419,23 @@ impl Subscriber {
         Ok(())
     }
 
-    pub fn check_auth_event(&self, e: &Event) -> bool {
+    pub async fn check_auth_event(&self, e: &Event) -> bool {
         // To verify AUTH messages, relays must ensure:
         // that the kind is 22242;
         // that the event created_at is close (e.g. within ~10 minutes) of the current time;
         // that the "challenge" tag matches the challenge sent before;
         // that the "relay" tag matches the relay URL:
         // URL normalization techniques can be applied. For most cases just checking if the domain name is correct should be enough.
-        if let Err(err) = e.verify() {
+        if let Err(err) = self.verifier.verify(e.clone()).await {
             error!("auth msg verify failed！{:?}, event: {:?}", err, e);
             return false;
         }
-        if e.kind != EventKind::Auth {
+        if e.kind() != EventKind::Auth {
             return false;
         }
 
         let (mut relay, mut challenge) = ("", "");
-        for tag in &e.tags {
+        for tag in e.tags() {
             match tag {
                 Tag::Relay(r) => {
                     relay = r;
@@ -198,29 +449,97 @@ impl Subscriber {
         let now = SystemTime::now()
             .duration_since(UNIX_EPOCH)
             .expect("get now time faild!")
-            .as_secs();
-        let duration = now - (e.created_at.0) as u64;
-        let ten_minutes = 10 * 60 as u64;
+            .as_secs() as i64;
+        let duration = now.abs_diff(e.created_at().0);
+        let auth = &self.config.auth;
 
-        if ten_minutes - duration > 0 && challenge == "ksana.io" && relay == "wss://relay.ksana.net"
+        duration <= auth.max_age
+            && challenge == auth.challenge
+            && Self::same_relay(relay, &self.config.network.relay_url)
+    }
+
+    /// 按 NIP-42 的建议，只比较 relay 地址的域名
+    fn same_relay(a: &str, b: &str) -> bool {
+        match (Url::parse(a), Url::parse(b)) {
+            (Ok(a), Ok(b)) => a.host_str() == b.host_str() && a.port() == b.port(),
+            _ => false,
+        }
+    }
+
+    /// relay 退出前关闭所有订阅，并告诉客户端关闭的原因
+    ///
+    /// 每次写入最多等待 SHUTDOWN_WRITE_TIMEOUT，超时后放弃剩下的消息
+    async fn close_for_shutdown(&mut self) {
+        let ids: Vec<String> = self.subscriptions.drain().map(|(id, _)| id).collect();
+        METRICS.subscriptions.sub(ids.len() as i64);
+        for id in ids {
+            let closed = RelayMessage::Closed(id, SHUTTING_DOWN.to_string());
+            if timeout(SHUTDOWN_WRITE_TIMEOUT, self.send_relay_message(&closed))
+                .await
+                .is_err()
+            {
+                warn!(
+                    "Client {} is not reading, close without CLOSED",
+                    &self.socket_addr
+                );
+                return;
+            }
+        }
+        let frame = CloseFrame {
+            code: CloseCode::Away,
+            reason: SHUTTING_DOWN.into(),
+        };
+        let _ = timeout(
+            SHUTDOWN_WRITE_TIMEOUT,
+            self.writer.send(Message::Close(Some(frame))),
+        )
+        .await;
+        let _ = timeout(SHUTDOWN_WRITE_TIMEOUT, self.writer.close()).await;
+    }
+
+    /// 按限流配置检查一条消息，被拒绝时给 IP 记一次 strike，次数过多时封禁 IP
+    fn allow(&mut self, msg: MessageType, kind: Option<EventKind>) -> bool {
+        let limits = &self.config.rate_limit;
+        if self
+            .rate_limiter
+            .check(limits, self.client_ip, self.auth_pubkey(), msg, kind)
         {
-            true
-        } else {
-            false
+            return true;
+        }
+        if let Some(ip) = self.client_ip {
+            if self.rate_limiter.strike(limits, ip) {
+                info!(
+                    "ban {} for {} seconds after {} rate limited messages",
+                    ip, limits.ban_secs, limits.max_strikes
+                );
+                self.banned = true;
+            }
         }
+        false
+    }
+
+    /// 当前连接认证过的公钥
+    fn auth_pubkey(&self) -> Option<&PublicKey> {
+        self.user_info.as_ref().map(|u| &u.pubkey)
+    }
+
+    /// 配置要求认证且当前连接尚未认证
+    fn auth_required(&self) -> bool {
+        self.config.auth.required && self.user_info.is_none()
     }
     pub async fn send_auth_event(&mut self) {
         if self.user_info.is_none() {
             let notice = &RelayMessage::Notice("restricted: we can't serve DMs to unauthenticated users, does your client implement NIP-42?".to_string());
             self.send_relay_message(notice).await;
-            self.send_relay_message(&RelayMessage::Auth("ksana.io".to_string()))
+            let challenge = self.config.auth.challenge.clone();
+            self.send_relay_message(&RelayMessage::Auth(challenge))
                 .await;
         }
     }
     pub fn is_subscribed(&self, event: &Event) -> Option<Vec<RelayMessage>> {
         let mut rmsgs: Vec<RelayMessage> = vec![];
         for (id, filters) in &self.subscriptions {
-            if EventFilter::any_filter(&event, filters) {
+            if EventFilter::any_filter(event, filters) {
                 rmsgs.push(RelayMessage::Event(id.to_owned(), event.clone()));
             }
         }
@@ -231,7 +550,19 @@ impl Subscriber {
         }
     }
     pub async fn send_relay_message(&mut self, relay_message: &RelayMessage) {
-        let msg_str = serde_json::to_string(&relay_message).expect("msg serde faild!");
+        let kind = match relay_message {
+            RelayMessage::Auth(_) => "AUTH",
+            RelayMessage::Event(..) => "EVENT",
+            RelayMessage::Notice(_) => "NOTICE",
+            RelayMessage::Ok(_, accepted, reason) => {
+                METRICS.count_ok(*accepted, reason);
+                "OK"
+            }
+            RelayMessage::Closed(..) => "CLOSED",
+            RelayMessage::Count(..) => "COUNT",
+        };
+        METRICS.messages_sent.with_label_values(&[kind]).inc();
+        let msg_str = relay_message.to_json().expect("msg serde faild!");
         if let Err(e) = self.writer.send(Message::Text(msg_str)).await {
             if let tokio_tungstenite::tungstenite::Error::ConnectionClosed = e {
                 error!("send relay msg error: {}", e)
@@ -239,3 +570,169 @@ impl Subscriber {
         }
     }
 }
+
+impl Drop for Subscriber {
+    fn drop(&mut self) {
+        METRICS.subscriptions.sub(self.subscriptions.len() as i64);
+    }
+}
+
+/// 检查 event 的 tag 数和 content 长度
+fn check_event_size(limits: &LimitsConfig, e: &Event) -> std::result::Result<(), String> {
+    if limits.max_event_tags > 0 && e.tags().len() > limits.max_event_tags {
+        return Err(format!("invalid: more than {} tags", limits.max_event_tags));
+    }
+    if limits.max_content_length > 0 && e.content().chars().count() > limits.max_content_length {
+        return Err(format!(
+            "invalid: content is longer than {} characters",
+            limits.max_content_length
+        ));
+    }
+    Ok(())
+}
+
+/// 检查订阅 id 的长度、filter 数和 subscriptions 中已有的订阅数，并把过大的 limit 截断为 max_limit
+fn check_req_size(
+    limits: &LimitsConfig,
+    subscriptions: &HashMap<String, Vec<Filter>>,
+    id: &str,
+    filters: &mut [Filter],
+) -> std::result::Result<(), String> {
+    check_filters(limits, id, filters)?;
+    // 替换已有的订阅不增加订阅数
+    if limits.max_subscriptions > 0
+        && !subscriptions.contains_key(id)
+        && subscriptions.len() >= limits.max_subscriptions
+    {
+        return Err(format!(
+            "blocked: more than {} subscriptions",
+            limits.max_subscriptions
+        ));
+    }
+    if limits.max_limit > 0 {
+        for filter in filters.iter_mut() {
+            filter.limit = Some(
+                filter
+                    .limit
+                    .map_or(limits.max_limit, |l| l.min(limits.max_limit)),
+            );
+        }
+    }
+    Ok(())
+}
+
+/// 检查 REQ 和 COUNT 共同的 subscription id 和 filter 数
+fn check_filters(
+    limits: &LimitsConfig,
+    id: &str,
+    filters: &[Filter],
+) -> std::result::Result<(), String> {
+    if id.is_empty() {
+        return Err("invalid: subscription id is empty".to_string());
+    }
+    if limits.max_subid_length > 0 && id.chars().count() > limits.max_subid_length {
+        return Err(format!(
+            "invalid: subscription id is longer than {} characters",
+            limits.max_subid_length
+        ));
+    }
+    if limits.max_filters > 0 && filters.len() > limits.max_filters {
+        return Err(format!("invalid: more than {} filters", limits.max_filters));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::nostr::{PreEvent, PrivateKey};
+
+    fn limits() -> LimitsConfig {
+        LimitsConfig {
+            max_subscriptions: 1,
+            max_filters: 2,
+            max_limit: 100,
+            max_subid_length: 4,
+            max_event_tags: 1,
+            max_content_length: 3,
+            ..Default::default()
+        }
+    }
+
+    fn event(tags: usize, content: &str) -> Event {
+        let key = PrivateKey::gen();
+        let tags = (0..tags)
+            .map(|i| Tag::Other {
+                tag: "t".to_string(),
+                data: vec![i.to_string()],
+            })
+            .collect();
+        let pre = PreEvent::new(key.public_key(), EventKind::TextNote, tags, content.into());
+        Event::new(pre, &key).unwrap()
+    }
+
+    #[test]
+    fn test_check_event_size() {
+        let limits = limits();
+        assert!(check_event_size(&limits, &event(1, "一二三")).is_ok());
+        assert_eq!(
+            check_event_size(&limits, &event(2, "")),
+            Err("invalid: more than 1 tags".to_string())
+        );
+        assert_eq!(
+            check_event_size(&limits, &event(0, "abcd")),
+            Err("invalid: content is longer than 3 characters".to_string())
+        );
+        // 0 表示不限制
+        assert!(check_event_size(&LimitsConfig::default(), &event(2, "abcd")).is_ok());
+    }
+
+    #[test]
+    fn test_check_req_size() {
+        let limits = limits();
+        let mut subscriptions = HashMap::new();
+        let check = |subscriptions: &HashMap<String, Vec<Filter>>, id: &str, n: usize| {
+            check_req_size(&limits, subscriptions, id, &mut vec![Filter::default(); n])
+        };
+        assert_eq!(
+            check(&subscriptions, "", 1),
+            Err("invalid: subscription id is empty".to_string())
+        );
+        assert_eq!(
+            check(&subscriptions, "abcde", 1),
+            Err("invalid: subscription id is longer than 4 characters".to_string())
+        );
+        assert_eq!(
+            check(&subscriptions, "sub", 3),
+            Err("invalid: more than 2 filters".to_string())
+        );
+
+        // 达到订阅数上限后不能新建订阅，但可以替换已有的订阅
+        subscriptions.insert("sub".to_string(), vec![]);
+        assert_eq!(
+            check(&subscriptions, "new", 1),
+            Err("blocked: more than 1 subscriptions".to_string())
+        );
+        assert!(check(&subscriptions, "sub", 2).is_ok());
+
+        // 超过 max_limit 和没有 limit 的 filter 都截断为 max_limit
+        let mut filters = vec![
+            Filter {
+                limit: Some(10),
+                ..Default::default()
+            },
+            Filter {
+                limit: Some(1000),
+                ..Default::default()
+            },
+            Filter::default(),
+        ];
+        let limits = LimitsConfig {
+            max_filters: 0,
+            ..limits
+        };
+        check_req_size(&limits, &HashMap::new(), "sub", &mut filters).unwrap();
+        let clamped: Vec<Option<usize>> = filters.iter().map(|f| f.limit).collect();
+        assert_eq!(clamped, [Some(10), Some(100), Some(100)]);
+    }
+}
@@ -0,0 +1,93 @@
+use super::cache::LruCache;
+use crate::nostr::{Error, Event, Id, PublicKey};
+use k256::schnorr::VerifyingKey;
+use std::{
+    sync::{Arc, Mutex},
+    thread,
+};
+use tokio::sync::Semaphore;
+
+const KEY_CACHE_CAPACITY: usize = 10_000;
+const VERIFIED_CACHE_CAPACITY: usize = 50_000;
+
+/// 签名校验池
+///
+/// schnorr 校验比较耗 CPU，放在 blocking 线程中执行，同时运行的校验数量不超过 CPU 核数，
+/// 避免大量 event 涌入时占满 tokio 的 worker 线程、拖慢 socket 读写。
+/// 解析好的公钥和最近校验通过的 event 都会被缓存。
+#[derive(Clone)]
+pub struct Verifier {
+    inner: Arc<Inner>,
+}
+
+struct Inner {
+    permits: Semaphore,
+    keys: Mutex<LruCache<PublicKey, VerifyingKey>>,
+    // 以 (id, sig) 为 key：id 由内容 hash 而来，仍会重新计算，签名不同的同 id event 不会命中
+    verified: Mutex<LruCache<(Id, [u8; 64]), ()>>,
+}
+
+impl Default for Verifier {
+    fn default() -> Self {
+        Verifier::with_workers(0)
+    }
+}
+
+impl Verifier {
+    /// workers 为 0 时使用 CPU 核数
+    pub fn with_workers(workers: usize) -> Self {
+        let workers = match workers {
+            0 => thread::available_parallelism().map_or(1, |n| n.get()),
+            n => n,
+        };
+        Verifier::new(workers, KEY_CACHE_CAPACITY, VERIFIED_CACHE_CAPACITY)
+    }
+
+    pub fn new(workers: usize, key_cache: usize, verified_cache: usize) -> Self {
+        Verifier {
+            inner: Arc::new(Inner {
+                permits: Semaphore::new(workers.max(1)),
+                keys: Mutex::new(LruCache::new(key_cache)),
+                verified: Mutex::new(LruCache::new(verified_cache)),
+            }),
+        }
+    }
+
+    /// 校验 event 的 id 和签名，校验通过时返回原 event
+    pub async fn verify(&self, event: Event) -> Result<Event, Error> {
+        let _permit = self
+            .inner
+            .permits
+            .acquire()
+            .await
+            .expect("verifier semaphore closed");
+        let inner = self.inner.clone();
+        tokio::task::spawn_blocking(move || inner.verify(&event).map(|_| event))
+            .await
+            .map_err(|_| Error::VerifierError)?
+    }
+}
+
+impl Inner {
+    fn verify(&self, event: &Event) -> Result<(), Error> {
+        let key = (event.id(), event.sig().to_bytes());
+        if self.verified.lock().expect("lock poisoned").contains(&key) {
+            return event.verify_id();
+        }
+        event.verify_with(&self.verifying_key(event.pubkey())?)?;
+        self.verified.lock().expect("lock poisoned").insert(key, ());
+        Ok(())
+    }
+
+    fn verifying_key(&self, pubkey: &PublicKey) -> Result<VerifyingKey, Error> {
+        if let Some(key) = self.keys.lock().expect("lock poisoned").get(pubkey) {
+            return Ok(*key);
+        }
+        let key = pubkey.verifying_key()?;
+        self.keys
+            .lock()
+            .expect("lock poisoned")
+            .insert(pubkey.clone(), key);
+        Ok(key)
+    }
+}
@@ -0,0 +1,134 @@
+use crate::{
+    config::{self, Config},
+    relay::Policy,
+};
+use log::{info, warn};
+use serde::Serialize;
+use std::sync::Arc;
+use tokio::sync::watch;
+
+/// 重新读取配置的函数，例如重新解析配置文件并应用命令行参数
+pub type ConfigLoader = Arc<dyn Fn() -> Result<Config, config::Error> + Send + Sync>;
+
+/// 一次重新加载的结果
+#[derive(Debug, Clone, Serialize)]
+pub struct ReloadReport {
+    /// 改动了但是需要重启才能生效的配置项
+    pub restart_required: Vec<&'static str>,
+    /// 改动了但是只对之后的新连接生效的配置项，已有的连接继续使用原来的值
+    pub new_connections_only: Vec<&'static str>,
+}
+
+/// 运行中的配置，重新加载时整体替换
+///
+/// 每个连接在处理每条消息时取一次当前配置，同一条消息不会看到新旧混合的配置
+#[derive(Clone)]
+pub struct ConfigReloader {
+    sender: Arc<watch::Sender<Arc<Config>>>,
+    loader: Option<ConfigLoader>,
+    policy: Option<Arc<dyn Policy>>,
+}
+
+impl ConfigReloader {
+    /// 替换配置后会调用 policy 的 [`Policy::reload`]
+    pub(crate) fn new(
+        config: Config,
+        loader: Option<ConfigLoader>,
+        policy: Option<Arc<dyn Policy>>,
+    ) -> Self {
+        let (sender, _) = watch::channel(Arc::new(config));
+        ConfigReloader {
+            sender: Arc::new(sender),
+            loader,
+            policy,
+        }
+    }
+
+    /// 当前的配置
+    pub fn current(&self) -> Arc<Config> {
+        self.sender.borrow().clone()
+    }
+
+    /// 配置变化时得到通知
+    pub(crate) fn subscribe(&self) -> watch::Receiver<Arc<Config>> {
+        self.sender.subscribe()
+    }
+
+    /// 用 loader 重新读取配置，校验通过后替换当前配置
+    ///
+    /// 读取或校验失败时保留当前配置，返回错误
+    pub fn reload(&self) -> Result<ReloadReport, config::Error> {
+        let loader = self.loader.as_ref().ok_or_else(|| {
+            config::Error::Invalid("reload", "no config loader is set".to_string())
+        })?;
+        let config = loader()?;
+        config.validate()?;
+        Ok(self.replace(config))
+    }
+
+    /// 直接替换当前配置，调用方需要先校验
+    pub fn replace(&self, config: Config) -> ReloadReport {
+        let current = self.current();
+        let restart_required = current.restart_required(&config);
+        let new_connections_only = current.new_connections_only(&config);
+        let config = Arc::new(config);
+        self.sender.send_replace(config.clone());
+        if let Some(policy) = &self.policy {
+            policy.reload(&config);
+        }
+        info!("config reloaded");
+        for name in &restart_required {
+            warn!("{} changed, restart the relay to apply it", name);
+        }
+        for name in &new_connections_only {
+            info!("{} changed, it applies to new connections only", name);
+        }
+        ReloadReport {
+            restart_required,
+            new_connections_only,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Mutex;
+
+    /// 记录最后一次重新加载时的 description
+    #[derive(Default)]
+    struct Recorder(Mutex<Option<String>>);
+
+    impl Policy for Recorder {
+        fn reload(&self, config: &Config) {
+            *self.0.lock().unwrap() = config.info.description.clone();
+        }
+    }
+
+    #[test]
+    fn test_reload() {
+        let loader: ConfigLoader = Arc::new(|| {
+            let mut config = Config::default();
+            config.info.description = Some("reloaded".to_string());
+            config.network.listen = vec!["127.0.0.1:9003".to_string()];
+            config.network.idle_timeout += 1;
+            Ok(config)
+        });
+        let policy = Arc::new(Recorder::default());
+        let reloader = ConfigReloader::new(Config::default(), Some(loader), Some(policy.clone()));
+        let mut receiver = reloader.subscribe();
+        let report = reloader.reload().unwrap();
+        assert_eq!(report.restart_required, vec!["network.listen"]);
+        assert_eq!(report.new_connections_only, vec!["network.idle_timeout"]);
+        assert_eq!(policy.0.lock().unwrap().as_deref(), Some("reloaded"));
+        assert!(receiver.has_changed().unwrap());
+        assert_eq!(
+            receiver.borrow_and_update().info.description.as_deref(),
+            Some("reloaded")
+        );
+
+        // 没有 loader 时不能重新加载
+        let reloader = ConfigReloader::new(Config::default(), None, None);
+        assert!(reloader.reload().is_err());
+    }
+}
@@ -0,0 +1,142 @@
+use std::{
+    collections::HashMap,
+    net::IpAddr,
+    sync::{Arc, Mutex},
+};
+
+/// 当前的连接数，总数和每个 IP 的连接数
+#[derive(Clone, Default)]
+pub(crate) struct Connections(Arc<Mutex<Counts>>);
+
+#[derive(Default)]
+struct Counts {
+    total: usize,
+    per_ip: HashMap<IpAddr, usize>,
+    /// 不计入上面的连接数、只用来读取健康检查请求的连接
+    probes: usize,
+}
+
+/// 连接数达到上限或者地址被封禁时，留给健康检查和指标请求的连接数
+const MAX_PROBES: usize = 8;
+
+/// 为什么拒绝一个连接
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) enum Rejected {
+    TooManyConnections,
+    TooManyConnectionsFromIp,
+}
+
+impl Connections {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// 登记一个新连接，超过限制时拒绝；返回的 guard 被 drop 时注销这个连接
+    ///
+    /// max_total 和 max_per_ip 为 0 时不限制
+    pub fn acquire(
+        &self,
+        ip: IpAddr,
+        max_total: usize,
+        max_per_ip: usize,
+    ) -> Result<ConnectionGuard, Rejected> {
+        let mut counts = self.0.lock().expect("connections lock poisoned");
+        if max_total > 0 && counts.total >= max_total {
+            return Err(Rejected::TooManyConnections);
+        }
+        let count = counts.per_ip.entry(ip).or_insert(0);
+        if max_per_ip > 0 && *count >= max_per_ip {
+            return Err(Rejected::TooManyConnectionsFromIp);
+        }
+        *count += 1;
+        counts.total += 1;
+        Ok(ConnectionGuard {
+            connections: self.clone(),
+            ip,
+        })
+    }
+
+    /// 在健康检查的名额内登记一个连接，名额用完时返回 None
+    pub fn acquire_probe(&self) -> Option<ProbeGuard> {
+        let mut counts = self.0.lock().expect("connections lock poisoned");
+        if counts.probes >= MAX_PROBES {
+            return None;
+        }
+        counts.probes += 1;
+        Some(ProbeGuard(self.clone()))
+    }
+
+    /// 当前的连接总数
+    pub fn total(&self) -> usize {
+        self.0.lock().expect("connections lock poisoned").total
+    }
+}
+
+pub(crate) struct ConnectionGuard {
+    connections: Connections,
+    ip: IpAddr,
+}
+
+impl Drop for ConnectionGuard {
+    fn drop(&mut self) {
+        let mut counts = self
+            .connections
+            .0
+            .lock()
+            .expect("connections lock poisoned");
+        counts.total -= 1;
+        if let Some(count) = counts.per_ip.get_mut(&self.ip) {
+            *count -= 1;
+            if *count == 0 {
+                counts.per_ip.remove(&self.ip);
+            }
+        }
+    }
+}
+
+pub(crate) struct ProbeGuard(Connections);
+
+impl Drop for ProbeGuard {
+    fn drop(&mut self) {
+        self.0 .0.lock().expect("connections lock poisoned").probes -= 1;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_connection_limits() {
+        let connections = Connections::new();
+        let a: IpAddr = "10.0.0.1".parse().unwrap();
+        let b: IpAddr = "10.0.0.2".parse().unwrap();
+        let first = connections.acquire(a, 3, 2).unwrap();
+        let _second = connections.acquire(a, 3, 2).unwrap();
+        assert_eq!(
+            connections.acquire(a, 3, 2).err(),
+            Some(Rejected::TooManyConnectionsFromIp)
+        );
+        let _third = connections.acquire(b, 3, 2).unwrap();
+        assert_eq!(
+            connections.acquire(b, 3, 2).err(),
+            Some(Rejected::TooManyConnections)
+        );
+        // 断开后名额释放
+        drop(first);
+        assert_eq!(connections.total(), 2);
+        assert!(connections.acquire(a, 3, 2).is_ok());
+    }
+
+    #[test]
+    fn test_probe_limit() {
+        let connections = Connections::new();
+        let probes: Vec<_> = (0..MAX_PROBES)
+            .map(|_| connections.acquire_probe().unwrap())
+            .collect();
+        assert!(connections.acquire_probe().is_none());
+        assert_eq!(connections.total(), 0);
+        drop(probes);
+        assert!(connections.acquire_probe().is_some());
+    }
+}
@@ -0,0 +1,130 @@
+use super::http::Response;
+use crate::relay::{Context, SubscriberEvent};
+use serde::Serialize;
+use std::{collections::BTreeMap, time::Duration};
+use tokio::{sync::oneshot, time::timeout};
+
+/// 每项检查的超时，Relay 或数据库忙到超过这个时间就认为没有就绪
+const CHECK_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// 就绪检查的结果，每项检查通过时为 "ok"，否则为失败原因
+#[derive(Debug, Serialize)]
+struct ReadyReport {
+    ready: bool,
+    checks: BTreeMap<&'static str, String>,
+}
+
+/// `/readyz`：没有在退出、Relay 任务在处理消息、数据库可用并且迁移已经应用
+pub(super) async fn ready_response(ctx: &Context) -> Response {
+    let mut checks = BTreeMap::new();
+    let shutdown = if *ctx.shutdown.borrow() {
+        Err("shutting down".to_string())
+    } else {
+        Ok(())
+    };
+    checks.insert("shutdown", shutdown);
+    checks.insert("relay", check_relay(ctx).await);
+    if let Some(db) = &ctx.database {
+        let database = match timeout(CHECK_TIMEOUT, db.check_ready()).await {
+            Ok(r) => r.map_err(|e| e.to_string()),
+            Err(_) => Err("timed out".to_string()),
+        };
+        checks.insert("database", database);
+    }
+
+    let report = ReadyReport {
+        ready: checks.values().all(|r| r.is_ok()),
+        checks: checks
+            .into_iter()
+            .map(|(name, r)| (name, r.err().unwrap_or_else(|| "ok".to_string())))
+            .collect(),
+    };
+    let status = if report.ready {
+        "200 OK"
+    } else {
+        "503 Service Unavailable"
+    };
+    let body = serde_json::to_string(&report).expect("serde ready report faild!");
+    Response::new(status).body("application/json", body)
+}
+
+/// 经过消息队列给 Relay 任务发一个 Ping，队列满或者任务退出都会失败
+async fn check_relay(ctx: &Context) -> Result<(), String> {
+    let (sx, rx) = oneshot::channel();
+    let ping = async {
+        ctx.sender
+            .send(SubscriberEvent::Ping(sx))
+            .await
+            .map_err(|_| "relay task is not running".to_string())?;
+        rx.await
+            .map_err(|_| "relay task is not running".to_string())
+    };
+    timeout(CHECK_TIMEOUT, ping)
+        .await
+        .unwrap_or_else(|_| Err("timed out".to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        config::Config,
+        relay::{AllowAll, RateLimiter, SeenEvents, Verifier},
+        reload::ConfigReloader,
+        server::Connections,
+    };
+    use std::sync::Arc;
+    use tokio::sync::{broadcast, mpsc, watch};
+
+    fn context(sender: mpsc::Sender<SubscriberEvent>, shutdown: watch::Receiver<bool>) -> Context {
+        Context {
+            sender,
+            broadcast_sender: broadcast::channel(1).0,
+            seen: SeenEvents::new(16),
+            verifier: Verifier::with_workers(1),
+            policy: Arc::new(AllowAll),
+            rate_limiter: RateLimiter::new(),
+            connections: Connections::new(),
+            shutdown,
+            database: None,
+            reloader: ConfigReloader::new(Config::default(), None, None),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_check_relay() {
+        let (_shutdown, shutdown_receiver) = watch::channel(false);
+        let (sender, mut receiver) = mpsc::channel(1);
+        tokio::spawn(async move {
+            while let Some(event) = receiver.recv().await {
+                if let SubscriberEvent::Ping(sx) = event {
+                    let _ = sx.send(());
+                }
+            }
+        });
+        let ctx = context(sender, shutdown_receiver.clone());
+        assert_eq!(check_relay(&ctx).await, Ok(()));
+
+        // Relay 任务退出后队列关闭
+        let (sender, receiver) = mpsc::channel(1);
+        drop(receiver);
+        let ctx = context(sender, shutdown_receiver);
+        assert_eq!(
+            check_relay(&ctx).await,
+            Err("relay task is not running".to_string())
+        );
+        let mut response = vec![];
+        ready_response(&ctx)
+            .await
+            .write_to(&mut response)
+            .await
+            .unwrap();
+        let response = String::from_utf8(response).unwrap();
+        assert!(response.starts_with("HTTP/1.1 503"), "{}", response);
+        assert!(
+            response.contains(r#""relay":"relay task is not running""#),
+            "{}",
+            response
+        );
+    }
+}
@@ -0,0 +1,143 @@
+use std::io;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+/// 请求头的最大长度
+const MAX_HEAD_SIZE: usize = 8 * 1024;
+const MAX_HEADERS: usize = 64;
+
+/// 解析后的 HTTP 请求头
+#[derive(Debug)]
+pub struct Request {
+    pub method: String,
+    pub path: String,
+    pub headers: Vec<(String, String)>,
+}
+
+impl Request {
+    /// 按名称（不区分大小写）取第一个同名 header
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(k, _)| k.eq_ignore_ascii_case(name))
+            .map(|(_, v)| v.as_str())
+    }
+
+    pub fn is_websocket_upgrade(&self) -> bool {
+        self.header("upgrade")
+            .is_some_and(|v| v.eq_ignore_ascii_case("websocket"))
+    }
+
+    /// Accept 中是否包含指定的 MIME 类型
+    pub fn accepts(&self, mime: &str) -> bool {
+        self.header("accept").is_some_and(|v| {
+            v.split(',')
+                .any(|m| m.split(';').next().unwrap_or("").trim() == mime)
+        })
+    }
+}
+
+/// 从流中读出完整的请求头
+///
+/// 返回解析后的请求和已经读出的全部字节，后者需要通过 `Rewind` 交还给 websocket 握手
+pub async fn read_request<S: AsyncRead + Unpin>(stream: &mut S) -> io::Result<(Request, Vec<u8>)> {
+    let mut buf = Vec::with_capacity(1024);
+    let mut chunk = [0u8; 1024];
+    loop {
+        let n = stream.read(&mut chunk).await?;
+        if n == 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "connection closed before request head",
+            ));
+        }
+        buf.extend_from_slice(&chunk[..n]);
+
+        let mut headers = [httparse::EMPTY_HEADER; MAX_HEADERS];
+        let mut req = httparse::Request::new(&mut headers);
+        match req.parse(&buf) {
+            Ok(httparse::Status::Complete(_)) => {
+                let request = Request {
+                    method: req.method.unwrap_or_default().to_string(),
+                    path: req.path.unwrap_or_default().to_string(),
+                    headers: req
+                        .headers
+                        .iter()
+                        .map(|h| {
+                            (
+                                h.name.to_string(),
+                                String::from_utf8_lossy(h.value).into_owned(),
+                            )
+                        })
+                        .collect(),
+                };
+                return Ok((request, buf));
+            }
+            Ok(httparse::Status::Partial) if buf.len() < MAX_HEAD_SIZE => continue,
+            Ok(httparse::Status::Partial) => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "request head too large",
+                ))
+            }
+            Err(e) => return Err(io::Error::new(io::ErrorKind::InvalidData, e)),
+        }
+    }
+}
+
+/// 一个简单的 HTTP 响应，写完后关闭连接
+pub struct Response {
+    status: &'static str,
+    headers: Vec<(&'static str, String)>,
+    body: String,
+}
+
+impl Response {
+    pub fn new(status: &'static str) -> Self {
+        Response {
+            status,
+            headers: vec![],
+            body: String::new(),
+        }
+    }
+
+    pub fn header(mut self, name: &'static str, value: impl Into<String>) -> Self {
+        self.headers.push((name, value.into()));
+        self
+    }
+
+    pub fn body(mut self, content_type: &'static str, body: impl Into<String>) -> Self {
+        self.body = body.into();
+        self.header("Content-Type", content_type)
+    }
+
+    pub async fn write_to<S: AsyncWrite + Unpin>(&self, stream: &mut S) -> io::Result<()> {
+        let mut out = format!("HTTP/1.1 {}\r\n", self.status);
+        for (name, value) in &self.headers {
+            out.push_str(&format!("{}: {}\r\n", name, value));
+        }
+        out.push_str(&format!(
+            "Content-Length: {}\r\nConnection: close\r\n\r\n",
+            self.body.len()
+        ));
+        out.push_str(&self.body);
+        stream.write_all(out.as_bytes()).await?;
+        stream.shutdown().await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_read_request() {
+        let raw = b"GET / HTTP/1.1\r\nHost: relay.ksana.net\r\nAccept: text/html, application/nostr+json\r\n\r\n";
+        let mut stream = &raw[..];
+        let (req, buf) = read_request(&mut stream).await.unwrap();
+        assert_eq!(req.method, "GET");
+        assert_eq!(req.header("HOST"), Some("relay.ksana.net"));
+        assert!(req.accepts("application/nostr+json"));
+        assert!(!req.is_websocket_upgrade());
+        assert_eq!(buf, raw);
+    }
+}
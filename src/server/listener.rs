@@ -0,0 +1,214 @@
+use super::Io;
+use crate::{config::ListenAddr, RelayError};
+use std::{fmt, io, net::SocketAddr, str::FromStr};
+use tokio::net::TcpListener;
+#[cfg(unix)]
+use tokio::net::UnixListener;
+
+/// 一个监听中的 socket：TCP（IPv4 或 IPv6）或者 Unix domain socket
+#[derive(Debug)]
+pub enum Listener {
+    Tcp(TcpListener),
+    #[cfg(unix)]
+    Unix(UnixListener),
+}
+
+impl From<TcpListener> for Listener {
+    fn from(listener: TcpListener) -> Self {
+        Listener::Tcp(listener)
+    }
+}
+
+#[cfg(unix)]
+impl From<UnixListener> for Listener {
+    fn from(listener: UnixListener) -> Self {
+        Listener::Unix(listener)
+    }
+}
+
+impl fmt::Display for Listener {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Listener::Tcp(l) => match l.local_addr() {
+                Ok(addr) => write!(f, "{}", addr),
+                Err(_) => write!(f, "tcp"),
+            },
+            #[cfg(unix)]
+            Listener::Unix(l) => {
+                let addr = l.local_addr().ok();
+                match addr.as_ref().and_then(|a| a.as_pathname()) {
+                    Some(path) => write!(f, "unix:{}", path.display()),
+                    None => write!(f, "unix"),
+                }
+            }
+        }
+    }
+}
+
+impl Listener {
+    /// TCP listener 的本地地址
+    pub fn local_addr(&self) -> Option<SocketAddr> {
+        match self {
+            Listener::Tcp(l) => l.local_addr().ok(),
+            #[cfg(unix)]
+            Listener::Unix(_) => None,
+        }
+    }
+
+    /// 接受一个连接，Unix socket 的对端没有 IP 地址，返回 None
+    pub(crate) async fn accept(&self) -> io::Result<(Box<dyn Io>, Option<SocketAddr>)> {
+        match self {
+            Listener::Tcp(l) => {
+                let (stream, peer) = l.accept().await?;
+                Ok((Box::new(stream), Some(peer)))
+            }
+            #[cfg(unix)]
+            Listener::Unix(l) => {
+                let (stream, _) = l.accept().await?;
+                Ok((Box::new(stream), None))
+            }
+        }
+    }
+}
+
+/// 按配置中的地址绑定 listener，systemd 传入的 socket 只能被取走一次
+pub(crate) async fn bind_all(addrs: &[String]) -> Result<Vec<Listener>, RelayError> {
+    let mut listeners = vec![];
+    for addr in addrs {
+        let listen_err = |e| RelayError::Listen(addr.clone(), e);
+        let parsed = ListenAddr::from_str(addr)
+            .map_err(|e| listen_err(io::Error::new(io::ErrorKind::InvalidInput, e)))?;
+        match parsed {
+            ListenAddr::Tcp(socket_addr) => {
+                let listener = TcpListener::bind(socket_addr).await.map_err(listen_err)?;
+                listeners.push(listener.into());
+            }
+            #[cfg(unix)]
+            ListenAddr::Unix(path) => {
+                // 上次运行留下的 socket 文件会导致绑定失败
+                if std::fs::metadata(&path)
+                    .is_ok_and(|m| std::os::unix::fs::FileTypeExt::is_socket(&m.file_type()))
+                {
+                    std::fs::remove_file(&path).map_err(listen_err)?;
+                }
+                listeners.push(UnixListener::bind(&path).map_err(listen_err)?.into());
+            }
+            #[cfg(unix)]
+            ListenAddr::Systemd(name) => {
+                let inherited = systemd::take(name.as_deref()).map_err(listen_err)?;
+                if inherited.is_empty() {
+                    return Err(listen_err(io::Error::new(
+                        io::ErrorKind::NotFound,
+                        "no socket is passed by systemd",
+                    )));
+                }
+                listeners.extend(inherited);
+            }
+            #[cfg(not(unix))]
+            _ => {
+                return Err(listen_err(io::Error::new(
+                    io::ErrorKind::Unsupported,
+                    "only tcp listeners are supported on this platform",
+                )))
+            }
+        }
+    }
+    Ok(listeners)
+}
+
+/// systemd socket activation：LISTEN_PID、LISTEN_FDS 和 LISTEN_FDNAMES 环境变量，
+/// 传入的 socket 从 3 号文件描述符开始
+#[cfg(unix)]
+mod systemd {
+    use super::Listener;
+    use std::{
+        io,
+        os::unix::io::{FromRawFd, IntoRawFd, RawFd},
+        sync::Mutex,
+    };
+
+    const LISTEN_FDS_START: RawFd = 3;
+
+    /// 还没有被取走的 socket 和它们的名称，None 表示还没有读取环境变量
+    static INHERITED: Mutex<Option<Vec<(String, RawFd)>>> = Mutex::new(None);
+
+    fn read_env() -> Vec<(String, RawFd)> {
+        let pid = std::env::var("LISTEN_PID")
+            .ok()
+            .and_then(|p| p.parse::<u32>().ok());
+        if pid != Some(std::process::id()) {
+            return vec![];
+        }
+        let count: RawFd = match std::env::var("LISTEN_FDS")
+            .ok()
+            .and_then(|n| n.parse().ok())
+        {
+            Some(n) => n,
+            None => return vec![],
+        };
+        let names = std::env::var("LISTEN_FDNAMES").unwrap_or_default();
+        let mut names = names.split(':');
+        (0..count)
+            .map(|i| {
+                let name = names.next().unwrap_or("unknown").to_string();
+                (name, LISTEN_FDS_START + i)
+            })
+            .collect()
+    }
+
+    /// 取走名称匹配的 socket，不指定名称时取走剩下的全部
+    pub fn take(name: Option<&str>) -> io::Result<Vec<Listener>> {
+        let mut inherited = INHERITED.lock().expect("systemd sockets lock poisoned");
+        let all = inherited.get_or_insert_with(read_env);
+        let (taken, rest): (Vec<_>, Vec<_>) = all
+            .drain(..)
+            .partition(|(n, _)| name.is_none_or(|name| n == name));
+        *all = rest;
+        taken.into_iter().map(|(_, fd)| from_fd(fd)).collect()
+    }
+
+    fn from_fd(fd: RawFd) -> io::Result<Listener> {
+        // 先当作 TCP socket，取不到 IP 地址时再当作 Unix socket
+        // SAFETY: systemd 传入的文件描述符只会在 take 中被取走一次
+        let tcp = unsafe { std::net::TcpListener::from_raw_fd(fd) };
+        if tcp.local_addr().is_ok() {
+            tcp.set_nonblocking(true)?;
+            return Ok(tokio::net::TcpListener::from_std(tcp)?.into());
+        }
+        // SAFETY: 同上，所有权从 tcp 转移过来
+        let unix = unsafe { std::os::unix::net::UnixListener::from_raw_fd(tcp.into_raw_fd()) };
+        unix.set_nonblocking(true)?;
+        Ok(tokio::net::UnixListener::from_std(unix)?.into())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_bind_all() {
+        let path = std::env::temp_dir().join(format!("ksana-{}.sock", std::process::id()));
+        let addrs = vec![
+            "127.0.0.1:0".to_string(),
+            format!("unix:{}", path.display()),
+        ];
+        let listeners = bind_all(&addrs).await.unwrap();
+        let addr = listeners[0].local_addr().unwrap();
+        assert_eq!(listeners[1].to_string(), format!("unix:{}", path.display()));
+
+        tokio::net::TcpStream::connect(addr).await.unwrap();
+        let (_, peer) = listeners[0].accept().await.unwrap();
+        assert!(peer.is_some());
+        tokio::net::UnixStream::connect(&path).await.unwrap();
+        let (_, peer) = listeners[1].accept().await.unwrap();
+        assert!(peer.is_none());
+
+        // 上次留下的 socket 文件不影响重新绑定
+        drop(listeners);
+        bind_all(&addrs[1..]).await.unwrap();
+        assert!(bind_all(&["systemd:web".to_string()]).await.is_err());
+        let _ = std::fs::remove_file(&path);
+    }
+}
@@ -0,0 +1,615 @@
+mod connections;
+mod health;
+mod http;
+mod listener;
+mod proxy;
+mod rewind;
+mod tls;
+
+use crate::{
+    metrics::METRICS,
+    relay::{Context, Subscriber},
+};
+pub(crate) use connections::Connections;
+use connections::{ConnectionGuard, Rejected};
+use http::{Request, Response};
+use k256::{
+    elliptic_curve::subtle::ConstantTimeEq,
+    sha2::{Digest, Sha256},
+};
+pub(crate) use listener::bind_all;
+pub use listener::Listener;
+use log::{debug, error, info};
+pub use rewind::Rewind;
+use std::{fmt::Display, future::Future, net::SocketAddr, time::Duration};
+pub(crate) use tls::Tls;
+use tokio::{
+    io::{AsyncRead, AsyncWrite},
+    time::timeout,
+};
+use tokio_native_tls::TlsAcceptor;
+use tokio_tungstenite::{
+    accept_async_with_config, tungstenite::protocol::WebSocketConfig, WebSocketStream,
+};
+
+/// 明文或 TLS 的底层连接
+pub trait Io: AsyncRead + AsyncWrite + Unpin + Send {}
+impl<T: AsyncRead + AsyncWrite + Unpin + Send> Io for T {}
+
+/// 客户端连接，请求头已经被预读过一次
+pub type ClientStream = Rewind<Box<dyn Io>>;
+pub type ClientSocket = WebSocketStream<ClientStream>;
+
+/// 接受新连接，每个连接在单独的任务中完成握手；指定了 tls 时先完成 TLS 握手
+///
+/// 收到退出信号后继续接受 `network.shutdown_drain` 秒，然后返回，listener 随之关闭
+pub(crate) async fn serve(listener: Listener, tls: Option<Tls>, ctx: Context) {
+    let mut shutdown = ctx.shutdown.clone();
+    accept_until(&listener, &tls, &ctx, async {
+        let _ = shutdown.changed().await;
+    })
+    .await;
+    // 退出时 /readyz 返回 503，再接受一段时间的连接，负载均衡才能看到并摘掉这个实例
+    let drain = Duration::from_secs(ctx.config().network.shutdown_drain);
+    if !drain.is_zero() {
+        debug!("draining {} for {:?}", listener, drain);
+        accept_until(&listener, &tls, &ctx, tokio::time::sleep(drain)).await;
+    }
+}
+
+/// 接受连接直到 until 完成
+async fn accept_until(
+    listener: &Listener,
+    tls: &Option<Tls>,
+    ctx: &Context,
+    until: impl Future<Output = ()>,
+) {
+    tokio::pin!(until);
+    loop {
+        tokio::select! {
+            r = listener.accept() => match r {
+                Ok((stream, peer)) => {
+                    let ctx = ctx.clone();
+                    let acceptor = tls.as_ref().map(Tls::acceptor);
+                    tokio::spawn(async move {
+                        handle_connection(stream, acceptor, peer, ctx).await;
+                    });
+                }
+                Err(e) => error!("accept connection faild: {}", e),
+            },
+            _ = &mut until => break,
+        }
+    }
+}
+
+/// peer 为 None 时连接来自 Unix socket，当作受信任的本机代理
+async fn handle_connection(
+    mut stream: Box<dyn Io>,
+    acceptor: Option<TlsAcceptor>,
+    peer: Option<SocketAddr>,
+    ctx: Context,
+) {
+    let config = ctx.config();
+    let network = &config.network;
+    // 握手超时为 0 时不限制
+    let handshake = Duration::from_secs(network.handshake_timeout);
+    let with_timeout = |d: Duration| if d.is_zero() { Duration::MAX } else { d };
+    // Unix socket 的连接没有客户端地址，除非代理转发了地址，否则不按 IP 限制连接数、限流和封禁，
+    // 避免所有本机代理的客户端共用 127.0.0.1 的配额
+    let mut ip_limited = peer.is_some();
+    let (mut peer, mut trusted) = match peer {
+        Some(peer) => {
+            let peer = SocketAddr::new(peer.ip().to_canonical(), peer.port());
+            (peer, network.is_trusted_proxy(peer.ip()))
+        }
+        None => (SocketAddr::from(([127, 0, 0, 1], 0)), true),
+    };
+
+    // 不经过受信任代理的连接在 accept 之后立即检查封禁和连接数，握手期间也占用连接数；
+    // 受信任代理的连接要等读到转发的客户端地址之后再检查
+    let mut rejected = None;
+    let mut _probe = None;
+    let guard = if trusted {
+        None
+    } else {
+        match admit(&ctx, peer, true) {
+            Ok(guard) => Some(guard),
+            // 被拒绝的连接也可能是健康检查，在单独的少量名额内读取请求再决定，
+            // 避免 relay 繁忙时探针失败、编排系统重启一个正常的 relay
+            Err(response) => match ctx.connections.acquire_probe() {
+                Some(probe) => {
+                    _probe = Some(probe);
+                    rejected = Some(response);
+                    None
+                }
+                None => {
+                    // TLS 还没有握手，无法回复 HTTP 响应，直接断开
+                    if acceptor.is_none() {
+                        let _ = response.write_to(&mut stream).await;
+                    }
+                    return;
+                }
+            },
+        }
+    };
+
+    // PROXY 协议的头在 TLS 握手之前
+    if network.proxy_protocol && trusted {
+        let header = proxy::read_proxy_header(&mut stream);
+        match handshake_step("read proxy header", peer, with_timeout(handshake), header).await {
+            Some(Some(addr)) => {
+                peer = SocketAddr::new(addr.ip().to_canonical(), addr.port());
+                trusted = network.is_trusted_proxy(peer.ip());
+                ip_limited = true;
+            }
+            Some(None) => {}
+            None => return,
+        }
+    }
+    let mut stream: Box<dyn Io> = match acceptor {
+        None => stream,
+        Some(acceptor) => {
+            let accept = acceptor.accept(stream);
+            match handshake_step("tls handshake", peer, with_timeout(handshake), accept).await {
+                Some(stream) => Box::new(stream),
+                None => return,
+            }
+        }
+    };
+    let read = http::read_request(&mut stream);
+    let Some((request, head)) =
+        handshake_step("read request", peer, with_timeout(handshake), read).await
+    else {
+        return;
+    };
+    if trusted {
+        if let Some(ip) = proxy::forwarded_ip(&request, &network.trusted_proxies) {
+            // 转发的地址中没有端口
+            peer = SocketAddr::new(ip, 0);
+            ip_limited = true;
+        }
+    }
+    if !ip_limited {
+        debug!("unix socket connection without a forwarded address, skip per-IP limits");
+    }
+
+    let _guard = match guard {
+        Some(guard) => Some(guard),
+        None => {
+            let admitted = match rejected {
+                Some(response) => Err(response),
+                None => admit(&ctx, peer, ip_limited),
+            };
+            match admitted {
+                Ok(guard) => Some(guard),
+                // 健康检查和指标不受连接数限制和封禁的影响
+                Err(_) if is_probe(&request) => None,
+                Err(response) => {
+                    let _ = response.write_to(&mut stream).await;
+                    return;
+                }
+            }
+        }
+    };
+
+    if request.is_websocket_upgrade() {
+        // 退出中的连接会被立即关闭，不再升级
+        if *ctx.shutdown.borrow() {
+            let response = Response::new("503 Service Unavailable")
+                .body("text/plain; charset=utf-8", "shutting down");
+            let _ = response.write_to(&mut stream).await;
+            return;
+        }
+        // 超过限制的消息在 tungstenite 中就会被拒绝，不会读入完整内容再解析
+        let max_size = Some(config.limits.max_message_length).filter(|&n| n > 0);
+        let config = WebSocketConfig {
+            max_message_size: max_size,
+            max_frame_size: max_size,
+            ..Default::default()
+        };
+        let accept = accept_async_with_config(Rewind::new(head, stream), Some(config));
+        match timeout(with_timeout(handshake), accept).await {
+            // 在当前任务中运行，连接断开后 guard 才会释放
+            Ok(Ok(ws_stream)) => {
+                let client_ip = ip_limited.then_some(peer.ip());
+                Subscriber::new(peer, client_ip, ws_stream, &ctx)
+                    .run()
+                    .await
+            }
+            Ok(Err(e)) => info!("websocket handshake faild with {}: {}", peer, e),
+            Err(_) => info!("websocket handshake with {} timed out", peer),
+        }
+        return;
+    }
+
+    debug!("http {} {} from {}", request.method, request.path, peer);
+    let response = http_response(&request, &ctx).await;
+    if let Err(e) = response.write_to(&mut stream).await {
+        debug!("write http response faild to {}: {}", peer, e);
+    }
+}
+
+/// 检查 peer 是否被封禁，并按连接数限制登记这个连接，拒绝时返回要回复的 HTTP 响应
+///
+/// ip_limited 为 false 时只检查连接总数，不检查封禁和每个 IP 的连接数
+fn admit(ctx: &Context, peer: SocketAddr, ip_limited: bool) -> Result<ConnectionGuard, Response> {
+    let config = ctx.config();
+    if ip_limited && ctx.rate_limiter.is_banned(peer.ip()) {
+        debug!("reject banned peer {}", peer);
+        return Err(Response::new("429 Too Many Requests")
+            .header("Retry-After", config.rate_limit.ban_secs.to_string()));
+    }
+    let network = &config.network;
+    let max_per_ip = if ip_limited {
+        network.max_connections_per_ip
+    } else {
+        0
+    };
+    match ctx
+        .connections
+        .acquire(peer.ip(), network.max_connections, max_per_ip)
+    {
+        Ok(guard) => {
+            debug!(
+                "connection from {}, {} connections",
+                peer,
+                ctx.connections.total()
+            );
+            Ok(guard)
+        }
+        Err(rejected) => {
+            debug!("reject {}: {:?}", peer, rejected);
+            let status = match rejected {
+                Rejected::TooManyConnections => "503 Service Unavailable",
+                Rejected::TooManyConnectionsFromIp => "429 Too Many Requests",
+            };
+            Err(Response::new(status))
+        }
+    }
+}
+
+/// 健康检查和指标请求，连接数达到上限时也要回复
+fn is_probe(request: &Request) -> bool {
+    !request.is_websocket_upgrade()
+        && matches!(request.path.as_str(), "/healthz" | "/readyz" | "/metrics")
+}
+
+/// 在超时时间内完成握手的一步，出错或超时时记录日志并返回 None
+async fn handshake_step<T, E: Display>(
+    name: &str,
+    peer: SocketAddr,
+    limit: Duration,
+    step: impl Future<Output = Result<T, E>>,
+) -> Option<T> {
+    match timeout(limit, step).await {
+        Ok(Ok(r)) => Some(r),
+        Ok(Err(e)) => {
+            debug!("{} faild from {}: {}", name, peer, e);
+            None
+        }
+        Err(_) => {
+            debug!("{} from {} timed out", name, peer);
+            None
+        }
+    }
+}
+
+/// 非 websocket 请求：NIP-11 信息文档、管理接口，或者提示使用 Nostr 客户端
+async fn http_response(request: &Request, ctx: &Context) -> Response {
+    if request.path.starts_with("/admin/") {
+        return admin_response(request, ctx).await;
+    }
+    match request.path.as_str() {
+        "/metrics" => return metrics_response(request, ctx).await,
+        "/healthz" | "/readyz" if request.method != "GET" => {
+            return Response::new("405 Method Not Allowed").header("Allow", "GET")
+        }
+        "/healthz" => return Response::new("200 OK").body("text/plain; charset=utf-8", "ok"),
+        "/readyz" => return health::ready_response(ctx).await,
+        _ => {}
+    }
+    match request.method.as_str() {
+        // NIP-11 要求支持跨域
+        "OPTIONS" => cors(Response::new("204 No Content")),
+        "GET" if request.accepts("application/nostr+json") => {
+            let info = serde_json::to_string(&ctx.config().relay_information())
+                .expect("serde relay info faild!");
+            cors(Response::new("200 OK")).body("application/nostr+json", info)
+        }
+        "GET" => Response::new("200 OK").body(
+            "text/plain; charset=utf-8",
+            "Please use a Nostr client to connect.",
+        ),
+        _ => Response::new("405 Method Not Allowed").header("Allow", "GET, OPTIONS"),
+    }
+}
+
+/// Prometheus 指标，抓取时更新连接数和数据库的状态
+async fn metrics_response(request: &Request, ctx: &Context) -> Response {
+    let config = ctx.config();
+    let metrics = &config.metrics;
+    if !metrics.enabled {
+        return Response::new("404 Not Found");
+    }
+    if !metrics.token.is_empty() && !bearer_matches(request, &metrics.token) {
+        return Response::new("401 Unauthorized").header("WWW-Authenticate", "Bearer");
+    }
+    if request.method != "GET" {
+        return Response::new("405 Method Not Allowed").header("Allow", "GET");
+    }
+    METRICS.connections.set(ctx.connections.total() as i64);
+    if let Some(db) = &ctx.database {
+        db.update_metrics().await;
+    }
+    Response::new("200 OK").body("text/plain; version=0.0.4", METRICS.encode())
+}
+
+/// 请求的 `Authorization: Bearer` 是否和 token 一致
+fn bearer_matches(request: &Request, token: &str) -> bool {
+    request
+        .header("authorization")
+        .and_then(|v| v.strip_prefix("Bearer "))
+        .is_some_and(|t| token_matches(t.trim(), token))
+}
+
+/// 用常数时间比较 token，先取 sha256 让比较的长度固定，不会从耗时泄露 token 的内容和长度
+fn token_matches(given: &str, token: &str) -> bool {
+    Sha256::digest(given.as_bytes())
+        .ct_eq(&Sha256::digest(token.as_bytes()))
+        .into()
+}
+
+/// 管理接口，需要配置 admin.token 并携带 `Authorization: Bearer <token>`
+async fn admin_response(request: &Request, ctx: &Context) -> Response {
+    let config = ctx.config();
+    let token = &config.admin.token;
+    if token.is_empty() {
+        return Response::new("404 Not Found");
+    }
+    if !bearer_matches(request, token) {
+        return Response::new("401 Unauthorized").header("WWW-Authenticate", "Bearer");
+    }
+
+    let (path, query) = request
+        .path
+        .split_once('?')
+        .unwrap_or((request.path.as_str(), ""));
+    match (request.method.as_str(), path) {
+        ("POST", "/admin/backup") => {
+            let Some(db) = &ctx.database else {
+                return Response::new("501 Not Implemented").body(
+                    "text/plain; charset=utf-8",
+                    "backup needs the sqlite backend",
+                );
+            };
+            let backup = &config.backup;
+            match db
+                .backup(&backup.dir, backup.keep, backup.verify_sample)
+                .await
+            {
+                Ok(report) => {
+                    let body = serde_json::to_string(&report).expect("serde backup report faild!");
+                    Response::new("200 OK").body("application/json", body)
+                }
+                Err(e) => {
+                    error!("backup faild: {}", e);
+                    Response::new("500 Internal Server Error")
+                        .body("text/plain; charset=utf-8", e.to_string())
+                }
+            }
+        }
+        (_, "/admin/backup") => Response::new("405 Method Not Allowed").header("Allow", "POST"),
+        ("GET", "/admin/quota") => {
+            let Some(db) = &ctx.database else {
+                return Response::new("501 Not Implemented").body(
+                    "text/plain; charset=utf-8",
+                    "quota usage needs the sqlite backend",
+                );
+            };
+            // 可以用 ?limit=N 指定列出多少个作者
+            let limit = query
+                .split('&')
+                .find_map(|kv| kv.strip_prefix("limit="))
+                .and_then(|v| v.parse().ok())
+                .unwrap_or(100);
+            match db.top_consumers(limit).await {
+                Ok(consumers) => {
+                    let body = serde_json::to_string(&consumers).expect("serde usage faild!");
+                    Response::new("200 OK").body("application/json", body)
+                }
+                Err(e) => {
+                    error!("query top consumers faild: {}", e);
+                    Response::new("500 Internal Server Error")
+                        .body("text/plain; charset=utf-8", e.to_string())
+                }
+            }
+        }
+        (_, "/admin/quota") => Response::new("405 Method Not Allowed").header("Allow", "GET"),
+        ("POST", "/admin/reload") => match ctx.reloader.reload() {
+            Ok(report) => {
+                let body = serde_json::to_string(&report).expect("serde reload report faild!");
+                Response::new("200 OK").body("application/json", body)
+            }
+            Err(e) => {
+                error!("reload config faild: {}", e);
+                Response::new("500 Internal Server Error")
+                    .body("text/plain; charset=utf-8", e.to_string())
+            }
+        },
+        (_, "/admin/reload") => Response::new("405 Method Not Allowed").header("Allow", "POST"),
+        _ => Response::new("404 Not Found"),
+    }
+}
+
+fn cors(response: Response) -> Response {
+    response
+        .header("Access-Control-Allow-Origin", "*")
+        .header("Access-Control-Allow-Headers", "*")
+        .header("Access-Control-Allow-Methods", "GET, OPTIONS")
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{config::Config, database::MemoryStore, RelayBuilder};
+    use futures::StreamExt;
+    use std::{net::SocketAddr, time::Duration};
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::{TcpListener, TcpStream},
+        sync::oneshot,
+    };
+
+    #[tokio::test]
+    async fn test_handshake_counts_against_ip_cap() {
+        let mut config = Config::default();
+        config.network.max_connections_per_ip = 1;
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let server = RelayBuilder::new(config)
+            .store(MemoryStore::new())
+            .listener(listener)
+            .build()
+            .await
+            .unwrap();
+        let addr = server.local_addrs()[0];
+        tokio::spawn(server.serve());
+
+        // 第一个连接不发送请求，停在握手阶段，仍然占用这个 IP 的连接数
+        let _idle = TcpStream::connect(addr).await.unwrap();
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        stream.write_all(b"GET / HTTP/1.1\r\n\r\n").await.unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await.unwrap();
+        assert!(response.starts_with("HTTP/1.1 429"), "{}", response);
+    }
+
+    #[tokio::test]
+    async fn test_health_over_connection_limit() {
+        let mut config = Config::default();
+        config.network.max_connections = 1;
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let server = RelayBuilder::new(config)
+            .store(MemoryStore::new())
+            .listener(listener)
+            .build()
+            .await
+            .unwrap();
+        let addr = server.local_addrs()[0];
+        tokio::spawn(server.serve());
+
+        // 停在握手阶段的连接占满了连接数
+        let _idle = TcpStream::connect(addr).await.unwrap();
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        let response = request(addr, "GET /healthz HTTP/1.1\r\n\r\n").await;
+        assert!(response.starts_with("HTTP/1.1 200"), "{}", response);
+        let response = request(addr, "GET / HTTP/1.1\r\n\r\n").await;
+        assert!(response.starts_with("HTTP/1.1 503"), "{}", response);
+    }
+
+    #[tokio::test]
+    async fn test_idle_timeout_without_ping() {
+        let mut config = Config::default();
+        config.network.ping_interval = 0;
+        config.network.idle_timeout = 1;
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let server = RelayBuilder::new(config)
+            .store(MemoryStore::new())
+            .listener(listener)
+            .build()
+            .await
+            .unwrap();
+        let addr = server.local_addrs()[0];
+        tokio::spawn(server.serve());
+
+        let (mut ws, _) = tokio_tungstenite::connect_async(format!("ws://{}", addr))
+            .await
+            .unwrap();
+        // 不发送任何消息，关闭 ping 时仍然按 idle_timeout 断开
+        let closed = tokio::time::timeout(Duration::from_secs(5), async {
+            while let Some(Ok(msg)) = ws.next().await {
+                if msg.is_close() {
+                    break;
+                }
+            }
+        })
+        .await;
+        assert!(closed.is_ok(), "idle connection was not closed");
+    }
+
+    /// 发送一个 HTTP 请求，返回完整的响应
+    async fn request(addr: SocketAddr, head: &str) -> String {
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        stream.write_all(head.as_bytes()).await.unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await.unwrap();
+        response
+    }
+
+    #[tokio::test]
+    async fn test_health() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let server = RelayBuilder::new(Config::default())
+            .store(MemoryStore::new())
+            .listener(listener)
+            .build()
+            .await
+            .unwrap();
+        let addr = server.local_addrs()[0];
+        tokio::spawn(server.serve());
+
+        let response = request(addr, "GET /healthz HTTP/1.1\r\n\r\n").await;
+        assert!(response.starts_with("HTTP/1.1 200"), "{}", response);
+        assert!(response.ends_with("ok"), "{}", response);
+        let response = request(addr, "POST /readyz HTTP/1.1\r\n\r\n").await;
+        assert!(response.starts_with("HTTP/1.1 405"), "{}", response);
+        let response = request(addr, "GET /readyz HTTP/1.1\r\n\r\n").await;
+        assert!(response.starts_with("HTTP/1.1 200"), "{}", response);
+        assert!(
+            response.ends_with(r#"{"ready":true,"checks":{"relay":"ok","shutdown":"ok"}}"#),
+            "{}",
+            response
+        );
+    }
+
+    #[tokio::test]
+    async fn test_drain_reports_shutdown() {
+        let mut config = Config::default();
+        config.network.shutdown_drain = 1;
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let server = RelayBuilder::new(config)
+            .store(MemoryStore::new())
+            .listener(listener)
+            .build()
+            .await
+            .unwrap();
+        let addr = server.local_addrs()[0];
+        let (signal, rx) = oneshot::channel::<()>();
+        let serving = tokio::spawn(server.serve_with_shutdown(async {
+            let _ = rx.await;
+        }));
+        let _ = signal.send(());
+        tokio::time::sleep(Duration::from_millis(50)).await;
+
+        // drain 期间仍然接受连接，/readyz 报告正在退出，websocket 升级被拒绝
+        let response = request(addr, "GET /readyz HTTP/1.1\r\n\r\n").await;
+        assert!(response.starts_with("HTTP/1.1 503"), "{}", response);
+        assert!(
+            response.contains(r#""shutdown":"shutting down""#),
+            "{}",
+            response
+        );
+        let upgrade = "GET / HTTP/1.1\r\nHost: localhost\r\nConnection: Upgrade\r\n\
+            Upgrade: websocket\r\nSec-WebSocket-Version: 13\r\n\
+            Sec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\n\r\n";
+        let response = request(addr, upgrade).await;
+        assert!(response.starts_with("HTTP/1.1 503"), "{}", response);
+
+        // drain 结束后停止接受连接并退出
+        tokio::time::timeout(Duration::from_secs(3), serving)
+            .await
+            .unwrap()
+            .unwrap()
+            .unwrap();
+        assert!(TcpStream::connect(addr).await.is_err());
+    }
+}
@@ -0,0 +1,191 @@
+use super::http::Request;
+use crate::config::IpNet;
+use std::{
+    io,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+};
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+/// PROXY 协议 v2 的头以这 12 个字节开始
+const V2_SIGNATURE: [u8; 12] = *b"\r\n\r\n\0\r\nQUIT\n";
+/// v1 头的最大长度，包括结尾的 CRLF
+const V1_MAX_LENGTH: usize = 107;
+
+fn invalid(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+/// 读出 PROXY 协议（v1 或 v2）的头，返回其中的客户端地址
+///
+/// 只读出头本身，之后的 TLS 握手或 HTTP 请求留在流中；LOCAL 命令和未知的地址族返回 None
+pub async fn read_proxy_header<S: AsyncRead + Unpin>(
+    stream: &mut S,
+) -> io::Result<Option<SocketAddr>> {
+    // 最短的 v1 头也超过 12 个字节，先读 12 个字节不会读过头
+    let mut head = [0u8; 12];
+    stream.read_exact(&mut head).await?;
+    if head == V2_SIGNATURE {
+        let mut meta = [0u8; 4];
+        stream.read_exact(&mut meta).await?;
+        let mut body = vec![0u8; u16::from_be_bytes([meta[2], meta[3]]) as usize];
+        stream.read_exact(&mut body).await?;
+        return parse_v2(meta[0], meta[1], &body);
+    }
+    if !head.starts_with(b"PROXY ") {
+        return Err(invalid("missing PROXY protocol header"));
+    }
+    // v1 头以 CRLF 结束，逐字节读取
+    let mut line = head.to_vec();
+    while !line.ends_with(b"\r\n") {
+        if line.len() >= V1_MAX_LENGTH {
+            return Err(invalid("PROXY protocol header is too long"));
+        }
+        line.push(stream.read_u8().await?);
+    }
+    parse_v1(&line)
+}
+
+/// `PROXY TCP4 <源地址> <目的地址> <源端口> <目的端口>\r\n`
+fn parse_v1(line: &[u8]) -> io::Result<Option<SocketAddr>> {
+    let line = std::str::from_utf8(line).map_err(|_| invalid("bad PROXY protocol header"))?;
+    let parts: Vec<&str> = line.trim_end().split(' ').collect();
+    match parts.as_slice() {
+        ["PROXY", "UNKNOWN", ..] => Ok(None),
+        ["PROXY", "TCP4" | "TCP6", src, _, port, _] => {
+            let ip = src
+                .parse()
+                .map_err(|_| invalid("bad PROXY protocol source address"))?;
+            let port = port
+                .parse()
+                .map_err(|_| invalid("bad PROXY protocol source port"))?;
+            Ok(Some(SocketAddr::new(ip, port)))
+        }
+        _ => Err(invalid("bad PROXY protocol header")),
+    }
+}
+
+/// 二进制格式：版本和命令、地址族和传输协议、地址长度，之后是地址
+fn parse_v2(version_command: u8, family: u8, body: &[u8]) -> io::Result<Option<SocketAddr>> {
+    if version_command >> 4 != 2 {
+        return Err(invalid("unsupported PROXY protocol version"));
+    }
+    match version_command & 0x0f {
+        // LOCAL：代理自己发起的连接，例如健康检查
+        0 => return Ok(None),
+        1 => {}
+        _ => return Err(invalid("unsupported PROXY protocol command")),
+    }
+    let port = |at: usize| u16::from_be_bytes([body[at], body[at + 1]]);
+    match family >> 4 {
+        1 if body.len() >= 12 => {
+            let ip: [u8; 4] = body[..4].try_into().expect("slice of 4 bytes");
+            Ok(Some(SocketAddr::new(Ipv4Addr::from(ip).into(), port(8))))
+        }
+        2 if body.len() >= 36 => {
+            let ip: [u8; 16] = body[..16].try_into().expect("slice of 16 bytes");
+            Ok(Some(SocketAddr::new(Ipv6Addr::from(ip).into(), port(32))))
+        }
+        1 | 2 => Err(invalid("PROXY protocol address is truncated")),
+        // AF_UNSPEC 和 AF_UNIX 中没有 IP 地址
+        _ => Ok(None),
+    }
+}
+
+/// 从受信任代理转发的请求头中取出客户端地址
+///
+/// X-Forwarded-For 从右往左跳过受信任的代理，第一个不受信任的地址就是客户端；
+/// 没有 X-Forwarded-For 时使用 X-Real-IP
+pub fn forwarded_ip(request: &Request, trusted: &[IpNet]) -> Option<IpAddr> {
+    let forwarded: Vec<&str> = request
+        .headers
+        .iter()
+        .filter(|(k, _)| k.eq_ignore_ascii_case("x-forwarded-for"))
+        .flat_map(|(_, v)| v.split(','))
+        .collect();
+    if forwarded.is_empty() {
+        return request
+            .header("x-real-ip")
+            .and_then(|v| v.trim().parse::<IpAddr>().ok())
+            .map(|ip| ip.to_canonical());
+    }
+    let mut client = None;
+    for addr in forwarded.into_iter().rev() {
+        // 无法解析的地址之后的内容都不可信
+        let ip = addr.trim().parse::<IpAddr>().ok()?.to_canonical();
+        client = Some(ip);
+        if !trusted.iter().any(|net| net.contains(ip)) {
+            break;
+        }
+    }
+    client
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_proxy_header() {
+        let mut v1: &[u8] = b"PROXY TCP4 203.0.113.7 10.0.0.1 51000 9002\r\nGET / HTTP/1.1\r\n";
+        let addr = read_proxy_header(&mut v1).await.unwrap();
+        assert_eq!(addr, Some("203.0.113.7:51000".parse().unwrap()));
+        // 头之后的数据留在流中
+        assert!(v1.starts_with(b"GET /"));
+
+        let mut v2 = V2_SIGNATURE.to_vec();
+        v2.extend([0x21, 0x11, 0, 12]);
+        v2.extend([198, 51, 100, 9, 10, 0, 0, 1, 0xc3, 0x50, 0x23, 0x2a]);
+        v2.extend(b"rest");
+        let mut stream = v2.as_slice();
+        let addr = read_proxy_header(&mut stream).await.unwrap();
+        assert_eq!(addr, Some("198.51.100.9:50000".parse().unwrap()));
+        assert_eq!(stream, b"rest");
+
+        let mut local = V2_SIGNATURE.to_vec();
+        local.extend([0x20, 0x00, 0, 0]);
+        assert_eq!(
+            read_proxy_header(&mut local.as_slice()).await.unwrap(),
+            None
+        );
+        let mut plain: &[u8] = b"GET / HTTP/1.1\r\nHost: x\r\n\r\n";
+        assert!(read_proxy_header(&mut plain).await.is_err());
+    }
+
+    #[test]
+    fn test_forwarded_ip() {
+        let trusted: Vec<IpNet> = ["10.0.0.0/8", "::1"]
+            .iter()
+            .map(|s| s.parse().unwrap())
+            .collect();
+        let request = |headers: &[(&str, &str)]| Request {
+            method: "GET".to_string(),
+            path: "/".to_string(),
+            headers: headers
+                .iter()
+                .map(|(k, v)| (k.to_string(), v.to_string()))
+                .collect(),
+        };
+        let ip = |s: &str| Some(s.parse::<IpAddr>().unwrap());
+
+        // 最左边的地址可能是客户端伪造的，从右边第一个不受信任的地址开始算
+        let r = request(&[("X-Forwarded-For", "1.1.1.1, 203.0.113.7, 10.0.0.2")]);
+        assert_eq!(forwarded_ip(&r, &trusted), ip("203.0.113.7"));
+        let r = request(&[
+            ("X-Forwarded-For", "203.0.113.7"),
+            ("x-forwarded-for", "10.1.1.1"),
+        ]);
+        assert_eq!(forwarded_ip(&r, &trusted), ip("203.0.113.7"));
+        let r = request(&[("X-Forwarded-For", "10.0.0.3, 10.0.0.2")]);
+        assert_eq!(forwarded_ip(&r, &trusted), ip("10.0.0.3"));
+        let r = request(&[("X-Forwarded-For", "unknown, 10.0.0.2")]);
+        assert_eq!(forwarded_ip(&r, &trusted), None);
+        let r = request(&[("X-Real-IP", "::ffff:203.0.113.7")]);
+        assert_eq!(forwarded_ip(&r, &trusted), ip("203.0.113.7"));
+        assert_eq!(forwarded_ip(&request(&[]), &trusted), None);
+
+        assert!(trusted[0].contains(ip("10.255.0.1").unwrap()));
+        assert!(!trusted[0].contains(ip("11.0.0.1").unwrap()));
+        assert!(trusted[1].contains(ip("::1").unwrap()));
+        assert!("10.0.0.0/33".parse::<IpNet>().is_err());
+    }
+}